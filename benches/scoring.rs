@@ -0,0 +1,39 @@
+//! Scoring a 50k-row store. Compare `cargo bench` with `cargo bench --features rayon` or
+//! `cargo bench --features blas`.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use mem::scoring;
+use ndarray::{Array1, Array2, ShapeBuilder};
+
+const ROWS: usize = 50_000;
+const DIMENSION: usize = 1536;
+
+fn scoring_benchmark(c: &mut Criterion) {
+    // deterministic pseudo-random values are enough, the scores themselves don't matter
+    let embeddings = Array2::from_shape_fn((ROWS, DIMENSION), |(i, j)| {
+        ((i * 31 + j * 17) % 101) as f32 / 101.0 - 0.5
+    });
+    let query = Array1::from_shape_fn(DIMENSION, |j| ((j * 7) % 13) as f32 / 13.0 - 0.5);
+
+    c.bench_function("dot_scores 50k rows", |b| {
+        b.iter(|| scoring::dot_scores(black_box(embeddings.view()), black_box(query.view())))
+    });
+
+    // the same store with every column contiguous instead of every row
+    let mut column_major = Array2::zeros((ROWS, DIMENSION).f());
+    column_major.assign(&embeddings);
+    c.bench_function("dot_scores 50k rows, column-major", |b| {
+        b.iter(|| scoring::dot_scores(black_box(column_major.view()), black_box(query.view())))
+    });
+
+    let scores: Vec<(usize, f32)> = scoring::dot_scores(embeddings.view(), query.view())
+        .into_iter()
+        .enumerate()
+        .collect();
+    c.bench_function("top_k 10 of 50k rows", |b| {
+        b.iter(|| scoring::top_k(black_box(scores.clone()), 10))
+    });
+}
+
+criterion_group!(benches, scoring_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,104 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind};
+use std::path::Path;
+
+/// An inverted index over the text of memories for Okapi BM25 keyword scoring.
+///
+/// Documents are identified by their position, like embedding rows, and can only be appended;
+/// any other change to the memories needs a new index.
+#[derive(Default, Serialize, Deserialize)]
+pub(crate) struct Bm25Index {
+    /// The number of terms in every document.
+    doc_lengths: Vec<u32>,
+    /// For every term, the documents containing it and how often, in document order.
+    postings: HashMap<String, Vec<(u32, u32)>>,
+}
+
+impl Bm25Index {
+    /// How quickly repeated terms stop adding to the score.
+    const K1: f32 = 1.2;
+    /// How much long documents are penalized, from 0 (not at all) to 1.
+    const B: f32 = 0.75;
+
+    /// The number of documents in the index.
+    pub fn len(&self) -> usize {
+        self.doc_lengths.len()
+    }
+
+    /// Add the next document, i.e. document `self.len()`, to the index.
+    pub fn push(&mut self, text: &str) {
+        let doc = self.doc_lengths.len() as u32;
+        let terms = tokenize(text);
+        self.doc_lengths.push(terms.len() as u32);
+        let mut counts: HashMap<String, u32> = HashMap::new();
+        for term in terms {
+            *counts.entry(term).or_default() += 1;
+        }
+        for (term, count) in counts {
+            self.postings.entry(term).or_default().push((doc, count));
+        }
+    }
+
+    /// Score every document against `query`.
+    ///
+    /// Returns one score per document, in document order. Documents that share no term with the
+    /// query score 0.
+    pub fn scores(&self, query: &str) -> Vec<f32> {
+        let mut scores = vec![0.0; self.doc_lengths.len()];
+        if self.doc_lengths.is_empty() {
+            return scores;
+        }
+        let docs = self.doc_lengths.len() as f32;
+        let average_length = self.doc_lengths.iter().map(|&len| len as f32).sum::<f32>() / docs;
+        let mut terms = tokenize(query);
+        terms.sort();
+        terms.dedup();
+        for term in terms {
+            let Some(postings) = self.postings.get(&term) else {
+                continue;
+            };
+            let containing = postings.len() as f32;
+            // the 1 keeps terms that most documents contain from scoring negatively
+            let idf = ((docs - containing + 0.5) / (containing + 0.5) + 1.0).ln();
+            for &(doc, count) in postings {
+                let count = count as f32;
+                let length = self.doc_lengths[doc as usize] as f32;
+                let saturation = Self::K1 * (1.0 - Self::B + Self::B * length / average_length);
+                scores[doc as usize] += idf * count * (Self::K1 + 1.0) / (count + saturation);
+            }
+        }
+        scores
+    }
+
+    /// Read the index from `path`, or `None` if the file doesn't exist.
+    pub fn read(path: &Path) -> Result<Option<Bm25Index>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let index = serde_json::from_reader(BufReader::new(file))
+            .context(format!("Corrupt BM25 index file: {}", path.display()))?;
+        Ok(Some(index))
+    }
+
+    /// Write the index to `path`, replacing any existing file.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let file = File::create(path).context("Failed to create BM25 index file.")?;
+        let mut writer = BufWriter::new(file);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.into_inner()?.sync_all()?;
+        Ok(())
+    }
+}
+
+/// Split text into lowercase terms at every character that isn't a letter or a digit.
+fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|term| !term.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
@@ -0,0 +1,91 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+/// A small cache of query embeddings, persisted as JSON, that evicts the entries cached longest
+/// ago.
+///
+/// Entries are keyed by model as well as text, so switching models never serves embeddings
+/// from the old model. Reading the cache never writes it, so that hits stay cheap and don't
+/// race other processes.
+pub struct QueryCache {
+    path: PathBuf,
+    capacity: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CacheEntry {
+    model: String,
+    text: String,
+    embedding: Vec<f32>,
+}
+
+impl QueryCache {
+    pub fn new(path: PathBuf, capacity: usize) -> QueryCache {
+        QueryCache { path, capacity }
+    }
+
+    /// Get the cached embedding of `text`.
+    pub fn get(&self, model: &str, text: &str) -> Option<Vec<f32>> {
+        let text = Self::normalize(text);
+        self.read_entries()
+            .into_iter()
+            .find(|entry| entry.model == model && entry.text == text)
+            .map(|entry| entry.embedding)
+    }
+
+    /// Cache the embedding of `text`, evicting the entries cached longest ago if full.
+    pub fn put(&self, model: &str, text: &str, embedding: &[f32]) -> Result<()> {
+        let text = Self::normalize(text);
+        let mut entries = self.read_entries();
+        entries.retain(|entry| !(entry.model == model && entry.text == text));
+        entries.push(CacheEntry {
+            model: model.to_owned(),
+            text,
+            embedding: embedding.to_vec(),
+        });
+        let overflow = entries.len().saturating_sub(self.capacity);
+        entries.drain(..overflow);
+        self.write_entries(&entries)
+    }
+
+    /// Remove every entry from the cache.
+    pub fn clear(&self) -> Result<()> {
+        match std::fs::remove_file(&self.path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// A missing or unreadable cache is treated as empty, since it can always be rebuilt.
+    fn read_entries(&self) -> Vec<CacheEntry> {
+        std::fs::read(&self.path)
+            .ok()
+            .and_then(|contents| serde_json::from_slice(&contents).ok())
+            .unwrap_or_default()
+    }
+
+    /// Write the entries to a file of this process' own and rename it over the cache, so that
+    /// processes writing at the same time never leave a torn cache behind.
+    fn write_entries(&self, entries: &[CacheEntry]) -> Result<()> {
+        let contents = serde_json::to_vec(entries)?;
+        let mut tmp_file_path = self.path.as_os_str().to_owned();
+        tmp_file_path.push(format!(".{}.tmp", std::process::id()));
+        let tmp_file_path = PathBuf::from(tmp_file_path);
+        // the data directory only exists once a store has been saved in it
+        let data_dir_path = self.path.parent().expect("the cache is in a directory");
+        std::fs::create_dir_all(data_dir_path)
+            .and_then(|_| std::fs::write(&tmp_file_path, contents))
+            .and_then(|_| std::fs::rename(&tmp_file_path, &self.path))
+            .context(format!(
+                "Failed to write query cache. Make sure you have write permissions to {}",
+                self.path.display()
+            ))
+    }
+
+    /// Queries that only differ in whitespace share an entry.
+    fn normalize(text: &str) -> String {
+        text.split_whitespace().collect::<Vec<_>>().join(" ")
+    }
+}
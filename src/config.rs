@@ -0,0 +1,61 @@
+use crate::embedder::Provider;
+use crate::scoring::Metric;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// Defaults for options that would otherwise be passed on every invocation.
+///
+/// Read from `config.toml` in the data directory. Every field is optional, and flags and
+/// environment variables take precedence over it.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The embedding provider to use.
+    pub provider: Option<Provider>,
+    /// The embedding model to use.
+    pub model: Option<String>,
+    /// How the memories of new stores are scored.
+    pub metric: Option<Metric>,
+    /// The directory that stores, the query cache and the API key file live in.
+    pub data_dir: Option<PathBuf>,
+    /// The URL of the Ollama server, when using the Ollama provider.
+    pub ollama_url: Option<String>,
+    /// The URL of the OpenAI-compatible API, when using the OpenAI provider.
+    pub base_url: Option<String>,
+    /// OpenAI API keys to rotate through when one hits a rate limit.
+    pub openai_api_keys: Option<Vec<String>>,
+    /// The number of rolling backups of each store to keep.
+    pub backups: Option<usize>,
+    /// Whether to compress the data file of stores with zstd.
+    pub compress: Option<bool>,
+}
+
+impl Config {
+    /// Read the config file at `path`, or the empty config if it doesn't exist.
+    pub fn load(path: &Path) -> Result<Config> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Config::default()),
+            Err(err) => return Err(err).context("Failed to read config file."),
+        };
+        toml::from_str(&contents)
+            .with_context(|| format!("Failed to parse config file {}", path.display()))
+    }
+
+    /// Fill in every option that isn't set with the one from `fallback`.
+    pub fn or(self, fallback: Config) -> Config {
+        Config {
+            provider: self.provider.or(fallback.provider),
+            model: self.model.or(fallback.model),
+            metric: self.metric.or(fallback.metric),
+            data_dir: self.data_dir.or(fallback.data_dir),
+            ollama_url: self.ollama_url.or(fallback.ollama_url),
+            base_url: self.base_url.or(fallback.base_url),
+            openai_api_keys: self.openai_api_keys.or(fallback.openai_api_keys),
+            backups: self.backups.or(fallback.backups),
+            compress: self.compress.or(fallback.compress),
+        }
+    }
+}
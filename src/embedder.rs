@@ -0,0 +1,220 @@
+use crate::openai::{self, EmbeddingRequest};
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+
+/// Something that turns text into embedding vectors.
+pub trait Embedder {
+    /// The name of the model producing the embeddings.
+    ///
+    /// Stores record this so that embeddings from different models are never mixed.
+    fn model(&self) -> &str;
+
+    /// Embed a single text.
+    fn embed(&self, text: &str) -> Result<Vec<f32>>;
+
+    /// Embed many texts, returning the embeddings in the same order as `texts`.
+    ///
+    /// Providers that support batched requests should override this.
+    fn embed_batch(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        texts.iter().map(|text| self.embed(text)).collect()
+    }
+
+    /// Embed many texts like `embed_batch`, along with the number of tokens the provider billed
+    /// for them.
+    ///
+    /// Providers that report usage should override this. The rest report `None`.
+    fn embed_batch_with_usage(&self, texts: &[String]) -> Result<(Vec<Vec<f32>>, Option<u64>)> {
+        Ok((self.embed_batch(texts)?, None))
+    }
+
+    /// The exact revision of the model that the provider reported for the last request, if it
+    /// reports one.
+    ///
+    /// Providers may update the model behind a name, which changes its embeddings.
+    fn model_revision(&self) -> Option<String> {
+        None
+    }
+}
+
+/// The embedding providers that `mem` can use.
+#[derive(Clone, Copy, Debug, Default, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Provider {
+    #[default]
+    #[value(name = "openai")]
+    OpenAi,
+    Ollama,
+}
+
+impl Provider {
+    /// The model used for new stores when no model is requested.
+    pub fn default_model(&self) -> &'static str {
+        match self {
+            Provider::OpenAi => "text-embedding-ada-002",
+            Provider::Ollama => "nomic-embed-text",
+        }
+    }
+}
+
+/// Embeds text using the OpenAI API.
+pub struct OpenAiEmbedder {
+    client: openai::Client,
+    model: String,
+    /// The model the API reported for the last request.
+    revision: RefCell<Option<String>>,
+}
+
+impl OpenAiEmbedder {
+    pub fn new(client: openai::Client, model: String) -> OpenAiEmbedder {
+        OpenAiEmbedder {
+            client,
+            model,
+            revision: RefCell::new(None),
+        }
+    }
+}
+
+impl Embedder for OpenAiEmbedder {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn embed(&self, text: &str) -> Result<Vec<f32>> {
+        self.embed_batch(&[text.to_owned()])?
+            .into_iter()
+            .next()
+            .context("The OpenAI API returned no embedding.")
+    }
+
+    fn embed_batch(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        Ok(self.embed_batch_with_usage(texts)?.0)
+    }
+
+    /// Embed many texts with a single OpenAI API request.
+    fn embed_batch_with_usage(&self, texts: &[String]) -> Result<(Vec<Vec<f32>>, Option<u64>)> {
+        let req = EmbeddingRequest {
+            model: &self.model,
+            input: texts,
+        };
+        let mut res = self
+            .client
+            .embedding(&req)
+            .context("Failed to get embedding from OpenAI API.")?;
+        if res.data.is_empty() && !texts.is_empty() {
+            // the API answers some failures with an empty list instead of an error status
+            return Err(anyhow::anyhow!(
+                "The OpenAI API returned no embeddings. Expected: {}",
+                texts.len()
+            ));
+        }
+        if res.data.len() != texts.len() {
+            return Err(anyhow::anyhow!(
+                "Wrong number of embeddings. Expected: {}, Got: {}",
+                texts.len(),
+                res.data.len()
+            ));
+        }
+        // the API does not promise to return embeddings in input order
+        res.data.sort_by_key(|data| data.index);
+        let tokens = res.usage.map(|usage| usage.total_tokens);
+        if res.model.is_some() {
+            *self.revision.borrow_mut() = res.model;
+        }
+        Ok((
+            res.data.into_iter().map(|data| data.embedding).collect(),
+            tokens,
+        ))
+    }
+
+    fn model_revision(&self) -> Option<String> {
+        self.revision.borrow().clone()
+    }
+}
+
+/// Embeds text using a local Ollama server, so that memories never leave the machine.
+pub struct OllamaEmbedder {
+    agent: ureq::Agent,
+    base_url: String,
+    model: String,
+}
+
+#[derive(Serialize)]
+struct OllamaEmbeddingRequest<'a> {
+    model: &'a str,
+    prompt: &'a str,
+}
+
+#[derive(Deserialize)]
+struct OllamaEmbeddingResponse {
+    embedding: Vec<f32>,
+}
+
+impl OllamaEmbedder {
+    pub const DEFAULT_BASE_URL: &'static str = "http://localhost:11434";
+
+    pub fn new(base_url: String, model: String) -> OllamaEmbedder {
+        OllamaEmbedder {
+            agent: ureq::AgentBuilder::new().build(),
+            base_url,
+            model,
+        }
+    }
+}
+
+impl Embedder for OllamaEmbedder {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn embed(&self, text: &str) -> Result<Vec<f32>> {
+        let url = format!("{}/api/embeddings", self.base_url.trim_end_matches('/'));
+        let req = OllamaEmbeddingRequest {
+            model: &self.model,
+            prompt: text,
+        };
+        let response = match self.agent.post(&url).send_json(&req) {
+            Ok(response) => response,
+            Err(ureq::Error::Status(code, response)) => {
+                let body = response.into_string().unwrap_or_default();
+                return Err(anyhow::anyhow!("Ollama returned status {}: {}", code, body));
+            }
+            Err(err) => {
+                return Err(anyhow::Error::new(err).context(format!(
+                    "Failed to connect to Ollama at {}. Is Ollama running?",
+                    self.base_url
+                )))
+            }
+        };
+        let res: OllamaEmbeddingResponse = response
+            .into_json()
+            .context("Failed to parse embedding from Ollama.")?;
+        Ok(res.embedding)
+    }
+}
+
+/// Stands in for an embedder that couldn't be created, failing once an embedding is needed.
+///
+/// This lets commands that never embed anything, like counting memories, run without an API
+/// key.
+pub struct UnavailableEmbedder {
+    model: String,
+    reason: String,
+}
+
+impl UnavailableEmbedder {
+    pub fn new(model: String, reason: String) -> UnavailableEmbedder {
+        UnavailableEmbedder { model, reason }
+    }
+}
+
+impl Embedder for UnavailableEmbedder {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn embed(&self, _text: &str) -> Result<Vec<f32>> {
+        Err(anyhow::anyhow!("{}", self.reason))
+    }
+}
@@ -0,0 +1,69 @@
+use crate::store::Memory;
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use std::path::Path;
+
+/// The format of a file of memories to import.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum ImportFormat {
+    /// One memory per line as `description<TAB>memory`
+    Tsv,
+    /// One JSON object per line with `value`, `description` and optional `tags` keys
+    Jsonl,
+    /// A JSON array of objects with `value`, `description` and optional `tags` keys, as
+    /// written by `mem export`
+    Json,
+}
+
+impl ImportFormat {
+    /// Guess the format of a file from its extension, defaulting to TSV.
+    pub fn from_path(path: &Path) -> ImportFormat {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => ImportFormat::Json,
+            Some("jsonl") => ImportFormat::Jsonl,
+            _ => ImportFormat::Tsv,
+        }
+    }
+}
+
+/// Read memories from the file at `path`.
+///
+/// Blank lines are skipped in line-based formats.
+pub fn read_memories(path: &Path, format: ImportFormat) -> Result<Vec<Memory>> {
+    let contents = std::fs::read_to_string(path)
+        .context(format!("Failed to read import file {}", path.display()))?;
+    if let ImportFormat::Json = format {
+        return Ok(serde_json::from_str(&contents)?);
+    }
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            parse_line(line, format).context(format!("Failed to parse line {}.", i + 1))
+        })
+        .collect()
+}
+
+fn parse_line(line: &str, format: ImportFormat) -> Result<Memory> {
+    match format {
+        ImportFormat::Tsv => {
+            let (description, value) = line
+                .split_once('\t')
+                .context("Expected a tab between the description and the memory.")?;
+            Ok(Memory {
+                value: value.to_string(),
+                description: description.to_string(),
+                tags: vec![],
+                created_at: None,
+                embeds_value: false,
+                archived: false,
+                chunk_words: None,
+                metadata: None,
+                id: None,
+            })
+        }
+        ImportFormat::Jsonl => Ok(serde_json::from_str(line)?),
+        ImportFormat::Json => unreachable!("JSON files are not parsed line by line"),
+    }
+}
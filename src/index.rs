@@ -0,0 +1,332 @@
+use anyhow::{Context, Result};
+use ndarray::{ArrayView1, ArrayView2};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+
+/// A hierarchical navigable small world graph for approximate nearest neighbor search.
+///
+/// The graph only stores row indices. The vectors stay in the store's embedding matrix, which
+/// is passed to every call, and are assumed to be normalized so that similarity is the dot
+/// product. Rows can only be appended; any other change to the matrix needs a new index.
+pub(crate) struct HnswIndex {
+    /// The maximum number of neighbors per node above layer 0. Layer 0 allows twice as many.
+    m: usize,
+    /// How many candidates to consider when connecting a new node.
+    ef_construction: usize,
+    entry_point: Option<usize>,
+    /// `neighbors[node][layer]` are the neighbors of `node` on `layer`.
+    neighbors: Vec<Vec<Vec<u32>>>,
+    rng_state: u64,
+}
+
+/// A node and its similarity to the query, ordered by similarity.
+#[derive(Clone, Copy, Debug)]
+struct Candidate {
+    score: f32,
+    node: usize,
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.score
+            .total_cmp(&other.score)
+            .then(self.node.cmp(&other.node))
+    }
+}
+
+impl HnswIndex {
+    const MAGIC: &'static [u8; 8] = b"MEMHNSW1";
+    const NO_ENTRY_POINT: u64 = u64::MAX;
+
+    pub fn new(m: usize, ef_construction: usize) -> HnswIndex {
+        HnswIndex {
+            m,
+            ef_construction,
+            entry_point: None,
+            neighbors: vec![],
+            rng_state: 0x2545_f491_4f6c_dd1d,
+        }
+    }
+
+    /// The number of rows in the index.
+    pub fn len(&self) -> usize {
+        self.neighbors.len()
+    }
+
+    /// Add the next row of `embeddings`, i.e. row `self.len()`, to the index.
+    pub fn insert(&mut self, embeddings: ArrayView2<f32>) {
+        let node = self.neighbors.len();
+        let query = embeddings.row(node);
+        let level = self.random_level();
+        self.neighbors.push(vec![vec![]; level + 1]);
+        let Some(entry_point) = self.entry_point else {
+            self.entry_point = Some(node);
+            return;
+        };
+        let max_level = self.max_level();
+        let mut entry_points = vec![Candidate {
+            score: embeddings.row(entry_point).dot(&query),
+            node: entry_point,
+        }];
+        for layer in (level + 1..=max_level).rev() {
+            entry_points = self.search_layer(embeddings, query, &entry_points, 1, layer);
+        }
+        for layer in (0..=level.min(max_level)).rev() {
+            let found = self.search_layer(
+                embeddings,
+                query,
+                &entry_points,
+                self.ef_construction,
+                layer,
+            );
+            let selected: Vec<u32> = found
+                .iter()
+                .take(self.m)
+                .map(|candidate| candidate.node as u32)
+                .collect();
+            for &neighbor in &selected {
+                self.connect(embeddings, neighbor as usize, node, layer);
+            }
+            self.neighbors[node][layer] = selected;
+            entry_points = found;
+        }
+        if level > max_level {
+            self.entry_point = Some(node);
+        }
+    }
+
+    /// Find the `k` rows most similar to `query`, best first, as `(index, score)` pairs.
+    ///
+    /// `ef` trades speed for recall: more candidates are explored the larger it is.
+    pub fn search(
+        &self,
+        embeddings: ArrayView2<f32>,
+        query: ArrayView1<f32>,
+        k: usize,
+        ef: usize,
+    ) -> Vec<(usize, f32)> {
+        let Some(entry_point) = self.entry_point else {
+            return vec![];
+        };
+        let mut entry_points = vec![Candidate {
+            score: embeddings.row(entry_point).dot(&query),
+            node: entry_point,
+        }];
+        for layer in (1..=self.max_level()).rev() {
+            entry_points = self.search_layer(embeddings, query, &entry_points, 1, layer);
+        }
+        let mut found = self.search_layer(embeddings, query, &entry_points, ef.max(k), 0);
+        found.truncate(k);
+        found
+            .into_iter()
+            .map(|candidate| (candidate.node, candidate.score))
+            .collect()
+    }
+
+    /// Greedily search one layer of the graph for the `ef` nodes most similar to `query`.
+    ///
+    /// Returns the nodes best first.
+    fn search_layer(
+        &self,
+        embeddings: ArrayView2<f32>,
+        query: ArrayView1<f32>,
+        entry_points: &[Candidate],
+        ef: usize,
+        layer: usize,
+    ) -> Vec<Candidate> {
+        let mut visited: HashSet<usize> = entry_points.iter().map(|c| c.node).collect();
+        let mut candidates: BinaryHeap<Candidate> = entry_points.iter().copied().collect();
+        // a min-heap, so the worst result is always on top
+        let mut results: BinaryHeap<Reverse<Candidate>> =
+            entry_points.iter().copied().map(Reverse).collect();
+        while results.len() > ef {
+            results.pop();
+        }
+        while let Some(candidate) = candidates.pop() {
+            let Some(&Reverse(worst)) = results.peek() else {
+                break;
+            };
+            if results.len() >= ef && candidate.score < worst.score {
+                break;
+            }
+            let Some(neighbors) = self.neighbors[candidate.node].get(layer) else {
+                continue;
+            };
+            for &neighbor in neighbors {
+                let neighbor = neighbor as usize;
+                if !visited.insert(neighbor) {
+                    continue;
+                }
+                let score = embeddings.row(neighbor).dot(&query);
+                let is_better = match results.peek() {
+                    Some(Reverse(worst)) => score > worst.score,
+                    None => true,
+                };
+                if results.len() < ef || is_better {
+                    let neighbor = Candidate {
+                        score,
+                        node: neighbor,
+                    };
+                    candidates.push(neighbor);
+                    results.push(Reverse(neighbor));
+                    if results.len() > ef {
+                        results.pop();
+                    }
+                }
+            }
+        }
+        let mut results: Vec<Candidate> = results.into_iter().map(|Reverse(c)| c).collect();
+        results.sort_by(|a, b| b.cmp(a));
+        results
+    }
+
+    /// Link `from` to `to` on `layer`, dropping `from`'s least similar neighbor if it has too many.
+    fn connect(&mut self, embeddings: ArrayView2<f32>, from: usize, to: usize, layer: usize) {
+        let max_neighbors = if layer == 0 { 2 * self.m } else { self.m };
+        let neighbors = &mut self.neighbors[from][layer];
+        neighbors.push(to as u32);
+        if neighbors.len() > max_neighbors {
+            let from_row = embeddings.row(from);
+            let mut scored: Vec<(f32, u32)> = neighbors
+                .iter()
+                .map(|&neighbor| (embeddings.row(neighbor as usize).dot(&from_row), neighbor))
+                .collect();
+            scored.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+            scored.truncate(max_neighbors);
+            *neighbors = scored.into_iter().map(|(_, neighbor)| neighbor).collect();
+        }
+    }
+
+    fn max_level(&self) -> usize {
+        self.entry_point
+            .map_or(0, |entry_point| self.neighbors[entry_point].len() - 1)
+    }
+
+    /// Draw a level from an exponentially decaying distribution, as in the HNSW paper.
+    fn random_level(&mut self) -> usize {
+        // xorshift64, which is plenty for picking levels
+        self.rng_state ^= self.rng_state << 13;
+        self.rng_state ^= self.rng_state >> 7;
+        self.rng_state ^= self.rng_state << 17;
+        // uniform in (0, 1]
+        let uniform = ((self.rng_state >> 11) as f64 + 1.0) / (1u64 << 53) as f64;
+        let level_multiplier = 1.0 / (self.m as f64).ln();
+        (-uniform.ln() * level_multiplier).floor() as usize
+    }
+}
+
+/// Reading and writing the index as a compact little-endian binary file.
+impl HnswIndex {
+    /// Read the index from `path`, or `None` if the file doesn't exist.
+    pub fn read(path: &Path) -> Result<Option<HnswIndex>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let mut reader = BufReader::new(file);
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != Self::MAGIC {
+            return Err(anyhow::anyhow!("Not an index file: {}", path.display()));
+        }
+        let m = read_u64(&mut reader)? as usize;
+        let ef_construction = read_u64(&mut reader)? as usize;
+        let entry_point = match read_u64(&mut reader)? {
+            Self::NO_ENTRY_POINT => None,
+            entry_point => Some(entry_point as usize),
+        };
+        let rng_state = read_u64(&mut reader)?;
+        let node_count = read_u64(&mut reader)? as usize;
+        let mut neighbors = Vec::with_capacity(node_count);
+        for _ in 0..node_count {
+            let layer_count = read_u32(&mut reader)? as usize;
+            let mut layers = Vec::with_capacity(layer_count);
+            for _ in 0..layer_count {
+                let neighbor_count = read_u32(&mut reader)? as usize;
+                let layer = (0..neighbor_count)
+                    .map(|_| read_u32(&mut reader))
+                    .collect::<Result<Vec<u32>>>()?;
+                if layer
+                    .iter()
+                    .any(|&neighbor| neighbor as usize >= node_count)
+                {
+                    return Err(anyhow::anyhow!("Corrupt index file: {}", path.display()));
+                }
+                layers.push(layer);
+            }
+            neighbors.push(layers);
+        }
+        if let Some(entry_point) = entry_point {
+            if !matches!(neighbors.get(entry_point), Some(layers) if !layers.is_empty()) {
+                return Err(anyhow::anyhow!("Corrupt index file: {}", path.display()));
+            }
+        }
+        Ok(Some(HnswIndex {
+            m,
+            ef_construction,
+            entry_point,
+            neighbors,
+            rng_state,
+        }))
+    }
+
+    /// Write the index to `path`, replacing any existing file.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let file = File::create(path).context("Failed to create index file.")?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(Self::MAGIC)?;
+        let entry_point = self
+            .entry_point
+            .map_or(Self::NO_ENTRY_POINT, |entry_point| entry_point as u64);
+        for value in [
+            self.m as u64,
+            self.ef_construction as u64,
+            entry_point,
+            self.rng_state,
+            self.neighbors.len() as u64,
+        ] {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        for layers in &self.neighbors {
+            writer.write_all(&(layers.len() as u32).to_le_bytes())?;
+            for layer in layers {
+                writer.write_all(&(layer.len() as u32).to_le_bytes())?;
+                for neighbor in layer {
+                    writer.write_all(&neighbor.to_le_bytes())?;
+                }
+            }
+        }
+        writer.into_inner()?.sync_all()?;
+        Ok(())
+    }
+}
+
+fn read_u64(reader: &mut impl Read) -> Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
@@ -0,0 +1,20 @@
+//! A semantic memory store.
+//!
+//! Memories are retrieved by the similarity of their description to a query. The `mem` binary
+//! is a CLI over `store::MemoryStore`, which can also be used directly.
+
+// links the BLAS library that ndarray's matrix products call into
+#[cfg(feature = "blas")]
+extern crate blas_src;
+
+mod bm25;
+pub mod cache;
+pub mod config;
+pub mod embedder;
+pub mod import;
+mod index;
+pub mod openai;
+pub mod quantize;
+pub mod rerank;
+pub mod scoring;
+pub mod store;
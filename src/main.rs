@@ -1,93 +1,1418 @@
-use clap::{Parser, Subcommand};
-use std::io::{stdin, stdout, Write};
+use chrono::Utc;
+use clap::{ArgAction, CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use indicatif::{ProgressBar, ProgressStyle};
+use std::fs::File;
+use std::io::{stderr, stdin, stdout, BufWriter, IsTerminal, Read, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
 
-mod store;
+mod mcp;
+mod output;
+mod serve;
+mod watch;
 
-use store::MemoryStore;
+use mem::config::Config;
+use mem::embedder::Provider;
+use mem::import::{self, ImportFormat};
+use mem::openai::{self, RetryPolicy};
+use mem::quantize::Quantization;
+use mem::rerank::LlmReranker;
+use mem::scoring::Metric;
+use mem::store::{
+    Backend, Filter, InsertOutcome, LockMode, Memory, MemoryStore, ScoredMemory, SortBy,
+    StoreOptions, TieResult,
+};
+use output::{Output, OutputFormat};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct MemCli {
     #[command(subcommand)]
     command: MemCommand,
+    /// Log what mem is doing to stderr. Repeat for more detail, e.g. -vv
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    verbose: u8,
+    /// Fail immediately instead of waiting if another mem process is using the store
+    #[arg(long, global = true)]
+    no_wait: bool,
+    /// The embedding model to use. Defaults to the model the store was built with
+    #[arg(long, global = true, value_name = "MODEL", env = "MEM_MODEL")]
+    model: Option<String>,
+    /// The embedding provider to use. Defaults to openai
+    #[arg(long, global = true, value_enum, env = "MEM_PROVIDER")]
+    provider: Option<Provider>,
+    /// The URL of the Ollama server used by the ollama provider
+    #[arg(long, global = true, value_name = "URL")]
+    ollama_url: Option<String>,
+    /// The URL of the OpenAI-compatible API used by the openai provider, e.g. a proxy
+    #[arg(long, global = true, value_name = "URL")]
+    base_url: Option<String>,
+    /// Back up the store before every change, keeping this many backups (0 disables them)
+    #[arg(long, global = true, value_name = "COUNT")]
+    backups: Option<usize>,
+    /// The maximum number of query embeddings to cache (0 disables the cache)
+    #[arg(long, global = true, value_name = "COUNT", default_value_t = 64)]
+    cache_size: usize,
+    /// Print results as JSON
+    #[arg(long, global = true)]
+    json: bool,
+    /// Print the description each memory was matched on after its value
+    #[arg(long, global = true)]
+    show_description: bool,
+    /// Don't print status messages like "Memory inserted!", only results and errors
+    #[arg(short, long, global = true)]
+    quiet: bool,
+    /// The data directory. Defaults to ~/.mem
+    #[arg(long, global = true, value_name = "PATH", env = "MEM_DATA_DIR")]
+    data_dir: Option<PathBuf>,
+    /// The named store to use. Defaults to "default"
+    #[arg(long, global = true, value_name = "NAME")]
+    store: Option<String>,
+    /// Score every memory instead of using the approximate index of large stores
+    #[arg(long, global = true)]
+    exact: bool,
+    /// Fail unless the provider embeds with this exact model revision, e.g. a dated snapshot
+    #[arg(long, global = true, value_name = "REVISION")]
+    model_revision: Option<String>,
+    /// Use the store even if the model's revision changed since it was built
+    #[arg(long, global = true)]
+    force: bool,
+    /// How memories are scored. Only an empty store can change it. Defaults to the metric the
+    /// store was built with, or cosine
+    #[arg(long, global = true, value_enum, env = "MEM_METRIC")]
+    metric: Option<Metric>,
+    /// How embeddings are stored, e.g. f16 or int8 to shrink them. Only an empty store can change
+    /// it. Defaults to the quantization the store was built with, or f32
+    #[arg(long, global = true, value_enum)]
+    quantize: Option<Quantization>,
+    /// Where a new store keeps its memories. sqlite needs mem to be built with the sqlite
+    /// feature. Existing stores are converted with `mem --backend <BACKEND> migrate`
+    #[arg(long, global = true, value_enum)]
+    backend: Option<Backend>,
+    /// How queries rank memories
+    #[arg(long, global = true, value_enum, default_value_t = Ranker::Semantic)]
+    ranker: Ranker,
+    /// The weight of embedding scores against BM25 keyword scores with --ranker hybrid, from 0
+    /// to 1
+    #[arg(long, global = true, value_name = "ALPHA", default_value_t = 0.5)]
+    alpha: f32,
+    /// How many times to retry OpenAI API requests that hit a rate limit or a transient error
+    #[arg(long, global = true, value_name = "COUNT", default_value_t = 3)]
+    max_retries: u32,
+    /// The delay before the first retry, doubled with every retry, e.g. "500ms" or "2s"
+    #[arg(long, global = true, value_name = "DURATION", default_value = "500ms", value_parser = humantime::parse_duration)]
+    retry_delay: Duration,
+    /// How long an OpenAI API request may take, e.g. "30s"
+    #[arg(long, global = true, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    timeout: Option<Duration>,
+    /// Don't call the embedding provider; only queries with a cached embedding work
+    #[arg(long, global = true)]
+    offline: bool,
+    /// Show what insert, import, delete and update would do without calling the API or
+    /// changing the store
+    #[arg(long, global = true)]
+    dry_run: bool,
+}
+
+/// How queries rank memories.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Ranker {
+    /// By embedding score alone.
+    Semantic,
+    /// By a blend of embedding and BM25 keyword scores, for keyword-heavy queries.
+    Hybrid,
 }
 
 #[derive(Subcommand, Debug)]
 enum MemCommand {
     /// Insert a memory into the store
+    ///
+    /// With --stdin or --file, only the description is passed as an argument.
     Insert {
         /// The memory to store
         #[arg(value_name = "MEMORY")]
-        mem: String,
+        mem: Option<String>,
         /// A description of the memory that is used for semantic retrieval
         #[arg(value_name = "DESCRIPTION")]
-        description: String,
+        description: Option<String>,
+        /// Read the memory from stdin until EOF
+        #[arg(long, conflicts_with = "file")]
+        stdin: bool,
+        /// Read the memory from a file
+        #[arg(long, value_name = "PATH")]
+        file: Option<PathBuf>,
+        /// Don't insert the memory if one with a description at least this similar exists
+        #[arg(
+            long,
+            value_name = "THRESHOLD",
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = "0.97"
+        )]
+        dedup: Option<f32>,
+        /// A tag to label the memory with (can be repeated)
+        #[arg(long = "tag", value_name = "TAG")]
+        tags: Vec<String>,
+        /// Embed the memory along with its description, so that queries can match either
+        #[arg(long)]
+        include_value: bool,
+        /// Also embed a long memory in chunks of this many words, so that queries can match any
+        /// part of it
+        #[arg(long, value_name = "WORDS", conflicts_with = "dedup")]
+        chunk_words: Option<usize>,
+        /// Structured data to store with the memory as JSON, e.g. '{"source": "https://..."}'
+        #[arg(long, value_name = "JSON", conflicts_with = "dedup", value_parser = parse_metadata)]
+        meta: Option<serde_json::Value>,
+        /// A key for the memory. Inserting a memory with the key of an existing one replaces it
+        /// instead of adding another
+        #[arg(long, value_name = "KEY", conflicts_with = "dedup")]
+        id: Option<String>,
     },
     /// Get a memory from the store
     Get {
         /// A description of the memory you are looking for
-        #[arg(value_name = "DESCRIPTION")]
-        description: String,
+        #[arg(value_name = "DESCRIPTION", required_unless_present = "id")]
+        description: Option<String>,
+        /// Get the memory inserted with this key instead of searching by description, unless it
+        /// is archived
+        #[arg(
+            long,
+            value_name = "KEY",
+            conflicts_with_all = [
+                "description",
+                "threshold",
+                "tags",
+                "min_length",
+                "max_length",
+                "exclude",
+                "recency_halflife",
+                "tie_epsilon",
+                "explain",
+                "hybrid",
+                "context",
+                "json_embedding",
+                "rerank"
+            ]
+        )]
+        id: Option<String>,
+        /// The minimum score required for a match, or the maximum distance with --metric euclidean
+        #[arg(short, long, value_name = "THRESHOLD")]
+        threshold: Option<f32>,
+        /// Only consider memories with this tag (can be repeated)
+        #[arg(long = "tag", value_name = "TAG")]
+        tags: Vec<String>,
+        /// Only consider memories whose value has at least this many characters
+        #[arg(long, value_name = "CHARS")]
+        min_length: Option<usize>,
+        /// Only consider memories whose value has at most this many characters
+        #[arg(long, value_name = "CHARS")]
+        max_length: Option<usize>,
+        /// Leave out the memory at this index, as listed by `list` (can be repeated)
+        #[arg(long, value_name = "INDEX")]
+        exclude: Vec<usize>,
+        /// Favor recent memories by halving scores every time memories get this much older,
+        /// e.g. "30days"
+        #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+        recency_halflife: Option<Duration>,
+        /// Fail if another memory scores within this much of the best match
+        #[arg(long, value_name = "EPSILON")]
+        tie_epsilon: Option<f32>,
+        /// Show which phrases of the description drove the match
+        #[arg(long)]
+        explain: bool,
+        /// Fall back to matching the text of memories if no memory scores at least this much
+        #[arg(
+            long,
+            value_name = "THRESHOLD",
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = "0.75",
+            conflicts_with = "tie_epsilon"
+        )]
+        hybrid: Option<f32>,
+        /// Also show the COUNT memories inserted right before and after the match
+        #[arg(
+            long,
+            value_name = "COUNT",
+            conflicts_with_all = ["tie_epsilon", "hybrid", "explain"]
+        )]
+        context: Option<usize>,
+        /// Print the query embedding as JSON instead of matching it, or write it to PATH
+        #[arg(
+            long,
+            value_name = "PATH",
+            num_args = 0..=1,
+            require_equals = true,
+            conflicts_with_all = ["threshold", "tie_epsilon", "hybrid", "context", "explain"]
+        )]
+        json_embedding: Option<Option<PathBuf>>,
+        /// Ask an OpenAI chat model which of the best matches by embedding fits the description
+        /// best
+        #[arg(
+            long,
+            conflicts_with_all = ["tie_epsilon", "hybrid", "context", "json_embedding"]
+        )]
+        rerank: bool,
+        /// The number of best matches by embedding to rerank
+        #[arg(long, value_name = "K", default_value_t = 20, requires = "rerank")]
+        rerank_k: usize,
+        /// The chat model that reranks matches
+        #[arg(
+            long,
+            value_name = "MODEL",
+            default_value_t = LlmReranker::DEFAULT_MODEL.to_owned(),
+            requires = "rerank"
+        )]
+        rerank_model: String,
+        /// Print every result with this template, e.g. '{value}' (also {description}, {score}
+        /// and {index})
+        #[arg(long, value_name = "TEMPLATE", value_parser = Output::validate_template)]
+        format: Option<String>,
     },
     /// List memories from the store
     List {
         /// The maximum number of memories to list
         #[arg(short, long, value_name = "COUNT", default_value_t = 10)]
-        count: u8,
+        count: usize,
+        /// List every memory, sorted by score
+        #[arg(short, long, conflicts_with = "count")]
+        all: bool,
+        /// Skip this many of the best matches, to list the ones after them
+        #[arg(long, value_name = "N", default_value_t = 0)]
+        offset: usize,
+        /// List this page of --count memories, starting from 1. Same as --offset (PAGE - 1) * COUNT
+        #[arg(
+            long,
+            value_name = "PAGE",
+            value_parser = clap::value_parser!(usize).range(1..),
+            conflicts_with_all = ["offset", "all"]
+        )]
+        page: Option<usize>,
+        /// Only consider memories with this tag (can be repeated)
+        #[arg(long = "tag", value_name = "TAG")]
+        tags: Vec<String>,
+        /// Only consider memories inserted within this long, e.g. "7days" or "12h"
+        #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+        since: Option<Duration>,
+        /// Only consider memories whose value has at least this many characters
+        #[arg(long, value_name = "CHARS")]
+        min_length: Option<usize>,
+        /// Only consider memories whose value has at most this many characters
+        #[arg(long, value_name = "CHARS")]
+        max_length: Option<usize>,
+        /// Leave out the memory at this index, as listed by `list` (can be repeated)
+        #[arg(long, value_name = "INDEX")]
+        exclude: Vec<usize>,
+        /// Favor recent memories by halving scores every time memories get this much older,
+        /// e.g. "30days"
+        #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+        recency_halflife: Option<Duration>,
+        /// The order to list memories in
+        #[arg(long, value_enum, default_value_t = SortBy::Score)]
+        sort_by: SortBy,
+        /// Pick memories that match well but don't repeat each other, by maximal marginal
+        /// relevance
+        #[arg(long, conflicts_with = "sort_by")]
+        mmr: bool,
+        /// The weight of scores against diversity with --mmr, from 0 (most diverse) to 1 (by
+        /// score alone)
+        #[arg(long, value_name = "LAMBDA", default_value_t = 0.5, requires = "mmr")]
+        lambda: f32,
+        /// Show which phrases of each description drove the match
+        #[arg(long)]
+        explain: bool,
+        /// List archived memories instead
+        #[arg(long)]
+        archived: bool,
+        /// Leave out memories scoring below this, or farther than this with --metric euclidean
+        #[arg(short, long, value_name = "THRESHOLD")]
+        threshold: Option<f32>,
+        /// Print every result with this template, e.g. '{value}' (also {description}, {score}
+        /// and {index})
+        #[arg(long, value_name = "TEMPLATE", value_parser = Output::validate_template)]
+        format: Option<String>,
+        /// A description of the memory you are looking for
+        #[arg(value_name = "DESCRIPTION")]
+        description: String,
+    },
+    /// Archive the best matching memory, so that it can be restored, or delete it for good
+    Delete {
+        /// A description of the memory you want to delete
+        #[arg(value_name = "DESCRIPTION", required_unless_present_any = ["index", "id"])]
+        description: Option<String>,
+        /// Delete the memory at this index instead of searching by description
+        #[arg(short, long, value_name = "INDEX", conflicts_with = "description")]
+        index: Option<usize>,
+        /// Delete the memory inserted with this key instead of searching by description
+        #[arg(long, value_name = "KEY", conflicts_with_all = ["description", "index"])]
+        id: Option<String>,
+        /// Delete the memory for good instead of archiving it
+        #[arg(long)]
+        purge: bool,
+    },
+    /// Restore an archived memory
+    Restore {
+        /// The index of the archived memory, as shown by `mem list --archived`
+        #[arg(short, long, value_name = "INDEX", required_unless_present = "backup")]
+        index: Option<usize>,
+        /// Replace the whole store with this backup instead, as shown by `mem backups list`
+        #[arg(long, value_name = "ID", conflicts_with = "index")]
+        backup: Option<String>,
+    },
+    /// Update a memory in the store
+    Update {
+        /// The index of the memory to update
+        #[arg(value_name = "INDEX")]
+        index: usize,
+        /// The new memory
+        #[arg(short, long = "memory", value_name = "MEMORY")]
+        mem: Option<String>,
+        /// The new description of the memory
+        #[arg(short, long, value_name = "DESCRIPTION")]
+        description: Option<String>,
+    },
+    /// Find memories containing some text, without embedding anything
+    Find {
+        /// The text to look for in values and descriptions
+        #[arg(value_name = "PATTERN")]
+        pattern: String,
+        /// Treat the pattern as a regular expression
+        #[arg(short, long)]
+        regex: bool,
+    },
+    /// Score how similar two descriptions are, without changing the store
+    Score {
+        /// The description to score
+        #[arg(value_name = "DESCRIPTION")]
+        description: String,
+        /// The query to score it against
+        #[arg(long, value_name = "QUERY")]
+        against: String,
+    },
+    /// Count the memories in the store
+    Count {
+        /// Only count memories with this tag (can be repeated)
+        #[arg(long = "tag", value_name = "TAG")]
+        tags: Vec<String>,
+        /// Only count memories inserted within this long, e.g. "7days" or "12h"
+        #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+        since: Option<Duration>,
+        /// Only count memories whose value has at least this many characters
+        #[arg(long, value_name = "CHARS")]
+        min_length: Option<usize>,
+        /// Only count memories whose value has at most this many characters
+        #[arg(long, value_name = "CHARS")]
+        max_length: Option<usize>,
+        /// Count archived memories instead
+        #[arg(long)]
+        archived: bool,
+    },
+    /// Show the store's settings, size and how similar its memories are to each other
+    Stats,
+    /// Find memories with near-duplicate descriptions
+    Dedup {
+        /// List the clusters of near-duplicates so that they can be cleaned up by hand
+        #[arg(long, required = true)]
+        report: bool,
+        /// The minimum score between two descriptions for them to count as duplicates
+        #[arg(short, long, value_name = "THRESHOLD", default_value_t = 0.97)]
+        threshold: f32,
+    },
+    /// Delete every memory in the store
+    Clear {
+        /// Don't ask for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+    /// Check the store for problems
+    Doctor {
+        /// Fix the problems found by dropping memories or embeddings that can't be recovered
+        #[arg(long)]
+        fix: bool,
+    },
+    /// Move the embeddings of an old store out of store.json into the binary embeddings file,
+    /// or convert the store to --backend
+    Migrate,
+    /// Embed every memory again, e.g. with a new model
+    Reembed {
+        /// The model to embed with. Defaults to --model, or else the store's model
+        #[arg(value_name = "MODEL")]
+        model: Option<String>,
+    },
+    /// Import memories from a file
+    Import {
+        /// The file to import memories from
+        #[arg(value_name = "PATH")]
+        path: PathBuf,
+        /// The format of the file. Guessed from the file extension by default
+        #[arg(short, long, value_enum)]
+        format: Option<ImportFormat>,
+        /// Embed every memory along with its description, so that queries can match either
+        #[arg(long)]
+        include_value: bool,
+    },
+    /// Append the memories of the store in another data directory, without re-embedding them
+    Merge {
+        /// The data directory of the other store, e.g. a copy of ~/.mem from another machine
+        #[arg(value_name = "DIR")]
+        other: PathBuf,
+        /// The name of the store to merge from the other data directory. Defaults to `default`
+        #[arg(long, value_name = "NAME")]
+        from_store: Option<String>,
+        /// Leave out memories with an embedding at least this similar to one in the store
+        #[arg(
+            long,
+            value_name = "THRESHOLD",
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = "0.97"
+        )]
+        dedup: Option<f32>,
+    },
+    /// Answer `GET /search?q=DESCRIPTION&k=COUNT` requests over HTTP with JSON lists of memories
+    Serve {
+        /// The port to listen on
+        #[arg(short, long, default_value_t = 8080)]
+        port: u16,
+        /// The address to listen on. Only local tools can connect by default
+        #[arg(long, value_name = "ADDRESS", default_value = "127.0.0.1")]
+        host: String,
+    },
+    /// Serve the store as insert, get and list tools to an MCP client over stdio
+    Mcp,
+    /// List memories from the store, and list them again whenever the store changes
+    #[command(alias = "tail")]
+    Watch {
+        /// The maximum number of memories to list
+        #[arg(short, long, value_name = "COUNT", default_value_t = 10)]
+        count: usize,
+        /// Only consider memories with this tag (can be repeated)
+        #[arg(long = "tag", value_name = "TAG")]
+        tags: Vec<String>,
         /// A description of the memory you are looking for
         #[arg(value_name = "DESCRIPTION")]
         description: String,
     },
+    /// Export every memory, without embeddings, as JSON
+    Export {
+        /// The file to write to. Defaults to stdout
+        #[arg(value_name = "PATH")]
+        path: Option<PathBuf>,
+    },
+    /// Manage the named stores in the data directory
+    Stores {
+        #[command(subcommand)]
+        command: StoresCommand,
+    },
+    /// Manage the rolling backups of the store, see --backups
+    Backups {
+        #[command(subcommand)]
+        command: BackupsCommand,
+    },
+    /// Inspect the configuration in config.toml
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Manage the query embedding cache
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommand,
+    },
     /// Set OpenAI API key
-    SetKey,
+    SetKey {
+        /// Store the key in the OS keychain instead of a file in the data directory
+        #[arg(long)]
+        keyring: bool,
+    },
+    /// Print a shell completion script, e.g. `mem completions zsh > ~/.zfunc/_mem`
+    Completions {
+        /// The shell to generate completions for
+        #[arg(value_enum)]
+        shell: Shell,
+    },
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+#[derive(Subcommand, Debug)]
+enum StoresCommand {
+    /// List every store
+    List,
+    /// Rename a store, keeping its memories
+    Rename {
+        /// The current name of the store
+        #[arg(value_name = "OLD")]
+        old_name: String,
+        /// The new name of the store, which must not be taken
+        #[arg(value_name = "NEW")]
+        new_name: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum BackupsCommand {
+    /// List the backups of the store, newest first
+    List,
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    /// Print the effective configuration, after flags and environment variables
+    Show,
+}
+
+#[derive(Subcommand, Debug)]
+enum CacheCommand {
+    /// Remove every cached query embedding
+    Clear,
+}
+
+/// Returned by `get` when no memory matched, which isn't an error but exits with its own code.
+#[derive(Debug)]
+struct NoMatch;
+
+impl std::fmt::Display for NoMatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "No memory found.")
+    }
+}
+
+impl std::error::Error for NoMatch {}
+
+/// Exit with 0 on success, 1 if `get` found no match and 2 on errors, so that scripts can tell
+/// them apart.
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) if err.is::<NoMatch>() => ExitCode::from(1),
+        Err(err) => {
+            eprintln!("Error: {err:?}");
+            ExitCode::from(2)
+        }
+    }
+}
+
+fn run() -> Result<(), Box<dyn std::error::Error>> {
     let cli = MemCli::parse();
+    init_logging(cli.verbose);
+    // flags and environment variables take precedence over config.toml
+    let config = Config {
+        provider: cli.provider,
+        model: cli.model.clone(),
+        metric: cli.metric,
+        data_dir: cli.data_dir.clone(),
+        ollama_url: cli.ollama_url.clone(),
+        base_url: cli.base_url.clone(),
+        openai_api_keys: None,
+        backups: cli.backups,
+        compress: None,
+    }
+    .or(Config::load(&MemoryStore::default_config_file_path(
+        cli.data_dir.as_deref(),
+    ))?);
+    let provider = config.provider.unwrap_or_default();
+    let data_dir = config.data_dir.as_deref();
+    let options = StoreOptions {
+        lock_mode: if cli.no_wait {
+            LockMode::NonBlocking
+        } else {
+            LockMode::Blocking
+        },
+        provider,
+        ollama_url: config.ollama_url.clone(),
+        openai_base_url: config.base_url.clone(),
+        openai_api_keys: config.openai_api_keys.clone().unwrap_or_default(),
+        query_cache_size: cli.cache_size,
+        model: config.model.clone(),
+        data_dir: config.data_dir.clone(),
+        store_name: cli.store.clone(),
+        exact: cli.exact,
+        retry_policy: RetryPolicy {
+            max_retries: cli.max_retries,
+            base_delay: cli.retry_delay,
+        },
+        timeout: cli.timeout,
+        offline: cli.offline,
+        metric: config.metric,
+        quantization: cli.quantize,
+        backend: cli.backend,
+        model_revision: cli.model_revision.clone(),
+        allow_model_drift: cli.force,
+        backup_count: config.backups.unwrap_or(0),
+        compress: config.compress.unwrap_or(false),
+        hybrid_alpha: match cli.ranker {
+            Ranker::Semantic => None,
+            Ranker::Hybrid => Some(cli.alpha),
+        },
+        recency_half_life: match &cli.command {
+            MemCommand::Get {
+                recency_halflife, ..
+            }
+            | MemCommand::List {
+                recency_halflife, ..
+            } => *recency_halflife,
+            _ => None,
+        },
+    };
+    let template = match &cli.command {
+        MemCommand::Get { format, .. } | MemCommand::List { format, .. } => format.clone(),
+        _ => None,
+    };
+    if cli.json && template.is_some() {
+        return Err("--format can't be combined with --json.".into());
+    }
+    let output = Output {
+        format: if cli.json {
+            OutputFormat::Json
+        } else {
+            OutputFormat::Human
+        },
+        show_description: cli.show_description,
+        quiet: cli.quiet,
+        template,
+    };
 
     match &cli.command {
-        MemCommand::Insert { mem, description } => {
-            let mut store = MemoryStore::load()?;
-            store.insert(mem, description)?;
-            println!("Memory inserted!");
-        }
-        MemCommand::Get { description } => {
-            let store = MemoryStore::load()?;
-            let memory = store.get(description)?;
-            if let Some(memory) = memory {
+        MemCommand::Insert {
+            mem,
+            description,
+            dedup,
+            tags,
+            include_value,
+            stdin: read_stdin,
+            file,
+            chunk_words,
+            meta,
+            id,
+        } => {
+            let from_input = *read_stdin || file.is_some();
+            let (mem, description) = match (mem, description) {
+                (Some(description), None) if from_input => {
+                    let mem = match file {
+                        Some(path) => std::fs::read_to_string(path)?,
+                        None => {
+                            let mut mem = String::new();
+                            stdin().read_to_string(&mut mem)?;
+                            mem
+                        }
+                    };
+                    (mem, description)
+                }
+                (Some(mem), Some(description)) if !from_input => (mem.clone(), description),
+                _ if from_input => {
+                    return Err("With --stdin or --file, pass only the description.".into())
+                }
+                _ => return Err("Pass both the memory and its description.".into()),
+            };
+            if cli.dry_run {
+                println!("Would insert {}", describe_memory(&mem, description));
+                return Ok(());
+            }
+            let mut store = MemoryStore::load(&options)?;
+            if let Some(dedup_threshold) = dedup {
+                match store.insert_checked(
+                    &mem,
+                    description,
+                    tags,
+                    *include_value,
+                    *dedup_threshold,
+                )? {
+                    InsertOutcome::Inserted => output.status("Memory inserted!"),
+                    InsertOutcome::Skipped { index } => {
+                        output.status(format!("Memory #{index} is a duplicate, skipping!"))
+                    }
+                    InsertOutcome::Merged { index } => output.status(format!(
+                        "Memory #{index} is a duplicate, updated its value!"
+                    )),
+                    InsertOutcome::Updated { .. } => unreachable!("only upserts update"),
+                }
+            } else if chunk_words.is_some() || meta.is_some() || id.is_some() {
+                let memory = Memory {
+                    id: id.clone(),
+                    value: mem,
+                    description: description.clone(),
+                    tags: tags.clone(),
+                    created_at: None,
+                    embeds_value: *include_value,
+                    archived: false,
+                    chunk_words: *chunk_words,
+                    metadata: meta.clone(),
+                };
+                if id.is_none() {
+                    store.insert_many(&[memory])?;
+                    output.status("Memory inserted!");
+                } else {
+                    match store.upsert(memory)? {
+                        InsertOutcome::Skipped { index } => {
+                            output.status(format!("Memory #{index} is unchanged, skipping!"))
+                        }
+                        InsertOutcome::Updated { index } => {
+                            output.status(format!("Memory #{index} updated!"))
+                        }
+                        InsertOutcome::Inserted => output.status("Memory inserted!"),
+                        InsertOutcome::Merged { .. } => unreachable!("only dedup merges"),
+                    }
+                }
+            } else {
+                store.insert(&mem, description, tags, *include_value)?;
+                output.status("Memory inserted!");
+            }
+            if let Some(usage) = store.embedding_usage() {
+                output.usage(&usage)?;
+            }
+        }
+        MemCommand::Get {
+            description,
+            id,
+            threshold,
+            tags,
+            min_length,
+            max_length,
+            exclude,
+            tie_epsilon,
+            explain,
+            hybrid,
+            context,
+            json_embedding,
+            rerank,
+            rerank_k,
+            rerank_model,
+            ..
+        } => {
+            let store = MemoryStore::load(&options)?;
+            if let Some(id) = id {
+                let memory = store.get_by_id(id)?;
+                output.memory(memory.as_ref())?;
+                if memory.is_none() {
+                    return Err(NoMatch.into());
+                }
+                return Ok(());
+            }
+            let description = description
+                .as_deref()
+                .expect("clap requires a description or an id");
+            let filter = Filter {
+                tags: tags.clone(),
+                min_length: *min_length,
+                max_length: *max_length,
+                exclude: exclude.clone(),
+                ..Filter::default()
+            };
+            if let Some(path) = json_embedding {
+                let embedding = store.query_embedding(description)?;
+                match path {
+                    Some(path) => {
+                        let writer = BufWriter::new(File::create(path)?);
+                        serde_json::to_writer(writer, &embedding)?;
+                        output.status(format!(
+                            "Wrote a {}-dimensional embedding!",
+                            embedding.len()
+                        ));
+                    }
+                    None => println!("{}", serde_json::to_string(&embedding)?),
+                }
+            } else if let Some(context) = context {
+                let window = store.get_with_context(description, *threshold, &filter, *context)?;
+                output.memories(&window)?;
+                if window.is_empty() {
+                    return Err(NoMatch.into());
+                }
+            } else if let Some(epsilon) = tie_epsilon {
+                match store.get_with_tie_detection(description, *threshold, *epsilon, &filter)? {
+                    TieResult::NotFound => {
+                        output.memory(None)?;
+                        return Err(NoMatch.into());
+                    }
+                    TieResult::Unique(memory) => {
+                        output.memory(Some(&memory))?;
+                        if *explain {
+                            explain_match(&store, &output, description, &memory)?;
+                        }
+                    }
+                    TieResult::Tie(memories) => {
+                        output.memories(&memories)?;
+                        return Err(format!(
+                            "Ambiguous match: {} memories score within {} of the best match.",
+                            memories.len(),
+                            epsilon
+                        )
+                        .into());
+                    }
+                }
+            } else {
+                let memory = if *rerank {
+                    let client =
+                        MemoryStore::default_openai_client(data_dir, &options.openai_api_keys)?
+                            .with_base_url(
+                                options
+                                    .openai_base_url
+                                    .as_deref()
+                                    .unwrap_or(openai::Client::DEFAULT_BASE_URL),
+                            )
+                            .with_retry_policy(options.retry_policy)
+                            .with_timeout(
+                                options.timeout.unwrap_or(openai::Client::DEFAULT_TIMEOUT),
+                            );
+                    let reranker = LlmReranker::new(client, rerank_model.clone());
+                    store.get_reranked(description, *rerank_k, *threshold, &filter, &reranker)?
+                } else {
+                    match hybrid {
+                        Some(fallback_threshold) => store.get_hybrid(
+                            description,
+                            *threshold,
+                            *fallback_threshold,
+                            &filter,
+                        )?,
+                        None => store.get(description, *threshold, &filter)?,
+                    }
+                };
+                output.memory(memory.as_ref())?;
+                let Some(memory) = memory else {
+                    return Err(NoMatch.into());
+                };
+                if *explain {
+                    explain_match(&store, &output, description, &memory)?;
+                }
+            }
+        }
+        MemCommand::List {
+            description,
+            count,
+            all,
+            offset,
+            page,
+            tags,
+            since,
+            min_length,
+            max_length,
+            exclude,
+            sort_by,
+            mmr,
+            lambda,
+            explain,
+            archived,
+            threshold,
+            ..
+        } => {
+            let store = MemoryStore::load(&options)?;
+            let offset = match page {
+                Some(page) => (page - 1).saturating_mul(*count),
+                None => *offset,
+            };
+            let count = if *all { usize::MAX } else { *count };
+            let since = match since {
+                Some(since) => Some(Utc::now() - chrono::Duration::from_std(*since)?),
+                None => None,
+            };
+            let filter = Filter {
+                tags: tags.clone(),
+                since,
+                archived: *archived,
+                min_length: *min_length,
+                max_length: *max_length,
+                exclude: exclude.clone(),
+            };
+            // the skipped matches are ranked too, so that every page continues the same ranking
+            let ranked = count.saturating_add(offset);
+            let mut memories = if *mmr {
+                store.list_diverse(description, ranked, *threshold, &filter, *lambda)?
+            } else {
+                store.list(description, ranked, *threshold, &filter, *sort_by)?
+            };
+            memories.drain(..offset.min(memories.len()));
+            output.memories(&memories)?;
+            if *explain {
+                for memory in &memories {
+                    explain_match(&store, &output, description, memory)?;
+                }
+            }
+        }
+        MemCommand::Delete {
+            description,
+            index,
+            id,
+            purge,
+        } => {
+            let verb = if *purge { "delete" } else { "archive" };
+            let mut store = MemoryStore::load(&options)?;
+            let index = match id {
+                Some(id) => Some(
+                    store
+                        .index_of(id)?
+                        .ok_or_else(|| format!("There is no memory with the id {id:?}."))?,
+                ),
+                None => *index,
+            };
+            if cli.dry_run {
+                match (index, description) {
+                    (Some(index), _) => {
+                        let memories = store.export()?;
+                        let memory = memories.get(index).ok_or(format!(
+                            "Index {} is out of bounds. The store has {} memories.",
+                            index,
+                            memories.len()
+                        ))?;
+                        println!(
+                            "Would {verb} #{index} {}",
+                            describe_memory(&memory.value, &memory.description)
+                        );
+                    }
+                    (None, Some(description)) => {
+                        println!("Would {verb} the memory that best matches {description:?}")
+                    }
+                    (None, None) => unreachable!("clap requires a description, an index or an id"),
+                }
+                return Ok(());
+            }
+            let memory = match (index, description) {
+                (Some(index), _) if *purge => store.delete_at(index)?,
+                (Some(index), _) => store.archive_at(index)?,
+                (None, Some(description)) if *purge => store.delete(description)?,
+                (None, Some(description)) => store.archive(description)?,
+                (None, None) => unreachable!("clap requires a description, an index or an id"),
+            };
+            match memory {
+                Some(memory) if *purge => {
+                    output.status(format!("Deleted: {}", output.format_memory(&memory)))
+                }
+                Some(memory) => output.status(format!(
+                    "Archived #{}: {}",
+                    memory.index,
+                    output.format_memory(&memory)
+                )),
+                None => output.status("No memory found!"),
+            }
+        }
+        MemCommand::Restore {
+            index: Some(index), ..
+        } => {
+            if cli.dry_run {
+                println!("Would restore #{index}");
+                return Ok(());
+            }
+            let mut store = MemoryStore::load(&options)?;
+            let memory = store.restore_at(*index)?;
+            output.status(format!("Restored: {}", output.format_memory(&memory)));
+        }
+        MemCommand::Restore {
+            backup: Some(backup),
+            ..
+        } => {
+            if cli.dry_run {
+                println!("Would restore the store from backup {backup}");
+                return Ok(());
+            }
+            let mut store = MemoryStore::load(&options)?;
+            let count = store.restore_backup(backup)?;
+            output.status(format!("Restored {count} memories from backup {backup}!"));
+        }
+        MemCommand::Restore { .. } => unreachable!("clap requires an index or a backup"),
+        MemCommand::Update {
+            index,
+            mem,
+            description,
+        } => {
+            if cli.dry_run {
+                if let Some(mem) = mem {
+                    println!("Would set the memory of #{index} to {mem:?}");
+                }
+                if let Some(description) = description {
+                    println!("Would set the description of #{index} to {description:?}");
+                }
+                return Ok(());
+            }
+            let mut store = MemoryStore::load(&options)?;
+            store.update(*index, mem.as_deref(), description.as_deref())?;
+            output.status("Memory updated!");
+        }
+        MemCommand::Find { pattern, regex } => {
+            let store = MemoryStore::load(&options)?;
+            output.found(&store.find(pattern, *regex)?)?;
+        }
+        MemCommand::Score {
+            description,
+            against,
+        } => {
+            let store = MemoryStore::load(&options)?;
+            output.score(store.similarity(description, against)?)?;
+        }
+        MemCommand::Count {
+            tags,
+            since,
+            min_length,
+            max_length,
+            archived,
+        } => {
+            let store = MemoryStore::load(&options)?;
+            let since = match since {
+                Some(since) => Some(Utc::now() - chrono::Duration::from_std(*since)?),
+                None => None,
+            };
+            let filter = Filter {
+                tags: tags.clone(),
+                since,
+                archived: *archived,
+                min_length: *min_length,
+                max_length: *max_length,
+                ..Filter::default()
+            };
+            let unfiltered = tags.is_empty()
+                && since.is_none()
+                && !*archived
+                && min_length.is_none()
+                && max_length.is_none();
+            let count = if unfiltered {
+                store.len()?
+            } else {
+                store.count(&filter)?
+            };
+            output.count(count)?;
+        }
+        MemCommand::Stats => {
+            let store = MemoryStore::load(&options)?;
+            output.stats(&store.stats()?)?;
+        }
+        MemCommand::Dedup {
+            report: _,
+            threshold,
+        } => {
+            let store = MemoryStore::load(&options)?;
+            let clusters = store.find_duplicates(*threshold)?;
+            output.duplicates(&clusters, &store.export()?)?;
+        }
+        MemCommand::Clear { yes } => {
+            let mut store = MemoryStore::load(&options)?;
+            let count = store.len()?;
+            if !*yes
+                && !confirm(&format!(
+                    "This will permanently delete all {count} memories. Continue? [y/N] "
+                ))?
+            {
+                output.status("Aborted!");
+                return Ok(());
+            }
+            let count = store.clear()?;
+            output.status(format!("Cleared {count} memories!"));
+        }
+        MemCommand::Doctor { fix } => {
+            let mut unfixed = 0;
+            if let Provider::OpenAi = provider {
+                if let Err(err) =
+                    MemoryStore::default_openai_client(data_dir, &options.openai_api_keys)
+                {
+                    println!("Problem: {err:#}");
+                    unfixed += 1;
+                }
+            }
+            let mut store = MemoryStore::load(&options)?;
+            let problems = store.doctor(*fix)?;
+            for problem in &problems {
+                if problem.fixed {
+                    println!("Fixed: {}", problem.description);
+                } else {
+                    println!("Problem: {}", problem.description);
+                    unfixed += 1;
+                }
+            }
+            if unfixed > 0 && *fix {
+                return Err(format!("{unfixed} problems could not be fixed.").into());
+            }
+            if unfixed > 0 {
+                return Err(format!(
+                    "Found {unfixed} problems. Run `mem doctor --fix` to fix the store."
+                )
+                .into());
+            }
+            if problems.is_empty() {
+                output.status("No problems found!");
+            }
+        }
+        MemCommand::Migrate => {
+            let mut store = MemoryStore::load(&options)?;
+            match store.migrate()? {
+                Some(count) if cli.backend.is_some() => output.status(format!(
+                    "Migrated {count} memories to the {} backend!",
+                    cli.backend.unwrap_or_default().name()
+                )),
+                Some(count) => {
+                    output.status(format!("Migrated {count} memories to the binary format!"))
+                }
+                None => output.status("The store is already in the binary format!"),
+            }
+        }
+        MemCommand::Reembed { model } => {
+            let options = StoreOptions {
+                model: model.clone().or(options.model),
+                ..options
+            };
+            let mut store = MemoryStore::load(&options)?;
+            if cli.dry_run {
                 println!(
-                    "[{score}] {memory}",
-                    memory = memory.value,
-                    score = format!("{:.2}", memory.score)
+                    "Would re-embed {} memories with {}",
+                    store.len()?,
+                    options.model.as_deref().unwrap_or("the store's model")
                 );
+                return Ok(());
+            }
+            let progress = if stdout().is_terminal() && !cli.json {
+                ProgressBar::new(store.len()? as u64).with_style(ProgressStyle::with_template(
+                    "{bar:40} {pos}/{len} memories embedded (ETA {eta})",
+                )?)
             } else {
-                println!("No memory found!");
+                ProgressBar::hidden()
+            };
+            let count =
+                store.reembed_with_progress(|embedded| progress.set_position(embedded as u64))?;
+            progress.finish_and_clear();
+            output.status(format!("Re-embedded {count} memories!"));
+            if let Some(usage) = store.embedding_usage() {
+                output.usage(&usage)?;
             }
         }
-        MemCommand::List { description, count } => {
-            let store = MemoryStore::load()?;
-            let memories = store.list(description, *count as usize)?;
-            if memories.is_empty() {
-                println!("No memories found!");
-            } else {
-                memories.iter().for_each(|memory| {
+        MemCommand::Merge {
+            other,
+            from_store,
+            dedup,
+        } => {
+            if !other.is_dir() {
+                return Err(format!("{} is not a data directory.", other.display()).into());
+            }
+            // the other data directory may be a read-only backup, and a mistyped store name
+            // shouldn't leave an empty store behind in it
+            let other_path = MemoryStore::find_data_file_path(Some(other), from_store.as_deref())?;
+            let Some(other_path) = MemoryStore::stored_file_path(&other_path) else {
+                return Err(format!("There is no store in {}.", other.display()).into());
+            };
+            let this_path = MemoryStore::default_data_file_path(
+                options.data_dir.as_deref(),
+                options.store_name.as_deref(),
+            )?;
+            let this_path = MemoryStore::stored_file_path(&this_path);
+            // locking the same store twice would wait forever
+            if this_path.and_then(|path| path.canonicalize().ok())
+                == Some(other_path.canonicalize()?)
+            {
+                return Err("Can't merge a store into itself.".into());
+            }
+            let other_store = MemoryStore::load(&StoreOptions {
+                data_dir: Some(other.clone()),
+                store_name: from_store.clone(),
+                model: None,
+                metric: None,
+                quantization: None,
+                backend: None,
+                model_revision: None,
+                query_cache_size: 0,
+                ..options.clone()
+            })?;
+            if cli.dry_run {
+                println!(
+                    "Would merge {} memories from {}",
+                    other_store.len()?,
+                    other.display()
+                );
+                return Ok(());
+            }
+            let mut store = MemoryStore::load(&options)?;
+            let report = store.merge(&other_store, *dedup)?;
+            output.status(format!(
+                "Merged {} memories, skipped {} duplicates!",
+                report.merged, report.skipped
+            ));
+        }
+        MemCommand::Serve { port, host } => {
+            let store = MemoryStore::load(&options)?;
+            output.status(format!("Listening on http://{host}:{port}"));
+            serve::serve(&store, host, *port)?;
+        }
+        MemCommand::Mcp => {
+            let mut store = MemoryStore::load(&options)?;
+            mcp::serve(&mut store, stdin().lock(), stdout().lock())?;
+        }
+        MemCommand::Watch {
+            count,
+            tags,
+            description,
+        } => {
+            let filter = Filter {
+                tags: tags.clone(),
+                ..Filter::default()
+            };
+            watch::watch(&options, &output, description, *count, &filter)?;
+        }
+        MemCommand::Import {
+            path,
+            format,
+            include_value,
+        } => {
+            let format = format.unwrap_or_else(|| ImportFormat::from_path(path));
+            let mut memories = import::read_memories(path, format)?;
+            if *include_value {
+                memories
+                    .iter_mut()
+                    .for_each(|memory| memory.embeds_value = true);
+            }
+            if cli.dry_run {
+                for memory in &memories {
                     println!(
-                        "[{score}] {memory}",
-                        memory = memory.value,
-                        score = format!("{:.2}", memory.score)
+                        "Would insert {}",
+                        describe_memory(&memory.value, &memory.description)
                     );
-                });
+                }
+                println!("Would import {} memories.", memories.len());
+                return Ok(());
+            }
+            let mut store = MemoryStore::load(&options)?;
+            // only draw a progress bar for people watching a terminal
+            let progress = if stdout().is_terminal() && !cli.json {
+                ProgressBar::new(memories.len() as u64).with_style(ProgressStyle::with_template(
+                    "{bar:40} {pos}/{len} memories embedded (ETA {eta})",
+                )?)
+            } else {
+                ProgressBar::hidden()
+            };
+            let count = store.insert_many_with_progress(&memories, |embedded| {
+                progress.set_position(embedded as u64)
+            })?;
+            progress.finish_and_clear();
+            output.status(format!("Imported {count} memories!"));
+            if let Some(usage) = store.embedding_usage() {
+                output.usage(&usage)?;
             }
         }
-        MemCommand::SetKey => {
-            print!("Please enter your API key: ");
-            let _ = stdout().flush();
-            let mut key = String::new();
-            stdin().read_line(&mut key)?;
-            key = key.trim_end().to_string();
-            MemoryStore::store_openai_api_key(&key)?;
-            println!("Key set!");
+        MemCommand::Stores {
+            command: StoresCommand::List,
+        } => {
+            output.stores(&MemoryStore::list_stores(data_dir)?)?;
+        }
+        MemCommand::Stores {
+            command: StoresCommand::Rename { old_name, new_name },
+        } => {
+            if cli.dry_run {
+                println!("Would rename store {old_name} to {new_name}");
+                return Ok(());
+            }
+            MemoryStore::rename_store(data_dir, old_name, new_name, options.lock_mode)?;
+            output.status(format!("Renamed store {old_name} to {new_name}!"));
+        }
+        MemCommand::Backups {
+            command: BackupsCommand::List,
+        } => {
+            let store = MemoryStore::load(&options)?;
+            output.backups(&store.backups()?)?;
+        }
+        MemCommand::Config {
+            command: ConfigCommand::Show,
+        } => {
+            output.config(&Config {
+                provider: Some(provider),
+                data_dir: Some(MemoryStore::resolve_data_dir_path(data_dir)),
+                // keys are secrets, so only their number is shown
+                openai_api_keys: config
+                    .openai_api_keys
+                    .as_ref()
+                    .map(|keys| vec!["<hidden>".to_owned(); keys.len()]),
+                ..config.clone()
+            })?;
+        }
+        MemCommand::Cache {
+            command: CacheCommand::Clear,
+        } => {
+            MemoryStore::default_query_cache(data_dir, cli.cache_size).clear()?;
+            output.status("Cache cleared!");
+        }
+        MemCommand::Export { path } => {
+            let store = MemoryStore::load(&options)?;
+            let memories = store.export()?;
+            if let Some(path) = path {
+                let writer = BufWriter::new(File::create(path)?);
+                serde_json::to_writer_pretty(writer, &memories)?;
+                output.status(format!("Exported {} memories!", memories.len()));
+            } else {
+                serde_json::to_writer_pretty(stdout().lock(), &memories)?;
+                println!();
+            }
+        }
+        MemCommand::SetKey { keyring } => {
+            let key = read_api_key()?;
+            if *keyring {
+                MemoryStore::store_openai_api_key_in_keyring(&key)?;
+                output.status("Key set! Stored in the OS keychain.");
+            } else {
+                let path = MemoryStore::store_openai_api_key(data_dir, &key)?;
+                output.status(format!(
+                    "Key set! Stored in {}, readable only by you.",
+                    path.display()
+                ));
+            }
+        }
+        MemCommand::Completions { shell } => {
+            let mut command = MemCli::command();
+            let name = command.get_name().to_owned();
+            clap_complete::generate(*shell, &mut command, name, &mut stdout());
         }
     }
     Ok(())
 }
+
+/// Log warnings by default, and more with every `-v`. `RUST_LOG` overrides this.
+fn init_logging(verbose: u8) {
+    let level = match verbose {
+        0 => log::LevelFilter::Warn,
+        1 => log::LevelFilter::Info,
+        2 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    };
+    env_logger::Builder::new()
+        .filter_level(level)
+        .parse_default_env()
+        .init();
+}
+
+/// Print the phrases of the memory's description that best match the query.
+fn explain_match(
+    store: &MemoryStore,
+    output: &Output,
+    description: &str,
+    memory: &ScoredMemory,
+) -> anyhow::Result<()> {
+    let mut phrases = store.explain(description, &memory.description)?;
+    phrases.truncate(5);
+    output.explanation(memory, &phrases)
+}
+
+/// Parse the JSON passed to `insert --meta`.
+fn parse_metadata(metadata: &str) -> Result<serde_json::Value, String> {
+    serde_json::from_str(metadata).map_err(|err| format!("invalid JSON: {err}"))
+}
+
+/// Describe a memory for dry runs.
+fn describe_memory(mem: &str, description: &str) -> String {
+    format!("{mem:?} described as {description:?}")
+}
+
+/// Ask a yes/no question on stderr, defaulting to no.
+fn confirm(prompt: &str) -> std::io::Result<bool> {
+    eprint!("{prompt}");
+    stderr().flush()?;
+    let mut answer = String::new();
+    stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Read the API key without echoing it, or straight from stdin when it is piped in.
+fn read_api_key() -> Result<String, Box<dyn std::error::Error>> {
+    let key = if stdin().is_terminal() {
+        rpassword::prompt_password("Please enter your API key: ")?
+    } else {
+        let mut key = String::new();
+        stdin().read_to_string(&mut key)?;
+        key
+    };
+    let key = key.trim().to_string();
+    if key.is_empty() {
+        return Err("API key must not be empty.".into());
+    }
+    Ok(key)
+}
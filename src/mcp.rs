@@ -0,0 +1,179 @@
+use anyhow::{Context, Result};
+use mem::store::{Filter, MemoryStore, SortBy};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io::{BufRead, Write};
+
+/// The version of the Model Context Protocol this server speaks.
+const PROTOCOL_VERSION: &str = "2024-11-05";
+/// The number of memories the `list` tool returns unless `count` is given.
+const DEFAULT_LIST_COUNT: usize = 10;
+
+/// A JSON-RPC request, or a notification if it has no id.
+#[derive(Deserialize)]
+struct Request {
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+/// Arguments of the `insert` tool.
+#[derive(Deserialize)]
+struct InsertArgs {
+    memory: String,
+    description: String,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+/// Arguments of the `get` tool.
+#[derive(Deserialize)]
+struct GetArgs {
+    description: String,
+    threshold: Option<f32>,
+}
+
+/// Arguments of the `list` tool.
+#[derive(Deserialize)]
+struct ListArgs {
+    description: String,
+    count: Option<usize>,
+}
+
+/// Serve the store as Model Context Protocol tools to a client talking JSON-RPC over stdin and
+/// stdout, one message per line, until stdin is closed.
+pub fn serve(store: &mut MemoryStore, input: impl BufRead, mut output: impl Write) -> Result<()> {
+    for line in input.lines() {
+        let line = line.context("Failed to read request.")?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => {
+                log::debug!("MCP request: {}", request.method);
+                let Some(id) = request.id else {
+                    // notifications, like `notifications/initialized`, get no response
+                    continue;
+                };
+                match handle(store, &request.method, request.params) {
+                    Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+                    Err((code, message)) => json!({
+                        "jsonrpc": "2.0",
+                        "id": id,
+                        "error": { "code": code, "message": message },
+                    }),
+                }
+            }
+            Err(err) => json!({
+                "jsonrpc": "2.0",
+                "id": null,
+                "error": { "code": -32700, "message": format!("Parse error: {}", err) },
+            }),
+        };
+        writeln!(output, "{}", response).context("Failed to write response.")?;
+        output.flush().context("Failed to write response.")?;
+    }
+    Ok(())
+}
+
+/// Handle a request, returning its result or a JSON-RPC error code and message.
+fn handle(store: &mut MemoryStore, method: &str, params: Value) -> Result<Value, (i64, String)> {
+    match method {
+        "initialize" => Ok(json!({
+            "protocolVersion": PROTOCOL_VERSION,
+            "capabilities": { "tools": {} },
+            "serverInfo": { "name": "mem", "version": env!("CARGO_PKG_VERSION") },
+        })),
+        "ping" => Ok(json!({})),
+        "tools/list" => Ok(json!({ "tools": tools() })),
+        "tools/call" => {
+            let name = params["name"]
+                .as_str()
+                .ok_or((-32602, "Missing tool name.".to_owned()))?;
+            let arguments = params.get("arguments").cloned().unwrap_or(json!({}));
+            // tool failures are results the model can read, not protocol errors
+            let result = match call(store, name, arguments) {
+                Ok(text) => json!({ "content": [{ "type": "text", "text": text }] }),
+                Err(err) => json!({
+                    "content": [{ "type": "text", "text": format!("{:#}", err) }],
+                    "isError": true,
+                }),
+            };
+            Ok(result)
+        }
+        _ => Err((-32601, format!("Method not found: {}", method))),
+    }
+}
+
+/// Run the tool called `name`, returning the text of its result.
+fn call(store: &mut MemoryStore, name: &str, arguments: Value) -> Result<String> {
+    match name {
+        "insert" => {
+            let args: InsertArgs =
+                serde_json::from_value(arguments).context("Invalid arguments.")?;
+            store.insert(&args.memory, &args.description, &args.tags, false)?;
+            Ok("Memory inserted!".to_owned())
+        }
+        "get" => {
+            let args: GetArgs = serde_json::from_value(arguments).context("Invalid arguments.")?;
+            let memory = store.get(&args.description, args.threshold, &Filter::default())?;
+            Ok(serde_json::to_string(&memory)?)
+        }
+        "list" => {
+            let args: ListArgs = serde_json::from_value(arguments).context("Invalid arguments.")?;
+            let memories = store.list(
+                &args.description,
+                args.count.unwrap_or(DEFAULT_LIST_COUNT),
+                None,
+                &Filter::default(),
+                SortBy::Score,
+            )?;
+            Ok(serde_json::to_string(&memories)?)
+        }
+        _ => Err(anyhow::anyhow!("Unknown tool: {}", name)),
+    }
+}
+
+/// The tools the server offers, with JSON schemas of their arguments.
+fn tools() -> Value {
+    json!([
+        {
+            "name": "insert",
+            "description": "Store a memory, e.g. a command or a fact, under a description it can later be found by.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "memory": { "type": "string", "description": "The memory to store." },
+                    "description": { "type": "string", "description": "What the memory is for, used to find it." },
+                    "tags": { "type": "array", "items": { "type": "string" }, "description": "Labels for the memory." },
+                },
+                "required": ["memory", "description"],
+            },
+        },
+        {
+            "name": "get",
+            "description": "Find the memory that best matches a description, or null if there is none.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "description": { "type": "string", "description": "A description of the memory to find." },
+                    "threshold": { "type": "number", "description": "The minimum score of the memory." },
+                },
+                "required": ["description"],
+            },
+        },
+        {
+            "name": "list",
+            "description": "List the memories that best match a description, best first, with their scores.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "description": { "type": "string", "description": "A description of the memories to find." },
+                    "count": { "type": "integer", "description": "The maximum number of memories to list." },
+                },
+                "required": ["description"],
+            },
+        },
+    ])
+}
@@ -0,0 +1,312 @@
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A minimal client for the OpenAI embeddings API.
+///
+/// The embeddings endpoint accepts an array of inputs, which lets us embed many texts in a
+/// single request.
+///
+/// With several API keys, a request that hits a rate limit is retried with the next key right
+/// away, and only backs off once every key has hit one.
+pub struct Client {
+    api_keys: Vec<String>,
+    /// The index of the key in `api_keys` that requests are sent with.
+    current_key: Cell<usize>,
+    base_url: String,
+    agent: ureq::Agent,
+    retry_policy: RetryPolicy,
+}
+
+/// How requests that fail with a transient error are retried.
+///
+/// Rate limits (429), server errors (5xx) and network errors are retried with exponential
+/// backoff and jitter. Any other error fails immediately.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// The number of retries after the first attempt.
+    pub max_retries: u32,
+    /// The delay before the first retry, which doubles with every retry.
+    pub base_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            base_delay: Duration::from_millis(500),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The delay before retry number `retry`, counting from 0.
+    ///
+    /// The exponential delay is scaled by a random factor between 0.5 and 1 so that clients
+    /// that failed together don't retry together.
+    fn delay(&self, retry: u32) -> Duration {
+        let exponential = self.base_delay.saturating_mul(2u32.saturating_pow(retry));
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .subsec_nanos();
+        let jitter = 0.5 + 0.5 * (nanos as f64 / 1_000_000_000.0);
+        exponential.mul_f64(jitter)
+    }
+}
+
+/// A request for the embeddings of one or more texts.
+#[derive(Serialize)]
+pub struct EmbeddingRequest<'a> {
+    pub model: &'a str,
+    pub input: &'a [String],
+}
+
+/// The embeddings returned for an `EmbeddingRequest`.
+///
+/// `data` is not guaranteed to be in input order; use `EmbeddingData::index` instead.
+#[derive(Deserialize)]
+pub struct EmbeddingResponse {
+    pub data: Vec<EmbeddingData>,
+    /// The exact model that produced the embeddings, which may differ from the requested alias.
+    #[serde(default)]
+    pub model: Option<String>,
+    /// How many tokens the request was billed for. OpenAI-compatible APIs may leave it out.
+    #[serde(default)]
+    pub usage: Option<Usage>,
+}
+
+#[derive(Deserialize)]
+pub struct Usage {
+    pub total_tokens: u64,
+}
+
+/// The price in US dollars of embedding a million tokens with `model`, if it is known.
+pub fn price_per_million_tokens(model: &str) -> Option<f64> {
+    match model {
+        "text-embedding-3-small" => Some(0.02),
+        "text-embedding-3-large" => Some(0.13),
+        "text-embedding-ada-002" => Some(0.10),
+        _ => None,
+    }
+}
+
+#[derive(Deserialize)]
+pub struct EmbeddingData {
+    pub embedding: Vec<f32>,
+    pub index: usize,
+}
+
+/// A request for a chat completion.
+#[derive(Serialize)]
+pub struct ChatRequest<'a> {
+    pub model: &'a str,
+    pub messages: Vec<ChatMessage>,
+    pub temperature: f32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ChatMessage {
+    /// `system`, `user` or `assistant`.
+    pub role: String,
+    pub content: String,
+}
+
+/// The completions returned for a `ChatRequest`.
+#[derive(Deserialize)]
+pub struct ChatResponse {
+    pub choices: Vec<ChatChoice>,
+}
+
+#[derive(Deserialize)]
+pub struct ChatChoice {
+    pub message: ChatMessage,
+}
+
+impl Client {
+    /// The URL of OpenAI's own API, used unless another base URL is set.
+    pub const DEFAULT_BASE_URL: &'static str = "https://api.openai.com/v1";
+
+    /// How long a request may take before it is abandoned.
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+    pub fn new(api_key: String) -> Client {
+        Self::with_api_keys(vec![api_key])
+    }
+
+    /// Create a client that rotates through `api_keys` when one hits a rate limit, starting
+    /// with the first.
+    ///
+    /// Panics if `api_keys` is empty.
+    pub fn with_api_keys(api_keys: Vec<String>) -> Client {
+        assert!(!api_keys.is_empty(), "a client needs at least one API key");
+        Client {
+            api_keys,
+            current_key: Cell::new(0),
+            base_url: Self::DEFAULT_BASE_URL.to_owned(),
+            agent: Self::agent(Self::DEFAULT_TIMEOUT),
+            retry_policy: RetryPolicy::default(),
+        }
+    }
+
+    /// Send requests to the OpenAI-compatible API at `base_url` instead of OpenAI's, e.g. a
+    /// proxy. Endpoints are appended to it, so it usually ends in `/v1`.
+    pub fn with_base_url(mut self, base_url: impl Into<String>) -> Client {
+        self.base_url = base_url.into().trim_end_matches('/').to_owned();
+        self
+    }
+
+    /// Check that `base_url` can be passed to `with_base_url`.
+    pub fn validate_base_url(base_url: &str) -> Result<()> {
+        let host = base_url
+            .strip_prefix("https://")
+            .or_else(|| base_url.strip_prefix("http://"));
+        match host {
+            Some(host) if !host.is_empty() && !host.starts_with('/') => Ok(()),
+            _ => Err(anyhow::anyhow!(
+                "Base URL must be an http:// or https:// URL with a host. Got: {:?}",
+                base_url
+            )),
+        }
+    }
+
+    /// Abandon requests that take longer than `timeout`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Client {
+        self.agent = Self::agent(timeout);
+        self
+    }
+
+    fn agent(timeout: Duration) -> ureq::Agent {
+        ureq::AgentBuilder::new().timeout(timeout).build()
+    }
+
+    /// Use `retry_policy` for requests that fail with a transient error.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Client {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Get the embeddings for every input of the request.
+    ///
+    /// Transient failures are retried according to the client's `RetryPolicy`.
+    pub fn embedding(&self, req: &EmbeddingRequest) -> Result<EmbeddingResponse> {
+        log::debug!("Embedding {} inputs", req.input.len());
+        self.post("embeddings", req)
+    }
+
+    /// Get a chat completion for the messages of the request.
+    ///
+    /// Transient failures are retried like those of `embedding`.
+    pub fn chat(&self, req: &ChatRequest) -> Result<ChatResponse> {
+        log::debug!(
+            "Completing {} messages with {}",
+            req.messages.len(),
+            req.model
+        );
+        self.post("chat/completions", req)
+    }
+
+    /// Post `req` as JSON to `endpoint`, retrying transient failures.
+    fn post<T: DeserializeOwned>(&self, endpoint: &str, req: &impl Serialize) -> Result<T> {
+        let url = format!("{}/{}", self.base_url, endpoint);
+        let mut retry = 0;
+        // the number of keys tried since the last backoff
+        let mut rotations = 0;
+        log::debug!("POST {}", url);
+        loop {
+            let current_key = self.current_key.get();
+            if self.api_keys.len() > 1 {
+                log::debug!("Using OpenAI API key {}", self.key_label(current_key));
+            }
+            let result = self
+                .agent
+                .post(&url)
+                .set(
+                    "Authorization",
+                    &format!("Bearer {}", self.api_keys[current_key]),
+                )
+                .send_json(req);
+            let can_retry = retry < self.retry_policy.max_retries;
+            let response = match result {
+                Ok(response) => response,
+                Err(ureq::Error::Status(429, _)) if rotations + 1 < self.api_keys.len() => {
+                    let next_key = (current_key + 1) % self.api_keys.len();
+                    log::info!(
+                        "OpenAI API key {} hit a rate limit, switching to key {}",
+                        self.key_label(current_key),
+                        self.key_label(next_key)
+                    );
+                    self.current_key.set(next_key);
+                    rotations += 1;
+                    continue;
+                }
+                Err(ureq::Error::Status(code, response))
+                    if can_retry && (code == 429 || code >= 500) =>
+                {
+                    // rate limits may say exactly how long to wait
+                    let retry_after = response
+                        .header("retry-after")
+                        .and_then(|seconds| seconds.parse().ok())
+                        .map(Duration::from_secs);
+                    let delay = retry_after.unwrap_or_else(|| self.retry_policy.delay(retry));
+                    log::warn!(
+                        "OpenAI API returned status {}, retrying in {:?}",
+                        code,
+                        delay
+                    );
+                    std::thread::sleep(delay);
+                    retry += 1;
+                    rotations = 0;
+                    continue;
+                }
+                Err(ureq::Error::Status(code, response)) => {
+                    let body = response.into_string().unwrap_or_default();
+                    return Err(anyhow::anyhow!(
+                        "OpenAI API returned status {}: {}",
+                        code,
+                        body
+                    ));
+                }
+                Err(ureq::Error::Transport(transport)) if can_retry => {
+                    let delay = self.retry_policy.delay(retry);
+                    log::warn!(
+                        "Request to the OpenAI API failed ({}), retrying in {:?}",
+                        transport,
+                        delay
+                    );
+                    std::thread::sleep(delay);
+                    retry += 1;
+                    continue;
+                }
+                Err(ureq::Error::Transport(transport)) => {
+                    return Err(Self::transport_error(transport));
+                }
+            };
+            return Ok(response.into_json()?);
+        }
+    }
+
+    /// Identify a key in logs by its position and last characters, without revealing it.
+    fn key_label(&self, index: usize) -> String {
+        let key = &self.api_keys[index];
+        let suffix: String = key
+            .chars()
+            .skip(key.chars().count().saturating_sub(4))
+            .collect();
+        format!("#{} (...{})", index + 1, suffix)
+    }
+
+    /// Explain a request that never got a response, which usually means there is no network.
+    fn transport_error(transport: ureq::Transport) -> anyhow::Error {
+        let message = match transport.kind() {
+            ureq::ErrorKind::Dns | ureq::ErrorKind::ConnectionFailed => {
+                "Failed to reach the OpenAI API. You appear to be offline."
+            }
+            ureq::ErrorKind::Io => "The request to the OpenAI API failed or timed out.",
+            _ => "The request to the OpenAI API failed.",
+        };
+        anyhow::Error::new(transport).context(message)
+    }
+}
@@ -0,0 +1,388 @@
+use anyhow::Result;
+use mem::config::Config;
+use mem::store::{
+    Backup, EmbeddingUsage, MatchSource, Memory, PhraseScore, ScoredMemory, StoreStats,
+};
+use serde::Serialize;
+use std::fmt::Display;
+
+/// How results are printed.
+#[derive(Clone, Copy, Debug)]
+pub enum OutputFormat {
+    /// Readable `[score] memory` lines.
+    Human,
+    /// Machine-readable JSON.
+    Json,
+}
+
+/// Prints command results in the chosen format.
+pub struct Output {
+    pub format: OutputFormat,
+    /// Follow human-readable memories with the description they were matched on. JSON always
+    /// includes it.
+    pub show_description: bool,
+    /// Don't print status messages.
+    pub quiet: bool,
+    /// Print every result of `get` and `list` with this template instead, see
+    /// `Output::validate_template`.
+    pub template: Option<String>,
+}
+
+impl Output {
+    /// Print a status message, e.g. that a memory was inserted, to stderr so that it never
+    /// mixes with results.
+    pub fn status(&self, message: impl Display) {
+        if !self.quiet {
+            eprintln!("{message}");
+        }
+    }
+
+    /// Print the result of `get`.
+    pub fn memory(&self, memory: Option<&ScoredMemory>) -> Result<()> {
+        match self.format {
+            OutputFormat::Human => match memory {
+                Some(memory) if self.template.is_some() => println!("{}", self.render(memory)),
+                Some(memory) => {
+                    println!("{}", self.format_memory(memory));
+                    if let Some(metadata) = &memory.metadata {
+                        println!("  metadata: {metadata}");
+                    }
+                }
+                None => self.status("No memory found!"),
+            },
+            OutputFormat::Json => Self::json(&memory)?,
+        }
+        Ok(())
+    }
+
+    /// Print the result of `list`.
+    pub fn memories(&self, memories: &[ScoredMemory]) -> Result<()> {
+        match self.format {
+            OutputFormat::Human if memories.is_empty() => self.status("No memories found!"),
+            OutputFormat::Human if self.template.is_some() => memories
+                .iter()
+                .for_each(|memory| println!("{}", self.render(memory))),
+            OutputFormat::Human => memories.iter().for_each(|memory| {
+                println!(
+                    "#{index} {memory}",
+                    index = memory.index,
+                    memory = self.format_memory(memory)
+                );
+            }),
+            OutputFormat::Json => Self::json(&memories)?,
+        }
+        Ok(())
+    }
+
+    /// Print the result of `find`, as `#index value (description)` lines.
+    pub fn found(&self, memories: &[(usize, Memory)]) -> Result<()> {
+        #[derive(Serialize)]
+        struct Found<'a> {
+            index: usize,
+            #[serde(flatten)]
+            memory: &'a Memory,
+        }
+        match self.format {
+            OutputFormat::Human if memories.is_empty() => self.status("No memories found!"),
+            OutputFormat::Human => memories.iter().for_each(|(index, memory)| {
+                println!(
+                    "#{index} {value} ({description})",
+                    value = memory.value,
+                    description = memory.description
+                )
+            }),
+            OutputFormat::Json => Self::json(
+                &memories
+                    .iter()
+                    .map(|(index, memory)| Found {
+                        index: *index,
+                        memory,
+                    })
+                    .collect::<Vec<_>>(),
+            )?,
+        }
+        Ok(())
+    }
+
+    /// Print the result of `score`.
+    pub fn score(&self, score: f32) -> Result<()> {
+        #[derive(Serialize)]
+        struct Score {
+            score: f32,
+        }
+        match self.format {
+            OutputFormat::Human => println!("{score:.4}"),
+            OutputFormat::Json => Self::json(&Score { score })?,
+        }
+        Ok(())
+    }
+
+    /// Print the number of memories in the store.
+    pub fn count(&self, count: usize) -> Result<()> {
+        #[derive(Serialize)]
+        struct Count {
+            count: usize,
+        }
+        match self.format {
+            OutputFormat::Human => println!("{count}"),
+            OutputFormat::Json => Self::json(&Count { count })?,
+        }
+        Ok(())
+    }
+
+    /// Print clusters of near-duplicate memories, given by their indices into `memories`.
+    pub fn duplicates(&self, clusters: &[Vec<usize>], memories: &[Memory]) -> Result<()> {
+        #[derive(Serialize)]
+        struct Duplicate<'a> {
+            index: usize,
+            value: &'a str,
+            description: &'a str,
+        }
+        match self.format {
+            OutputFormat::Human if clusters.is_empty() => self.status("No duplicates found!"),
+            OutputFormat::Human => clusters.iter().for_each(|cluster| {
+                println!("{} memories look alike:", cluster.len());
+                cluster.iter().for_each(|&index| {
+                    println!(
+                        "  #{index} {value} ({description})",
+                        value = memories[index].value,
+                        description = memories[index].description
+                    )
+                });
+            }),
+            OutputFormat::Json => {
+                let clusters: Vec<Vec<Duplicate>> = clusters
+                    .iter()
+                    .map(|cluster| {
+                        cluster
+                            .iter()
+                            .map(|&index| Duplicate {
+                                index,
+                                value: &memories[index].value,
+                                description: &memories[index].description,
+                            })
+                            .collect()
+                    })
+                    .collect();
+                Self::json(&clusters)?
+            }
+        }
+        Ok(())
+    }
+
+    /// Print the result of `stats`.
+    pub fn stats(&self, stats: &StoreStats) -> Result<()> {
+        const BAR_WIDTH: usize = 40;
+        if let OutputFormat::Json = self.format {
+            return Self::json(stats);
+        }
+        println!("Memories: {}", stats.memories);
+        println!("Model: {}", stats.model);
+        println!("Metric: {}", stats.metric.name());
+        println!("Quantization: {}", stats.quantization.name());
+        match stats.dimension {
+            Some(dimension) => println!("Dimension: {dimension}"),
+            None => println!("Dimension: unknown"),
+        }
+        println!("Size on disk: {}", Self::format_bytes(stats.disk_size));
+        let Some(mean_score) = stats.mean_score else {
+            return Ok(());
+        };
+        println!(
+            "Scores between {} sampled pairs of memories (mean {mean_score:.2}):",
+            stats.sampled_pairs
+        );
+        let max_count = stats.histogram.iter().map(|bucket| bucket.count).max();
+        for bucket in &stats.histogram {
+            let bar = bucket.count * BAR_WIDTH / max_count.unwrap_or(1).max(1);
+            println!(
+                "  [{start:.2}, {end:.2}] {count:>6} {bar}",
+                start = bucket.start,
+                end = bucket.end,
+                count = bucket.count,
+                bar = "#".repeat(bar)
+            );
+        }
+        Ok(())
+    }
+
+    /// Print how many tokens were embedded, e.g. `Embedded 1,240 tokens (~$0.0001)`, as a
+    /// status message.
+    pub fn usage(&self, usage: &EmbeddingUsage) -> Result<()> {
+        if let OutputFormat::Json = self.format {
+            return Self::json(usage);
+        }
+        let tokens = Self::format_count(usage.tokens);
+        match usage.estimated_cost {
+            Some(cost) if cost < 0.0001 => {
+                self.status(format!("Embedded {tokens} tokens (<$0.0001)"))
+            }
+            Some(cost) => self.status(format!("Embedded {tokens} tokens (~${cost:.4})")),
+            None => self.status(format!("Embedded {tokens} tokens")),
+        }
+        Ok(())
+    }
+
+    /// Print the phrases of a memory's description that best match the query.
+    pub fn explanation(&self, memory: &ScoredMemory, phrases: &[PhraseScore]) -> Result<()> {
+        #[derive(Serialize)]
+        struct Explanation<'a> {
+            index: usize,
+            phrases: &'a [PhraseScore],
+        }
+        match self.format {
+            OutputFormat::Human => {
+                println!("  #{} matched on:", memory.index);
+                phrases.iter().for_each(|phrase| {
+                    println!(
+                        "    [{score:.2}] {phrase}",
+                        score = phrase.score,
+                        phrase = phrase.phrase
+                    )
+                });
+            }
+            OutputFormat::Json => Self::json(&Explanation {
+                index: memory.index,
+                phrases,
+            })?,
+        }
+        Ok(())
+    }
+
+    /// Print the effective configuration, as TOML like `config.toml`.
+    pub fn config(&self, config: &Config) -> Result<()> {
+        match self.format {
+            OutputFormat::Human => print!("{}", toml::to_string(config)?),
+            OutputFormat::Json => Self::json(config)?,
+        }
+        Ok(())
+    }
+
+    /// Print the names of the stores in the data directory.
+    pub fn stores(&self, names: &[String]) -> Result<()> {
+        match self.format {
+            OutputFormat::Human if names.is_empty() => self.status("No stores found!"),
+            OutputFormat::Human => names.iter().for_each(|name| println!("{name}")),
+            OutputFormat::Json => Self::json(&names)?,
+        }
+        Ok(())
+    }
+
+    /// Print the rolling backups of a store, newest first.
+    pub fn backups(&self, backups: &[Backup]) -> Result<()> {
+        match self.format {
+            OutputFormat::Human if backups.is_empty() => self.status("No backups found!"),
+            OutputFormat::Human => {
+                for backup in backups {
+                    match backup.memories {
+                        Some(memories) => println!("{}  {} memories", backup.id, memories),
+                        None => println!("{}  unreadable", backup.id),
+                    }
+                }
+            }
+            OutputFormat::Json => Self::json(&backups)?,
+        }
+        Ok(())
+    }
+
+    /// Format a memory as `[score] value`, followed by `— (matched: description)` if
+    /// descriptions are shown.
+    ///
+    /// Lexical scores are labeled as such, since they aren't comparable to semantic ones.
+    pub fn format_memory(&self, memory: &ScoredMemory) -> String {
+        let label = match memory.source {
+            MatchSource::Semantic => "",
+            MatchSource::Lexical => "lexical ",
+            MatchSource::Hybrid => "hybrid ",
+            MatchSource::Reranked => "reranked ",
+        };
+        let formatted = format!(
+            "[{label}{score:.2}] {memory}",
+            memory = memory.value,
+            score = memory.score
+        );
+        if self.show_description {
+            format!("{formatted} — (matched: {})", memory.description)
+        } else {
+            formatted
+        }
+    }
+
+    /// The placeholders that templates can contain.
+    const TEMPLATE_PLACEHOLDERS: [&str; 4] = ["value", "description", "score", "index"];
+
+    /// Check that every `{placeholder}` in a template is one of `{value}`, `{description}`,
+    /// `{score}` and `{index}`.
+    pub fn validate_template(template: &str) -> Result<String, String> {
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let Some(end) = rest[start..].find('}') else {
+                return Err("Unclosed { in the template.".to_owned());
+            };
+            let placeholder = &rest[start + 1..start + end];
+            if !Self::TEMPLATE_PLACEHOLDERS.contains(&placeholder) {
+                return Err(format!(
+                    "Unknown placeholder {{{placeholder}}}. Use {{value}}, {{description}}, \
+                     {{score}} or {{index}}."
+                ));
+            }
+            rest = &rest[start + end + 1..];
+        }
+        Ok(template.to_owned())
+    }
+
+    /// Fill in the placeholders of the template with the fields of a memory.
+    fn render(&self, memory: &ScoredMemory) -> String {
+        let template = self.template.as_deref().unwrap_or_default();
+        // one pass, so that placeholders in the memory itself are left alone
+        let mut rendered = String::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let end = start + rest[start..].find('}').expect("templates are validated");
+            rendered.push_str(&rest[..start]);
+            match &rest[start + 1..end] {
+                "value" => rendered.push_str(&memory.value),
+                "description" => rendered.push_str(&memory.description),
+                "score" => rendered.push_str(&format!("{:.2}", memory.score)),
+                "index" => rendered.push_str(&memory.index.to_string()),
+                _ => unreachable!("templates are validated"),
+            }
+            rest = &rest[end + 1..];
+        }
+        rendered.push_str(rest);
+        rendered
+    }
+
+    /// Format a count with thousands separators, e.g. `1,240`.
+    fn format_count(count: u64) -> String {
+        let digits = count.to_string();
+        let mut formatted = String::new();
+        for (i, digit) in digits.chars().enumerate() {
+            if i > 0 && (digits.len() - i) % 3 == 0 {
+                formatted.push(',');
+            }
+            formatted.push(digit);
+        }
+        formatted
+    }
+
+    fn format_bytes(bytes: u64) -> String {
+        const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+        let mut size = bytes as f64;
+        let mut unit = 0;
+        while size >= 1024.0 && unit < UNITS.len() - 1 {
+            size /= 1024.0;
+            unit += 1;
+        }
+        if unit == 0 {
+            format!("{bytes} B")
+        } else {
+            format!("{size:.1} {}", UNITS[unit])
+        }
+    }
+
+    fn json(value: &impl Serialize) -> Result<()> {
+        println!("{}", serde_json::to_string(value)?);
+        Ok(())
+    }
+}
@@ -0,0 +1,108 @@
+use clap::ValueEnum;
+use half::f16;
+use ndarray::ArrayView1;
+use serde::{Deserialize, Serialize};
+
+/// How embedding rows are encoded in the embeddings and chunks files.
+///
+/// Rows are decoded into `f32`s when a store is loaded, and every embedding is rounded to the
+/// values its encoding can hold before it is stored, so scores are the same before and after
+/// reloading a store.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Quantization {
+    /// Full precision, 4 bytes per value.
+    #[default]
+    F32,
+    /// Half precision floats, 2 bytes per value.
+    F16,
+    /// Signed bytes scaled by the largest value of the row, 1 byte per value plus a 4 byte scale
+    /// per row.
+    Int8,
+}
+
+impl Quantization {
+    /// The name of the quantization, as passed to `--quantize`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Quantization::F32 => "f32",
+            Quantization::F16 => "f16",
+            Quantization::Int8 => "int8",
+        }
+    }
+
+    /// The number of bytes a row of `dimension` values takes.
+    pub fn row_bytes(&self, dimension: usize) -> usize {
+        match self {
+            Quantization::F32 => dimension * std::mem::size_of::<f32>(),
+            Quantization::F16 => dimension * std::mem::size_of::<f16>(),
+            Quantization::Int8 => std::mem::size_of::<f32>() + dimension,
+        }
+    }
+
+    /// Append the encoding of `row` to `bytes`.
+    pub fn encode(&self, row: ArrayView1<f32>, bytes: &mut Vec<u8>) {
+        match self {
+            Quantization::F32 => row
+                .iter()
+                .for_each(|value| bytes.extend(value.to_le_bytes())),
+            Quantization::F16 => row
+                .iter()
+                .for_each(|value| bytes.extend(f16::from_f32(*value).to_le_bytes())),
+            Quantization::Int8 => {
+                let scale = Self::int8_scale(row);
+                bytes.extend(scale.to_le_bytes());
+                for value in row {
+                    let quantized = if scale > 0.0 {
+                        (value / scale).round() as i8
+                    } else {
+                        0
+                    };
+                    bytes.extend(quantized.to_le_bytes());
+                }
+            }
+        }
+    }
+
+    /// Append the values of the row encoded in `bytes`, which holds exactly one row, to `values`.
+    pub fn decode(&self, bytes: &[u8], values: &mut Vec<f32>) {
+        match self {
+            Quantization::F32 => values.extend(
+                bytes
+                    .chunks_exact(std::mem::size_of::<f32>())
+                    .map(|bytes| f32::from_le_bytes(bytes.try_into().expect("chunks are 4 bytes"))),
+            ),
+            Quantization::F16 => {
+                values.extend(bytes.chunks_exact(std::mem::size_of::<f16>()).map(|bytes| {
+                    f16::from_le_bytes(bytes.try_into().expect("chunks are 2 bytes")).to_f32()
+                }))
+            }
+            Quantization::Int8 => {
+                let (scale, quantized) = bytes.split_at(std::mem::size_of::<f32>());
+                let scale = f32::from_le_bytes(scale.try_into().expect("the scale is 4 bytes"));
+                values.extend(
+                    quantized
+                        .iter()
+                        .map(|&byte| i8::from_le_bytes([byte]) as f32 * scale),
+                );
+            }
+        }
+    }
+
+    /// The values `row` decodes to after being encoded, i.e. the row scoring sees.
+    pub fn round_trip(&self, row: ArrayView1<f32>) -> Vec<f32> {
+        if *self == Quantization::F32 {
+            return row.to_vec();
+        }
+        let mut bytes = Vec::with_capacity(self.row_bytes(row.len()));
+        self.encode(row, &mut bytes);
+        let mut values = Vec::with_capacity(row.len());
+        self.decode(&bytes, &mut values);
+        values
+    }
+
+    /// The scale that maps the largest value of `row` to 127.
+    fn int8_scale(row: ArrayView1<f32>) -> f32 {
+        row.iter().fold(0.0f32, |max, value| max.max(value.abs())) / i8::MAX as f32
+    }
+}
@@ -0,0 +1,114 @@
+use crate::openai::{self, ChatMessage, ChatRequest};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+/// Something that scores how relevant memories are to a query more precisely than embeddings,
+/// used to reorder the best matches by embedding.
+pub trait Reranker {
+    /// Score every candidate against `query` from 0 to 1, larger is more relevant. Returns one
+    /// score per candidate, in the same order.
+    fn rerank(&self, query: &str, candidates: &[Candidate]) -> Result<Vec<f32>>;
+}
+
+/// A memory to be reranked.
+pub struct Candidate<'a> {
+    pub value: &'a str,
+    pub description: &'a str,
+}
+
+/// Reranks memories by asking a chat model to rate each of them against the query.
+pub struct LlmReranker {
+    client: openai::Client,
+    model: String,
+}
+
+#[derive(Deserialize)]
+struct Ratings {
+    scores: Vec<f32>,
+}
+
+impl LlmReranker {
+    /// The chat model that rates memories unless another one is requested.
+    pub const DEFAULT_MODEL: &'static str = "gpt-4o-mini";
+    /// The highest rating the model is asked to give.
+    const MAX_RATING: f32 = 10.0;
+    const SYSTEM_PROMPT: &'static str = "You rate how well stored memories match what a user \
+        is looking for. Every memory has a description and a value. Rate each memory from 0 \
+        (unrelated) to 10 (exactly what the user is looking for). Reply with only a JSON object \
+        of the form {\"scores\": [...]}, with one number per memory, in the order given.";
+
+    pub fn new(client: openai::Client, model: String) -> LlmReranker {
+        LlmReranker { client, model }
+    }
+
+    /// The user message listing the query and the candidates.
+    fn prompt(query: &str, candidates: &[Candidate]) -> String {
+        let mut prompt = format!("The user is looking for: {}\n\nMemories:\n", query);
+        for (i, candidate) in candidates.iter().enumerate() {
+            prompt.push_str(&format!(
+                "{}. Description: {}\n   Value: {}\n",
+                i + 1,
+                candidate.description,
+                candidate.value
+            ));
+        }
+        prompt
+    }
+
+    /// Read the ratings out of the model's reply, which may wrap the JSON object in other text.
+    fn parse_ratings(reply: &str, count: usize) -> Result<Vec<f32>> {
+        let start = reply.find('{');
+        let end = reply.rfind('}');
+        let json = match (start, end) {
+            (Some(start), Some(end)) if start < end => &reply[start..=end],
+            _ => reply,
+        };
+        let ratings: Ratings = serde_json::from_str(json)
+            .with_context(|| format!("The rerank model replied with no ratings: {:?}", reply))?;
+        if ratings.scores.len() != count {
+            return Err(anyhow::anyhow!(
+                "Wrong number of ratings from the rerank model. Expected: {}, Got: {}",
+                count,
+                ratings.scores.len()
+            ));
+        }
+        Ok(ratings
+            .scores
+            .into_iter()
+            .map(|score| (score / Self::MAX_RATING).clamp(0.0, 1.0))
+            .collect())
+    }
+}
+
+impl Reranker for LlmReranker {
+    fn rerank(&self, query: &str, candidates: &[Candidate]) -> Result<Vec<f32>> {
+        if candidates.is_empty() {
+            return Ok(vec![]);
+        }
+        let req = ChatRequest {
+            model: &self.model,
+            messages: vec![
+                ChatMessage {
+                    role: "system".to_owned(),
+                    content: Self::SYSTEM_PROMPT.to_owned(),
+                },
+                ChatMessage {
+                    role: "user".to_owned(),
+                    content: Self::prompt(query, candidates),
+                },
+            ],
+            temperature: 0.0,
+        };
+        let res = self
+            .client
+            .chat(&req)
+            .context("Failed to get ratings from the rerank model.")?;
+        let reply = res
+            .choices
+            .into_iter()
+            .next()
+            .map(|choice| choice.message.content)
+            .unwrap_or_default();
+        Self::parse_ratings(&reply, candidates.len())
+    }
+}
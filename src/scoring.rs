@@ -0,0 +1,224 @@
+use clap::ValueEnum;
+use ndarray::{Array1, ArrayView1, ArrayView2, Axis};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::time::Duration;
+
+/// The number of rows scored by each task when scoring in parallel.
+#[cfg(feature = "rayon")]
+const PARALLEL_CHUNK_ROWS: usize = 1024;
+
+/// Score every row of `embeddings` against `query` with a dot product.
+///
+/// With the `rayon` feature, chunks of rows are scored in parallel. With the `blas` feature, the
+/// product of every chunk is a BLAS `sgemv`. Embeddings are kept row-major since every score then
+/// reads one contiguous row, which is what both ndarray and BLAS are fastest at; the
+/// column-major case in `benches/scoring.rs` compares the two.
+pub fn dot_scores(embeddings: ArrayView2<f32>, query: ArrayView1<f32>) -> Vec<f32> {
+    #[cfg(feature = "rayon")]
+    {
+        use rayon::prelude::*;
+        let chunks: Vec<ArrayView2<f32>> = embeddings
+            .axis_chunks_iter(Axis(0), PARALLEL_CHUNK_ROWS)
+            .collect();
+        chunks
+            .par_iter()
+            .flat_map_iter(|chunk| chunk.dot(&query).to_vec())
+            .collect()
+    }
+    #[cfg(not(feature = "rayon"))]
+    {
+        embeddings.dot(&query).to_vec()
+    }
+}
+
+/// How embeddings are compared when scoring memories.
+///
+/// Internally every metric is turned into a similarity where larger is better, so that ranking
+/// works the same way for all of them. Scores shown to users and thresholds they pass are in the
+/// metric's own units; see `Metric::score` and `Metric::similarity`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Metric {
+    /// The dot product. Larger is more similar.
+    Dot,
+    /// The cosine of the angle between embeddings, between -1 and 1. Larger is more similar.
+    #[default]
+    Cosine,
+    /// The Euclidean distance. Smaller is more similar.
+    Euclidean,
+}
+
+impl Metric {
+    /// The name of the metric, as passed to `--metric`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Metric::Dot => "dot",
+            Metric::Cosine => "cosine",
+            Metric::Euclidean => "euclidean",
+        }
+    }
+
+    /// Whether embeddings are scaled to unit length before they are stored.
+    ///
+    /// Only cosine similarity ignores the length of embeddings, and storing unit vectors lets
+    /// it share the approximate index, which ranks by dot product.
+    pub fn normalizes(&self) -> bool {
+        matches!(self, Metric::Cosine)
+    }
+
+    /// Score every row of `embeddings` against `query` as a similarity, larger is better.
+    ///
+    /// `norms` holds the length of every row, so that only the query's length is computed.
+    pub fn similarities(
+        &self,
+        embeddings: ArrayView2<f32>,
+        norms: ArrayView1<f32>,
+        query: ArrayView1<f32>,
+    ) -> Vec<f32> {
+        let mut scores = dot_scores(embeddings, query);
+        let query_norm = query.dot(&query).sqrt();
+        match self {
+            Metric::Dot => {}
+            Metric::Cosine => scores.iter_mut().zip(norms).for_each(|(score, norm)| {
+                let norms = query_norm * norm;
+                *score = if norms > 0.0 { *score / norms } else { 0.0 };
+            }),
+            Metric::Euclidean => scores.iter_mut().zip(norms).for_each(|(score, norm)| {
+                // |q - r|² = |q|² + |r|² - 2 q·r, clamped since rounding can make it negative
+                let squared = query_norm * query_norm + norm * norm - 2.0 * *score;
+                *score = -squared.max(0.0).sqrt();
+            }),
+        }
+        scores
+    }
+
+    /// Convert a similarity into a score in the metric's own units.
+    pub fn score(&self, similarity: f32) -> f32 {
+        match self {
+            Metric::Dot | Metric::Cosine => similarity,
+            Metric::Euclidean => -similarity,
+        }
+    }
+
+    /// Convert a score in the metric's own units, e.g. a threshold, into a similarity.
+    pub fn similarity(&self, score: f32) -> f32 {
+        // negating is its own inverse
+        self.score(score)
+    }
+}
+
+/// The Euclidean length of every row of `embeddings`.
+pub fn norms(embeddings: ArrayView2<f32>) -> Array1<f32> {
+    embeddings.map_axis(Axis(1), |row| row.dot(&row).sqrt())
+}
+
+/// Rescale scores in place to `[0, 1]`, so that scores with different ranges can be blended.
+///
+/// The lowest score becomes 0 and the highest 1. If every score is the same, they all become 1
+/// if they are positive and 0 otherwise.
+pub fn rescale(scores: &mut [f32]) {
+    let min = scores.iter().copied().fold(f32::INFINITY, f32::min);
+    let max = scores.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let range = max - min;
+    for score in scores.iter_mut() {
+        *score = if range > 0.0 {
+            (*score - min) / range
+        } else if *score > 0.0 {
+            1.0
+        } else {
+            0.0
+        };
+    }
+}
+
+/// The factor that halves a score every `half_life`, for something `age` old.
+pub fn recency_decay(age: Duration, half_life: Duration) -> f32 {
+    (-std::f32::consts::LN_2 * age.as_secs_f32() / half_life.as_secs_f32()).exp()
+}
+
+/// Weigh a similarity by a recency decay between 0 and 1, so that it gets worse as the decay
+/// gets smaller.
+///
+/// Positive similarities are multiplied by the decay. Negative ones, like Euclidean similarities,
+/// are divided by it instead, since shrinking them would make them better.
+pub fn decay(similarity: f32, decay: f32) -> f32 {
+    if similarity >= 0.0 {
+        similarity * decay
+    } else {
+        similarity / decay.max(f32::MIN_POSITIVE)
+    }
+}
+
+/// Take the `k` best `(index, score)` pairs, best first.
+///
+/// The best `k` are selected in linear time and only they are sorted, in parallel with the
+/// `rayon` feature. Ties are broken by index, so the order never depends on how the pairs were
+/// produced.
+pub fn top_k(mut scores: Vec<(usize, f32)>, k: usize) -> Vec<(usize, f32)> {
+    let order =
+        |&(i, a): &(usize, f32), &(j, b): &(usize, f32)| compare_scores(b, a).then(i.cmp(&j));
+    if k == 0 {
+        return vec![];
+    }
+    if k < scores.len() {
+        // moves the k best pairs in front of the pair at index k
+        scores.select_nth_unstable_by(k, order);
+        scores.truncate(k);
+    }
+    #[cfg(feature = "rayon")]
+    {
+        use rayon::prelude::*;
+        scores.par_sort_unstable_by(order);
+    }
+    #[cfg(not(feature = "rayon"))]
+    scores.sort_unstable_by(order);
+    scores
+}
+
+/// Order scores so that NaN, e.g. from a corrupted embedding, ranks below every real score.
+pub fn compare_scores(a: f32, b: f32) -> Ordering {
+    match (a.is_nan(), b.is_nan()) {
+        (false, false) => a.total_cmp(&b),
+        (a_is_nan, b_is_nan) => b_is_nan.cmp(&a_is_nan),
+    }
+}
+
+/// Pick `k` of the rows of `embeddings` by maximal marginal relevance, and return their
+/// positions in the order they were picked.
+///
+/// Every pick is the row that maximizes `lambda * relevance - (1 - lambda) * redundancy`, where
+/// redundancy is the row's largest similarity to the rows already picked. A `lambda` of 1 picks
+/// rows by relevance alone, and smaller ones favor rows unlike the ones already picked.
+pub fn mmr(
+    metric: Metric,
+    embeddings: ArrayView2<f32>,
+    norms: ArrayView1<f32>,
+    relevance: &[f32],
+    lambda: f32,
+    k: usize,
+) -> Vec<usize> {
+    let mut redundancy = vec![f32::NEG_INFINITY; relevance.len()];
+    let mut picked = vec![false; relevance.len()];
+    let mut order = vec![];
+    while order.len() < k.min(relevance.len()) {
+        let marginal = |i: usize| {
+            if order.is_empty() {
+                relevance[i]
+            } else {
+                lambda * relevance[i] - (1.0 - lambda) * redundancy[i]
+            }
+        };
+        let best = (0..relevance.len())
+            .filter(|&i| !picked[i])
+            .max_by(|&i, &j| compare_scores(marginal(i), marginal(j)).then(j.cmp(&i)))
+            .expect("rows are left to pick");
+        picked[best] = true;
+        order.push(best);
+        let similarities = metric.similarities(embeddings, norms, embeddings.row(best));
+        for (redundancy, similarity) in redundancy.iter_mut().zip(similarities) {
+            *redundancy = redundancy.max(similarity);
+        }
+    }
+    order
+}
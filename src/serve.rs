@@ -0,0 +1,81 @@
+use anyhow::{Context, Result};
+use mem::store::{Filter, MemoryStore, SortBy};
+use serde_json::json;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/// The number of memories `/search` returns unless `k` is given.
+const DEFAULT_RESULT_COUNT: usize = 10;
+
+/// Answer `GET /search?q=DESCRIPTION&k=COUNT` requests with the memories `list` returns, as a
+/// JSON array of scored memories, until the process is stopped.
+///
+/// Requests are handled one at a time, since the store isn't meant to be shared between threads.
+pub fn serve(store: &MemoryStore, host: &str, port: u16) -> Result<()> {
+    let server = Server::http((host, port))
+        .map_err(|err| anyhow::anyhow!("{}", err))
+        .context(format!("Failed to listen on {}:{}.", host, port))?;
+    for request in server.incoming_requests() {
+        let (status, body) = match search(store, &request) {
+            Ok(body) => (200, body),
+            Err((status, message)) => (status, json!({ "error": message }).to_string()),
+        };
+        log::info!("{} {} {}", request.method(), request.url(), status);
+        let response = Response::from_string(body)
+            .with_status_code(status)
+            .with_header(
+                Header::from_bytes("Content-Type", "application/json")
+                    .expect("the header is valid"),
+            );
+        if let Err(err) = request.respond(response) {
+            log::warn!("Failed to respond: {}", err);
+        }
+    }
+    Ok(())
+}
+
+/// Handle a single request, returning the JSON body of the response or its error status.
+fn search(store: &MemoryStore, request: &Request) -> Result<String, (u16, String)> {
+    let (path, query) = request.url().split_once('?').unwrap_or((request.url(), ""));
+    if path != "/search" {
+        return Err((404, format!("No such endpoint: {}", path)));
+    }
+    if *request.method() != Method::Get {
+        return Err((405, "Only GET is supported.".to_owned()));
+    }
+    let mut description = None;
+    let mut count = DEFAULT_RESULT_COUNT;
+    for (key, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+        let value = decode(value).ok_or_else(|| (400, format!("Invalid encoding of {}.", key)))?;
+        match key {
+            "q" => description = Some(value),
+            "k" => {
+                count = value
+                    .parse()
+                    .map_err(|_| (400, format!("k must be a number. Got: {}", value)))?
+            }
+            _ => {}
+        }
+    }
+    let description = description.ok_or((400, "Pass a description as q.".to_owned()))?;
+    let memories = store
+        .list(&description, count, None, &Filter::default(), SortBy::Score)
+        .map_err(|err| (500, format!("{:#}", err)))?;
+    serde_json::to_string(&memories).map_err(|err| (500, err.to_string()))
+}
+
+/// Decode a percent-encoded query string value, where `+` is a space.
+fn decode(value: &str) -> Option<String> {
+    let mut bytes = vec![];
+    let mut rest = value.bytes();
+    while let Some(byte) = rest.next() {
+        bytes.push(match byte {
+            b'+' => b' ',
+            b'%' => {
+                let hex = [rest.next()?, rest.next()?];
+                u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?
+            }
+            byte => byte,
+        });
+    }
+    String::from_utf8(bytes).ok()
+}
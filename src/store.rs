@@ -1,255 +1,4125 @@
+use crate::bm25::Bm25Index;
+use crate::cache::QueryCache;
+use crate::embedder::{Embedder, OllamaEmbedder, OpenAiEmbedder, Provider, UnavailableEmbedder};
+use crate::index::HnswIndex;
+use crate::openai;
+use crate::quantize::Quantization;
+use crate::rerank::{Candidate, Reranker};
+use crate::scoring::{self, Metric};
 use anyhow::{Context, Result};
-use ndarray::{Array1, Array2, ArrayView};
-use openai_api_rs::v1::api as openai;
-use openai_api_rs::v1::embedding::EmbeddingRequest;
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use fs2::FileExt;
+use memmap2::Mmap;
+use ndarray::{s, Array1, Array2, ArrayView, ArrayView1, ArrayView2, Axis, Dimension};
+use serde::de::{DeserializeOwned, IgnoredAny};
 use serde::{Deserialize, Serialize};
 use serde_json;
+use std::cell::{Cell, Ref, RefCell};
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::fs::{File, OpenOptions};
-use std::io::{Seek, SeekFrom};
-use std::path::PathBuf;
+use std::io::{BufWriter, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+/// Stands in for the SQLite backend when mem is built without it, so that SQLite stores fail
+/// with an explanation instead of looking empty.
+#[cfg(not(feature = "sqlite"))]
+mod sqlite {
+    use super::{Filter, Memory, MemoryDB};
+    use anyhow::Result;
+    use std::path::Path;
+
+    fn unsupported<T>() -> Result<T> {
+        Err(anyhow::anyhow!(
+            "This store uses SQLite, but mem was built without the sqlite feature. Rebuild it \
+             with `--features sqlite`."
+        ))
+    }
+
+    pub(super) fn settings(_path: &Path) -> Result<Option<String>> {
+        unsupported()
+    }
+
+    pub(super) fn read(_path: &Path) -> Result<Option<MemoryDB>> {
+        unsupported()
+    }
+
+    pub(super) fn write(_path: &Path, _db: &MemoryDB) -> Result<()> {
+        unsupported()
+    }
+
+    pub(super) fn memories(_path: &Path) -> Result<Vec<Memory>> {
+        unsupported()
+    }
+
+    pub(super) fn count(_path: &Path, _filter: Option<&Filter>) -> Result<usize> {
+        unsupported()
+    }
+}
 
 /// A memory.
-#[derive(Clone, Serialize, Deserialize)]
-struct Memory {
-    value: String,
-    description: String,
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct Memory {
+    /// A key chosen by the caller that identifies the memory, see `MemoryStore::upsert`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub value: String,
+    /// The description of the memory that is used for semantic retrieval.
+    pub description: String,
+    /// Labels that queries can be restricted to.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// When the memory was inserted. Unknown for memories inserted before this was recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime<Utc>>,
+    /// Whether the value is embedded along with the description, so that queries can match
+    /// words that only appear in the value.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub embeds_value: bool,
+    /// Whether the memory was archived, which hides it from queries until it is restored.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub archived: bool,
+    /// Split values longer than this many words into chunks that are embedded separately, so
+    /// that queries can match any part of a long value.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunk_words: Option<usize>,
+    /// Structured data about the memory, e.g. its source or author, that is stored and returned
+    /// as is.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Value>,
+}
+
+impl Memory {
+    /// The text that the memory's embedding is computed from.
+    pub fn embedding_text(&self) -> String {
+        if self.embeds_value {
+            format!("{}\n\n{}", self.description, self.value)
+        } else {
+            self.description.clone()
+        }
+    }
+
+    /// The text that keyword search matches against.
+    fn keyword_text(&self) -> String {
+        format!("{}\n{}", self.description, self.value)
+    }
+
+    /// The chunks of the value that are embedded separately, if it is split into chunks.
+    pub fn chunks(&self) -> Vec<String> {
+        let Some(chunk_words) = self.chunk_words.filter(|&words| words > 0) else {
+            return vec![];
+        };
+        let words: Vec<&str> = self.value.split_whitespace().collect();
+        if words.len() <= chunk_words {
+            return vec![];
+        }
+        words
+            .chunks(chunk_words)
+            .map(|chunk| chunk.join(" "))
+            .collect()
+    }
+
+    fn into_scored(self, index: usize, score: f32) -> ScoredMemory {
+        ScoredMemory {
+            index,
+            id: self.id,
+            value: self.value,
+            description: self.description,
+            tags: self.tags,
+            created_at: self.created_at,
+            embeds_value: self.embeds_value,
+            metadata: self.metadata,
+            score,
+            source: MatchSource::Semantic,
+        }
+    }
+}
+
+/// A memory along with its similarity score.
+///
+/// `index` is the position of the memory in the store, not its rank in a result set.
+#[derive(Serialize)]
+pub struct ScoredMemory {
+    pub index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub value: String,
+    pub description: String,
+    pub tags: Vec<String>,
+    pub created_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub embeds_value: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Value>,
+    pub score: f32,
+    /// How the memory was matched, which decides what its score means.
+    #[serde(skip_serializing_if = "MatchSource::is_semantic")]
+    pub source: MatchSource,
+}
+
+/// How a `ScoredMemory` was matched.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchSource {
+    /// By embedding, scored with the store's metric.
+    #[default]
+    Semantic,
+    /// By the characters of its value or description, scored from 0 to 1.
+    Lexical,
+    /// By a blend of its embedding score and a BM25 keyword score, from 0 to 1.
+    Hybrid,
+    /// By a `Reranker`'s score of the best matches by embedding, from 0 to 1.
+    Reranked,
+}
+
+impl MatchSource {
+    fn is_semantic(&self) -> bool {
+        matches!(self, MatchSource::Semantic)
+    }
+}
+
+/// A single embedding vector.
+pub type Embedding = Array1<f32>;
+/// One embedding per row, in the same order as the memories.
+pub type EmbeddingMatrix = Array2<f32>;
+
+/// A memory database.
+///
+/// Only the memories are serialized to the data file. The embeddings are stored separately as
+/// a flat blob of little-endian `f32`s, one row per memory, so that inserts can append a single
+/// row instead of rewriting every embedding.
+#[derive(Serialize, Deserialize)]
+struct MemoryDB {
+    /// The version of the data file format. Stores written before it was recorded are version 0.
+    #[serde(default)]
+    version: u32,
+    /// The embedding model the memories were embedded with.
+    #[serde(default = "legacy_model")]
+    model: String,
+    /// The dimension of the embeddings, known once the first memory has been embedded.
+    #[serde(default)]
+    dimension: Option<usize>,
+    /// The exact model revision the provider reported when the memories were embedded, if it
+    /// reports one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    model_revision: Option<String>,
+    /// How memories are scored. Stores written before it was recorded use cosine similarity.
+    #[serde(default)]
+    metric: Metric,
+    /// How embedding rows are encoded in the embeddings and chunks files.
+    #[serde(default)]
+    quantization: Quantization,
+    memories: Vec<Memory>,
+    /// Only deserialized from stores written before embeddings moved to their own file.
+    #[serde(default, skip_serializing)]
+    embeddings: EmbeddingMatrix,
+    /// The length of every embedding row, so that scoring never recomputes them.
+    #[serde(skip)]
+    norms: Array1<f32>,
+    /// The number of leading embedding rows that are already saved in the embeddings file, or
+    /// `None` if the whole file needs to be rewritten.
+    #[serde(skip)]
+    persisted_rows: Option<usize>,
+    /// Whether the text of memories was changed in place since the database was saved, which
+    /// leaves the embeddings alone but invalidates the BM25 index.
+    #[serde(skip)]
+    memories_edited: bool,
+    /// The index of the memory that every chunk row belongs to, see `Memory::chunk_words`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    chunk_parents: Vec<usize>,
+    /// One embedding row per chunk, kept in their own file.
+    #[serde(skip)]
+    chunks: EmbeddingMatrix,
+    /// The length of every chunk row.
+    #[serde(skip)]
+    chunk_norms: Array1<f32>,
+    /// Whether the chunks changed since the database was saved.
+    #[serde(skip)]
+    chunks_edited: bool,
+    /// Whether chunk rows were removed since the database was saved, rather than only appended,
+    /// which invalidates the chunk index.
+    #[serde(skip)]
+    chunks_rewritten: bool,
+}
+
+/// Stores written before the model was recorded were all embedded with `text-embedding-ada-002`.
+fn legacy_model() -> String {
+    MemoryStore::LEGACY_EMBEDDING_MODEL.to_owned()
+}
+
+impl MemoryDB {
+    /// Append a memory and its embedding.
+    ///
+    /// Only the new row is written to the embeddings file when the database is saved.
+    fn append(&mut self, memory: Memory, embedding: &[f32]) {
+        if self.memories.is_empty() {
+            // the first embedding decides the dimension of the store
+            self.dimension = Some(embedding.len());
+            self.embeddings = Array2::zeros((0, embedding.len()));
+            self.norms = Array1::zeros(0);
+        }
+        let embedding = self.prepare_embedding(embedding);
+        // the matrix is always in standard layout, where pushing a row grows the underlying
+        // `Vec` geometrically instead of copying the matrix, so appends are amortized O(1)
+        self.embeddings
+            .push_row(embedding.view())
+            .expect("dimension mismatch");
+        self.norms
+            .append(
+                Axis(0),
+                ArrayView::from(&[embedding.dot(&embedding).sqrt()]),
+            )
+            .expect("norms are one-dimensional");
+        self.memories.push(memory);
+    }
+
+    /// Replace the embedding of the memory at `index`.
+    fn set_embedding(&mut self, index: usize, embedding: &[f32]) {
+        let embedding = self.prepare_embedding(embedding);
+        self.norms[index] = embedding.dot(&embedding).sqrt();
+        self.embeddings.row_mut(index).assign(&embedding);
+        self.persisted_rows = None;
+    }
+
+    /// The index of the memory with `id`, if there is one.
+    fn index_of(&self, id: &str) -> Option<usize> {
+        self.memories
+            .iter()
+            .position(|memory| memory.id.as_deref() == Some(id))
+    }
+
+    /// The ids of the memories that have one.
+    fn ids(&self) -> HashSet<&str> {
+        self.memories
+            .iter()
+            .filter_map(|memory| memory.id.as_deref())
+            .collect()
+    }
+
+    /// Replace the memory at `index`, leaving its embedding row alone.
+    fn set_memory(&mut self, index: usize, memory: Memory) {
+        self.memories[index] = memory;
+        self.memories_edited = true;
+    }
+
+    /// Replace the chunk rows of the memory at `parent` with `embeddings`.
+    fn set_chunks(&mut self, parent: usize, embeddings: &[Vec<f32>]) {
+        self.remove_chunks(parent);
+        if embeddings.is_empty() {
+            return;
+        }
+        if self.chunk_parents.is_empty() {
+            self.chunks = Array2::zeros((0, self.embeddings.ncols()));
+            self.chunk_norms = Array1::zeros(0);
+        }
+        for embedding in embeddings {
+            let embedding = self.prepare_embedding(embedding);
+            self.chunks
+                .push_row(embedding.view())
+                .expect("dimension mismatch");
+            self.chunk_norms
+                .append(
+                    Axis(0),
+                    ArrayView::from(&[embedding.dot(&embedding).sqrt()]),
+                )
+                .expect("norms are one-dimensional");
+            self.chunk_parents.push(parent);
+        }
+        self.chunks_edited = true;
+    }
+
+    /// Remove the chunk rows of the memory at `parent`.
+    fn remove_chunks(&mut self, parent: usize) {
+        if !self.chunk_parents.contains(&parent) {
+            return;
+        }
+        let keep: Vec<usize> = (0..self.chunk_parents.len())
+            .filter(|&i| self.chunk_parents[i] != parent)
+            .collect();
+        self.chunks = self.chunks.select(Axis(0), &keep);
+        self.chunk_norms = self.chunk_norms.select(Axis(0), &keep);
+        self.chunk_parents = keep.iter().map(|&i| self.chunk_parents[i]).collect();
+        self.chunks_edited = true;
+        self.chunks_rewritten = true;
+    }
+
+    /// Remove the memory at `index` along with its embedding row and chunks.
+    fn remove(&mut self, index: usize) -> Memory {
+        let keep: Vec<usize> = (0..self.embeddings.nrows())
+            .filter(|&i| i != index)
+            .collect();
+        self.embeddings = self.embeddings.select(Axis(0), &keep);
+        self.norms = self.norms.select(Axis(0), &keep);
+        self.persisted_rows = None;
+        self.remove_chunks(index);
+        for parent in self
+            .chunk_parents
+            .iter_mut()
+            .filter(|parent| **parent > index)
+        {
+            *parent -= 1;
+            self.chunks_edited = true;
+        }
+        self.memories.remove(index)
+    }
+
+    /// Remove every memory and embedding. Returns the number of memories removed.
+    fn clear(&mut self) -> usize {
+        self.embeddings = Array2::zeros((0, self.embeddings.ncols()));
+        self.norms = Array1::zeros(0);
+        self.persisted_rows = None;
+        self.chunks = Array2::zeros((0, self.embeddings.ncols()));
+        self.chunk_norms = Array1::zeros(0);
+        self.chunks_edited = !self.chunk_parents.is_empty();
+        self.chunks_rewritten = self.chunks_edited;
+        self.chunk_parents.clear();
+        std::mem::take(&mut self.memories).len()
+    }
+
+    /// Turn an embedding from the embedder into the row that is stored for the metric and
+    /// quantization.
+    fn prepare_embedding(&self, embedding: &[f32]) -> Embedding {
+        let mut embedding = Embedding::from(embedding.to_vec());
+        if self.metric.normalizes() {
+            let norm = embedding.dot(&embedding).sqrt();
+            if norm > 0.0 {
+                embedding /= norm;
+            }
+        }
+        // rounded now, so that scores don't change when the row is saved and loaded again
+        Embedding::from(self.quantization.round_trip(embedding.view()))
+    }
+
+    /// Score every row against `query` as a similarity, larger is better.
+    fn similarities(&self, query: ArrayView1<f32>) -> Vec<f32> {
+        self.metric
+            .similarities(self.embeddings.view(), self.norms.view(), query)
+    }
+}
+
+/// A store for memories.
+///
+/// Memories have a description and a value. The description is used for semantic retrieval.
+///
+/// The database is loaded by the first operation that needs it and kept in memory for the
+/// lifetime of the store, so a long-lived store only reads its files once. Changes are saved
+/// right away unless `buffer_writes` is set, in which case they are saved by `flush` or when
+/// the store is dropped. A store created with `in_memory` has no files at all.
+pub struct MemoryStore {
+    data_file_path: PathBuf,
+    /// Never read or write files, see `MemoryStore::in_memory`.
+    in_memory: bool,
+    /// The database, once an operation has loaded it.
+    db: RefCell<Option<MemoryDB>>,
+    /// Whether `db` has changes that haven't been saved yet.
+    dirty: bool,
+    /// Only save changes on `flush` instead of after every change.
+    buffer_writes: bool,
+    embedder: Box<dyn Embedder>,
+    query_cache: Option<QueryCache>,
+    /// The tokens billed for everything embedded since the store was opened, if the embedder
+    /// reports usage.
+    tokens_embedded: Cell<Option<u64>>,
+    /// Always score every memory, even when the store is large enough for the approximate index.
+    exact_search: bool,
+    /// Only use cached query embeddings, never the embedder.
+    offline: bool,
+    /// The metric requested for the store. Defaults to the metric the store was built with.
+    metric: Option<Metric>,
+    /// The quantization requested for the store. Defaults to the one the store was built with.
+    quantization: Option<Quantization>,
+    /// The weight of embedding scores when blending them with BM25 scores, or `None` to rank by
+    /// embedding alone.
+    hybrid_alpha: Option<f32>,
+    /// Halve the similarity of memories every time they get this much older, or `None` to rank
+    /// them regardless of their age.
+    recency_half_life: Option<Duration>,
+    /// The model revision that embeddings must come from, instead of the store's.
+    model_revision: Option<String>,
+    /// Only warn when the model revision differs from the store's, instead of failing.
+    allow_model_drift: bool,
+    /// The number of rolling backups to keep. 0 disables them.
+    backup_count: usize,
+    /// Compress the data file with zstd when saving it.
+    compress: bool,
+    /// Where the store keeps its memories, see `MemoryStore::stored_backend`.
+    backend: Backend,
+    /// The backend requested for the store. Defaults to the one the store was built with.
+    requested_backend: Option<Backend>,
+    /// Held for the lifetime of the store so that concurrent processes don't clobber each other.
+    _lock_file: Option<File>,
+}
+
+/// Options for loading a `MemoryStore`.
+#[derive(Clone, Debug, Default)]
+pub struct StoreOptions {
+    pub lock_mode: LockMode,
+    pub provider: Provider,
+    /// The URL of the Ollama server, when using the Ollama provider.
+    pub ollama_url: Option<String>,
+    /// The maximum number of query embeddings to cache. 0 disables the cache.
+    pub query_cache_size: usize,
+    /// The embedding model to use. Defaults to the model the store was built with.
+    pub model: Option<String>,
+    /// The data directory. Overrides the `MEM_DATA_DIR` environment variable and `~/.mem`.
+    pub data_dir: Option<PathBuf>,
+    /// The name of the store in the data directory. Defaults to `default`.
+    pub store_name: Option<String>,
+    /// Always score every memory instead of using the approximate index of large stores.
+    pub exact: bool,
+    /// The OpenAI-compatible API to send requests to. Defaults to
+    /// `openai::Client::DEFAULT_BASE_URL`.
+    pub openai_base_url: Option<String>,
+    /// OpenAI API keys to rotate through on rate limits, used unless the `OPENAI_API_KEYS` or
+    /// `OPENAI_API_KEY` environment variable is set.
+    pub openai_api_keys: Vec<String>,
+    /// How failed OpenAI API requests are retried.
+    pub retry_policy: openai::RetryPolicy,
+    /// How long an OpenAI API request may take. Defaults to `openai::Client::DEFAULT_TIMEOUT`.
+    pub timeout: Option<Duration>,
+    /// Never call the embedding provider, only use cached query embeddings.
+    pub offline: bool,
+    /// How memories are scored. Only an empty store can change its metric. Defaults to the
+    /// metric the store was built with, or cosine similarity for a new store.
+    pub metric: Option<Metric>,
+    /// How embeddings are stored. Only an empty store can change its quantization. Defaults to
+    /// the quantization the store was built with, or full precision for a new store.
+    pub quantization: Option<Quantization>,
+    /// Rank queries by a blend of embedding and BM25 keyword scores, giving embedding scores
+    /// this weight between 0 and 1. `None` ranks by embedding alone.
+    pub hybrid_alpha: Option<f32>,
+    /// Favor recent memories by halving the score of a memory every time it gets this much
+    /// older. `None` ranks memories regardless of their age.
+    pub recency_half_life: Option<Duration>,
+    /// Fail if the provider reports embedding with another model revision than this one.
+    /// Defaults to the revision the store was built with.
+    pub model_revision: Option<String>,
+    /// Only warn if the provider's model revision differs from the store's, instead of failing.
+    pub allow_model_drift: bool,
+    /// Copy the store into a timestamped backup before every save, keeping this many. 0 disables
+    /// backups.
+    pub backup_count: usize,
+    /// Compress the data file with zstd when saving it. Compressed and uncompressed data files
+    /// both load, whatever this is set to.
+    pub compress: bool,
+    /// Where memories are kept. Only `migrate` can convert a store to another backend. Defaults
+    /// to the backend the store was built with, or JSON for a new store.
+    pub backend: Option<Backend>,
+}
+
+/// Restricts which memories a query considers.
+///
+/// Memories that don't match are excluded before scoring, not just from the results.
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    /// Only consider memories with every one of these tags.
+    pub tags: Vec<String>,
+    /// Only consider memories inserted at or after this time.
+    pub since: Option<DateTime<Utc>>,
+    /// Only consider archived memories, instead of only the ones that aren't archived.
+    pub archived: bool,
+    /// Only consider memories whose value has at least this many characters.
+    pub min_length: Option<usize>,
+    /// Only consider memories whose value has at most this many characters.
+    pub max_length: Option<usize>,
+    /// Never consider the memories at these indices, e.g. ones a previous query returned.
+    pub exclude: Vec<usize>,
+}
+
+impl Filter {
+    /// Check whether the filter lets every memory of `db` through.
+    fn lets_all_through(&self, db: &MemoryDB) -> bool {
+        self.filters_by_archived_alone()
+            && db
+                .memories
+                .iter()
+                .all(|memory| memory.archived == self.archived)
+    }
+
+    /// Check whether the filter only looks at whether memories are archived.
+    fn filters_by_archived_alone(&self) -> bool {
+        self.tags.is_empty()
+            && self.since.is_none()
+            && self.min_length.is_none()
+            && self.max_length.is_none()
+            && self.exclude.is_empty()
+    }
+
+    fn matches(&self, memory: &Memory) -> bool {
+        memory.archived == self.archived
+            && self.tags.iter().all(|tag| memory.tags.contains(tag))
+            && match self.since {
+                Some(since) => memory
+                    .created_at
+                    .is_some_and(|created_at| created_at >= since),
+                None => true,
+            }
+            && self.matches_length(memory.value.chars().count())
+    }
+
+    fn matches_length(&self, length: usize) -> bool {
+        let too_short = self.min_length.is_some_and(|min| length < min);
+        let too_long = self.max_length.is_some_and(|max| length > max);
+        !too_short && !too_long
+    }
+
+    /// The indices of the memories that match the filter and aren't excluded.
+    fn candidates(&self, memories: &[Memory]) -> Vec<usize> {
+        memories
+            .iter()
+            .enumerate()
+            .filter(|(i, memory)| !self.exclude.contains(i) && self.matches(memory))
+            .map(|(i, _)| i)
+            .collect()
+    }
+}
+
+/// The order in which `MemoryStore::list` returns memories.
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+pub enum SortBy {
+    /// Best match first.
+    #[default]
+    Score,
+    /// Most recently inserted first.
+    Time,
+}
+
+/// How much a phrase of a memory's description matches a query, as found by
+/// `MemoryStore::explain`.
+#[derive(Debug, Serialize)]
+pub struct PhraseScore {
+    pub phrase: String,
+    pub score: f32,
 }
 
-impl Memory {
-    fn into_scored(self, score: f32) -> ScoredMemory {
-        ScoredMemory {
-            value: self.value,
-            description: self.description,
-            score,
+/// What `MemoryStore::stats` found out about a store.
+#[derive(Debug, Serialize)]
+pub struct StoreStats {
+    pub memories: usize,
+    pub model: String,
+    pub metric: Metric,
+    pub quantization: Quantization,
+    /// Unknown until the first memory has been embedded.
+    pub dimension: Option<usize>,
+    /// The combined size of the store's files, in bytes.
+    pub disk_size: u64,
+    /// The number of pairs of memories that were scored against each other.
+    pub sampled_pairs: usize,
+    /// The mean score of the sampled pairs, or `None` if there were none.
+    pub mean_score: Option<f32>,
+    /// How the scores of the sampled pairs are distributed, lowest scores first.
+    pub histogram: Vec<ScoreBucket>,
+}
+
+/// The number of sampled pairs of memories that score between `start` and `end`.
+#[derive(Debug, Serialize)]
+pub struct ScoreBucket {
+    pub start: f32,
+    pub end: f32,
+    pub count: usize,
+}
+
+/// A problem with the store found by `MemoryStore::doctor`.
+#[derive(Debug)]
+pub struct Problem {
+    pub description: String,
+    /// Whether the problem was fixed.
+    pub fixed: bool,
+}
+
+/// The result of `MemoryStore::get_with_tie_detection`.
+#[derive(Debug)]
+pub enum TieResult {
+    /// No memory matched.
+    NotFound,
+    /// A single memory scored clearly above the rest.
+    Unique(ScoredMemory),
+    /// Several memories scored within epsilon of the best one, best first.
+    Tie(Vec<ScoredMemory>),
+}
+
+/// What `MemoryStore::insert_checked` did with a memory.
+#[derive(Clone, Copy, Debug)]
+pub enum InsertOutcome {
+    /// The memory was appended to the store.
+    Inserted,
+    /// The memory at `index` is a duplicate with the same value, so nothing changed.
+    Skipped { index: usize },
+    /// The memory at `index` is a duplicate, and its value was replaced.
+    Merged { index: usize },
+    /// The memory at `index` has the same id, and was replaced.
+    Updated { index: usize },
+}
+
+/// What `MemoryStore::merge` did with the memories of the other store.
+#[derive(Clone, Copy, Debug)]
+pub struct MergeReport {
+    /// The number of memories appended to the store.
+    pub merged: usize,
+    /// The number of memories left out as duplicates of memories in the store.
+    pub skipped: usize,
+}
+
+/// A rolling backup of a store, see `StoreOptions::backup_count`.
+#[derive(Clone, Debug, Serialize)]
+pub struct Backup {
+    /// The time the backup was taken, e.g. `20240131T120000.000Z`, which identifies it.
+    pub id: String,
+    /// The number of memories in the backup, if its data file can be read.
+    pub memories: Option<usize>,
+}
+
+/// The tokens a store had embedded, see `MemoryStore::embedding_usage`.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct EmbeddingUsage {
+    pub tokens: u64,
+    /// The estimated cost in US dollars. Unknown for models without a known price.
+    pub estimated_cost: Option<f64>,
+}
+
+/// How to behave when another process holds the lock on the store.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum LockMode {
+    /// Wait until the lock is released.
+    #[default]
+    Blocking,
+    /// Fail immediately.
+    NonBlocking,
+}
+
+/// Where a store keeps its memories and embeddings.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Backend {
+    /// A JSON data file, with the embeddings in binary files next to it.
+    #[default]
+    Json,
+    /// A single SQLite database, which `count` can filter without loading the memories. Queries
+    /// still load the whole store, like with JSON stores. Needs mem to be built with the
+    /// `sqlite` feature.
+    Sqlite,
+}
+
+impl Backend {
+    /// The name of the backend, as passed to `--backend`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Backend::Json => "json",
+            Backend::Sqlite => "sqlite",
+        }
+    }
+}
+
+impl MemoryStore {
+    /// The version of the data file format written by this version of mem.
+    const FORMAT_VERSION: u32 = 1;
+    /// The bytes that zstd frames start with, which tell compressed data files apart.
+    const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+    /// The zstd compression level of compressed data files.
+    const ZSTD_LEVEL: i32 = 3;
+    const LEGACY_EMBEDDING_MODEL: &'static str = "text-embedding-ada-002";
+    /// The dimension of stores written before the dimension was recorded.
+    const LEGACY_EMBEDDING_SIZE: usize = 1536;
+    /// The maximum number of texts sent in a single embeddings request.
+    const EMBEDDING_BATCH_SIZE: usize = 100;
+    /// Stores with at least this many memories are searched with an approximate index.
+    const INDEX_MIN_MEMORIES: usize = 10_000;
+    /// The maximum number of neighbors per node in the approximate index.
+    const INDEX_M: usize = 16;
+    const INDEX_EF_CONSTRUCTION: usize = 100;
+    const INDEX_EF_SEARCH: usize = 64;
+    /// The minimum lexical score for `get_hybrid` to prefer a lexical match.
+    const LEXICAL_MIN_SCORE: f32 = 0.85;
+    /// The longest phrase, in words, that `explain` scores.
+    const EXPLAIN_MAX_PHRASE_WORDS: usize = 3;
+    /// The number of best matches per result that `list_diverse` picks from.
+    const MMR_CANDIDATES_PER_RESULT: usize = 5;
+    /// The maximum number of memories that `stats` scores against each other.
+    const STATS_SAMPLE_ROWS: usize = 200;
+    const STATS_HISTOGRAM_BUCKETS: usize = 10;
+
+    /// Insert a new memory into the store.
+    ///
+    /// If `include_value` is set, the value is embedded along with the description.
+    pub fn insert(
+        &mut self,
+        memory: &str,
+        description: &str,
+        tags: &[String],
+        include_value: bool,
+    ) -> Result<()> {
+        Self::validate_text("Memory", memory)?;
+        Self::validate_text("Description", description)?;
+        let memory = Memory {
+            value: memory.to_string(),
+            description: description.to_string(),
+            tags: tags.to_vec(),
+            created_at: Some(Utc::now()),
+            embeds_value: include_value,
+            archived: false,
+            chunk_words: None,
+            metadata: None,
+            id: None,
+        };
+        self.change_db(|store, db| {
+            let embedding = store
+                .embed(db, &memory.embedding_text())
+                .context("Failed to get memory description embedding.")?;
+            db.append(memory, &embedding);
+            Ok(((), true))
+        })
+    }
+
+    /// Insert a new memory with an embedding computed elsewhere, without calling the embedder.
+    ///
+    /// The embedding must have the store's dimension, unless the store is empty, and should
+    /// come from the store's model for scores to mean anything.
+    pub fn insert_with_embedding(
+        &mut self,
+        memory: &str,
+        description: &str,
+        embedding: Vec<f32>,
+    ) -> Result<()> {
+        Self::validate_text("Memory", memory)?;
+        Self::validate_text("Description", description)?;
+        if embedding.is_empty() {
+            return Err(anyhow::anyhow!("Embedding must not be empty."));
+        }
+        let memory = Memory {
+            value: memory.to_string(),
+            description: description.to_string(),
+            tags: vec![],
+            created_at: Some(Utc::now()),
+            embeds_value: false,
+            archived: false,
+            chunk_words: None,
+            metadata: None,
+            id: None,
+        };
+        self.change_db(|_, db| {
+            Self::check_dimension(db, &embedding)?;
+            db.append(memory, &embedding);
+            Ok(((), true))
+        })
+    }
+
+    /// Insert a new memory unless the store already has one with a similar description.
+    ///
+    /// An existing memory counts as a duplicate if its description scores at least
+    /// `dedup_threshold` against the new description. A duplicate with the same value is left
+    /// untouched, while one with a different value has its value replaced by the new one.
+    /// If `include_value` is set, the value is embedded along with the description.
+    pub fn insert_checked(
+        &mut self,
+        memory: &str,
+        description: &str,
+        tags: &[String],
+        include_value: bool,
+        dedup_threshold: f32,
+    ) -> Result<InsertOutcome> {
+        Self::validate_text("Memory", memory)?;
+        Self::validate_text("Description", description)?;
+        let new_memory = Memory {
+            value: memory.to_string(),
+            description: description.to_string(),
+            tags: tags.to_vec(),
+            created_at: Some(Utc::now()),
+            embeds_value: include_value,
+            archived: false,
+            chunk_words: None,
+            metadata: None,
+            id: None,
+        };
+        self.change_db(|store, db| {
+            Self::validate_threshold(db.metric, dedup_threshold)?;
+            let embedding = store
+                .embed(db, &new_memory.embedding_text())
+                .context("Failed to get memory description embedding.")?;
+            let duplicate = Self::best_unarchived_score(db, ArrayView::from(&embedding))
+                .filter(|(_, similarity)| *similarity >= db.metric.similarity(dedup_threshold));
+            match duplicate {
+                Some((index, _)) if db.memories[index].value == memory => {
+                    Ok((InsertOutcome::Skipped { index }, false))
+                }
+                Some((index, _)) => {
+                    let mut merged = db.memories[index].clone();
+                    merged.value = memory.to_string();
+                    if merged.embeds_value {
+                        let embedding = store
+                            .embed(db, &merged.embedding_text())
+                            .context("Failed to get memory embedding.")?;
+                        db.set_embedding(index, &embedding);
+                    }
+                    let chunks = store.embed_chunks(db, &merged)?;
+                    db.set_chunks(index, &chunks);
+                    db.set_memory(index, merged);
+                    Ok((InsertOutcome::Merged { index }, true))
+                }
+                None => {
+                    db.append(new_memory, &embedding);
+                    Ok((InsertOutcome::Inserted, true))
+                }
+            }
+        })
+    }
+
+    /// Insert a memory with an id, or replace the memory that already has its id.
+    ///
+    /// Memories are matched by id alone, never by their embedding, so that a script can insert
+    /// the same memory again without duplicating it. A replaced memory keeps its index and
+    /// creation time, takes on whether `memory` is archived, so that inserting an archived memory
+    /// again restores it, and is only re-embedded if the text it is embedded from changes. If it
+    /// is identical to the memory it would replace, the store is left untouched.
+    pub fn upsert(&mut self, memory: Memory) -> Result<InsertOutcome> {
+        let Some(id) = memory.id.clone() else {
+            return Err(anyhow::anyhow!("Only memories with an id can be upserted."));
+        };
+        Self::validate_text("Id", &id)?;
+        Self::validate_text("Memory", &memory.value)?;
+        Self::validate_text("Description", &memory.description)?;
+        self.change_db(|store, db| {
+            let Some(index) = db.index_of(&id) else {
+                let mut memory = memory;
+                memory.created_at.get_or_insert_with(Utc::now);
+                let embedding = store
+                    .embed(db, &memory.embedding_text())
+                    .context("Failed to get memory description embedding.")?;
+                let chunks = store.embed_chunks(db, &memory)?;
+                db.append(memory, &embedding);
+                db.set_chunks(db.memories.len() - 1, &chunks);
+                return Ok((InsertOutcome::Inserted, true));
+            };
+            let existing = &db.memories[index];
+            let memory = Memory {
+                created_at: existing.created_at,
+                ..memory
+            };
+            if memory == *existing {
+                return Ok((InsertOutcome::Skipped { index }, false));
+            }
+            let embedding = if memory.embedding_text() != existing.embedding_text() {
+                let embedding = store
+                    .embed(db, &memory.embedding_text())
+                    .context("Failed to get memory description embedding.")?;
+                Some(embedding)
+            } else {
+                None
+            };
+            let chunks =
+                if memory.value != existing.value || memory.chunk_words != existing.chunk_words {
+                    Some(store.embed_chunks(db, &memory)?)
+                } else {
+                    None
+                };
+            if let Some(embedding) = embedding {
+                db.set_embedding(index, &embedding);
+            }
+            if let Some(chunks) = chunks {
+                db.set_chunks(index, &chunks);
+            }
+            db.set_memory(index, memory);
+            Ok((InsertOutcome::Updated { index }, true))
+        })
+    }
+
+    /// Get the memory with `id`, scored against its own embedding like `delete_at`. Archived
+    /// memories are left out, like they are from searches.
+    pub fn get_by_id(&self, id: &str) -> Result<Option<ScoredMemory>> {
+        let db = self.db()?;
+        let index = db
+            .index_of(id)
+            .filter(|&index| !db.memories[index].archived);
+        Ok(index.map(|index| {
+            let score = Self::self_score(&db, index);
+            db.memories[index].clone().into_scored(index, score)
+        }))
+    }
+
+    /// The index of the memory with `id`, e.g. to delete it with `delete_at`.
+    pub fn index_of(&self, id: &str) -> Result<Option<usize>> {
+        Ok(self.db()?.index_of(id))
+    }
+
+    /// Insert many memories into the store.
+    ///
+    /// Memories without a creation time are stamped with the current time, and the values of
+    /// memories with `chunk_words` are embedded in chunks too. Fails if a memory has the id of
+    /// another one. Nothing is inserted until every memory has been embedded, and the database
+    /// is only saved once. Returns the number of memories inserted.
+    pub fn insert_many(&mut self, items: &[Memory]) -> Result<usize> {
+        self.insert_many_with_progress(items, |_| {})
+    }
+
+    /// Insert many memories into the store like `insert_many`, calling `on_progress` with the
+    /// number of memories embedded so far after every batch.
+    pub fn insert_many_with_progress(
+        &mut self,
+        items: &[Memory],
+        mut on_progress: impl FnMut(usize),
+    ) -> Result<usize> {
+        for (i, memory) in items.iter().enumerate() {
+            Self::validate_text("Memory", &memory.value)
+                .and_then(|_| Self::validate_text("Description", &memory.description))
+                .with_context(|| format!("Memory {} is invalid.", i))?;
+        }
+        self.change_db(|store, db| {
+            let mut ids = db.ids();
+            for (i, memory) in items.iter().enumerate() {
+                let Some(id) = &memory.id else {
+                    continue;
+                };
+                if !ids.insert(id.as_str()) {
+                    return Err(anyhow::anyhow!(
+                        "Memory {} has the id {:?} of another memory. Upsert it to replace the \
+                         other memory instead.",
+                        i,
+                        id
+                    ));
+                }
+            }
+            let mut embeddings = Vec::with_capacity(items.len());
+            for chunk in items.chunks(Self::EMBEDDING_BATCH_SIZE) {
+                let texts: Vec<String> = chunk.iter().map(Memory::embedding_text).collect();
+                embeddings.extend(
+                    store
+                        .embed_batch(db, &texts)
+                        .context("Failed to get memory description embeddings.")?,
+                );
+                on_progress(embeddings.len());
+            }
+            let chunks = items
+                .iter()
+                .map(|memory| store.embed_chunks(db, memory))
+                .collect::<Result<Vec<_>>>()?;
+            for ((memory, embedding), chunks) in items.iter().zip(embeddings).zip(chunks) {
+                let mut memory = memory.clone();
+                memory.created_at.get_or_insert_with(Utc::now);
+                db.append(memory, &embedding);
+                db.set_chunks(db.memories.len() - 1, &chunks);
+            }
+            Ok((items.len(), !items.is_empty()))
+        })
+    }
+
+    /// Append the memories of `other` to the store along with their embeddings and chunks, without
+    /// calling the embedder.
+    ///
+    /// Both stores must have been built with the same model, dimension and metric. If
+    /// `dedup_threshold` is set, memories of `other` whose embedding scores at least that much
+    /// against a memory in the store are left out. Merged memories keep their creation time and
+    /// whether they are archived. Fails if a memory of `other` has the id of a memory in the
+    /// store, before anything is merged.
+    pub fn merge(
+        &mut self,
+        other: &MemoryStore,
+        dedup_threshold: Option<f32>,
+    ) -> Result<MergeReport> {
+        if !self.in_memory && self.data_file_path == other.data_file_path {
+            return Err(anyhow::anyhow!("Can't merge a store into itself."));
+        }
+        let other_db = other.db()?;
+        self.change_db(|store, db| {
+            if let Some(threshold) = dedup_threshold {
+                Self::validate_threshold(db.metric, threshold)?;
+            }
+            if other_db.memories.is_empty() {
+                return Ok((
+                    MergeReport {
+                        merged: 0,
+                        skipped: 0,
+                    },
+                    false,
+                ));
+            }
+            if other_db.model != db.model {
+                return Err(anyhow::anyhow!(
+                    "The other store was built with the {} model but this store uses the {} \
+                     model. Embeddings from different models can't be mixed in one store, so \
+                     re-embed one of them with `mem reembed` first.",
+                    other_db.model,
+                    db.model
+                ));
+            }
+            if db.memories.is_empty() {
+                // an empty store takes on the metric and revision of the other one
+                db.metric = other_db.metric;
+                db.model_revision = other_db.model_revision.clone();
+            } else {
+                if other_db.embeddings.ncols() != db.embeddings.ncols() {
+                    return Err(anyhow::anyhow!(
+                        "The other store has embeddings of dimension {} but this store has \
+                         dimension {}.",
+                        other_db.embeddings.ncols(),
+                        db.embeddings.ncols()
+                    ));
+                }
+                if other_db.metric != db.metric {
+                    return Err(anyhow::anyhow!(
+                        "The other store uses the {} metric but this store uses the {} metric.",
+                        other_db.metric.name(),
+                        db.metric.name()
+                    ));
+                }
+                if let (Some(revision), Some(other_revision)) =
+                    (&db.model_revision, &other_db.model_revision)
+                {
+                    if revision != other_revision {
+                        let message = format!(
+                            "The other store was embedded with revision {} of the {} model \
+                             but this store with revision {}.",
+                            other_revision, db.model, revision
+                        );
+                        if !store.allow_model_drift {
+                            return Err(anyhow::anyhow!(
+                                "{} Pass --force to merge it anyway.",
+                                message
+                            ));
+                        }
+                        log::warn!("{}", message);
+                    }
+                }
+            }
+            let ids = db.ids();
+            for (i, memory) in other_db.memories.iter().enumerate() {
+                if let Some(id) = memory.id.as_deref().filter(|id| ids.contains(id)) {
+                    return Err(anyhow::anyhow!(
+                        "Memory {} of the other store has the id {:?} of a memory in this store. \
+                         Delete one of them or upsert it to replace the other instead.",
+                        i,
+                        id
+                    ));
+                }
+            }
+            let mut chunks: Vec<Vec<Vec<f32>>> = vec![vec![]; other_db.memories.len()];
+            for (row, &parent) in other_db
+                .chunks
+                .rows()
+                .into_iter()
+                .zip(&other_db.chunk_parents)
+            {
+                chunks[parent].push(row.to_vec());
+            }
+            let mut report = MergeReport {
+                merged: 0,
+                skipped: 0,
+            };
+            for ((memory, embedding), chunks) in other_db
+                .memories
+                .iter()
+                .zip(other_db.embeddings.rows())
+                .zip(chunks)
+            {
+                if let Some(threshold) = dedup_threshold {
+                    let duplicate = Self::best_unarchived_score(db, embedding)
+                        .filter(|(_, similarity)| *similarity >= db.metric.similarity(threshold));
+                    if duplicate.is_some() {
+                        report.skipped += 1;
+                        continue;
+                    }
+                }
+                db.append(memory.clone(), &embedding.to_vec());
+                db.set_chunks(db.memories.len() - 1, &chunks);
+                report.merged += 1;
+            }
+            Ok((report, report.merged > 0))
+        })
+    }
+
+    /// Update the value and/or description of the memory at `index`.
+    ///
+    /// The memory is only re-embedded if the text it is embedded from changes.
+    pub fn update(
+        &mut self,
+        index: usize,
+        new_value: Option<&str>,
+        new_description: Option<&str>,
+    ) -> Result<()> {
+        if new_value.is_none() && new_description.is_none() {
+            return Err(anyhow::anyhow!(
+                "Nothing to update. Provide a new memory and/or description."
+            ));
+        }
+        if let Some(value) = new_value {
+            Self::validate_text("Memory", value)?;
+        }
+        if let Some(description) = new_description {
+            Self::validate_text("Description", description)?;
+        }
+        self.change_db(|store, db| {
+            Self::check_index(db, index)?;
+            let mut memory = db.memories[index].clone();
+            if let Some(description) = new_description {
+                memory.description = description.to_string();
+            }
+            if let Some(value) = new_value {
+                memory.value = value.to_string();
+            }
+            if memory.embedding_text() != db.memories[index].embedding_text() {
+                let embedding = store
+                    .embed(db, &memory.embedding_text())
+                    .context("Failed to get memory description embedding.")?;
+                db.set_embedding(index, &embedding);
+            }
+            if memory.value != db.memories[index].value {
+                let chunks = store.embed_chunks(db, &memory)?;
+                db.set_chunks(index, &chunks);
+            }
+            db.set_memory(index, memory);
+            Ok(((), true))
+        })
+    }
+
+    /// Embed a description the way queries are embedded, without ranking any memories.
+    ///
+    /// The embedding comes from the query cache if it is there, and is returned as the embedder
+    /// produced it; cosine stores scale it to unit length before scoring.
+    pub fn query_embedding(&self, description: &str) -> Result<Vec<f32>> {
+        Self::validate_text("Description", description)?;
+        let db = self.db()?;
+        self.embed(&db, description)
+            .context("Failed to get query embedding.")
+    }
+
+    /// Get a memory from the store.
+    ///
+    /// Only memories matching `filter` are considered. Returns `None` if the best match scores
+    /// below `threshold`.
+    pub fn get(
+        &self,
+        description: &str,
+        threshold: Option<f32>,
+        filter: &Filter,
+    ) -> Result<Option<ScoredMemory>> {
+        Self::validate_text("Description", description)?;
+        let db = self.db()?;
+        let threshold = threshold
+            .map(|threshold| self.query_threshold(&db, threshold))
+            .transpose()?;
+        let Some((index, similarity)) = self.best_match(&db, description, filter)? else {
+            return Ok(None);
+        };
+        if threshold.is_some_and(|threshold| similarity < threshold) {
+            return Ok(None);
+        }
+        let memory = db.memories[index].clone();
+        Ok(Some(self.scored(&db, memory, index, similarity)))
+    }
+
+    /// Get a memory from the store like `get`, along with the `context` memories stored right
+    /// before and after it.
+    ///
+    /// Returns the memories in storage order, or nothing if no memory matched. Neighbors are
+    /// included whether or not they match `filter`, unless they are archived, and are scored
+    /// against the description by embedding alone.
+    pub fn get_with_context(
+        &self,
+        description: &str,
+        threshold: Option<f32>,
+        filter: &Filter,
+        context: usize,
+    ) -> Result<Vec<ScoredMemory>> {
+        let Some(matched) = self.get(description, threshold, filter)? else {
+            return Ok(vec![]);
+        };
+        let db = self.db()?;
+        let start = matched.index.saturating_sub(context);
+        let end = (matched.index + context + 1).min(db.memories.len());
+        if end - start == 1 {
+            return Ok(vec![matched]);
+        }
+        let query_embedding: Embedding = self
+            .embed(&db, description)
+            .context("Failed to get query embedding.")?
+            .into();
+        let similarities = db.metric.similarities(
+            db.embeddings.slice(s![start..end, ..]),
+            db.norms.slice(s![start..end]),
+            query_embedding.view(),
+        );
+        let mut window: Vec<ScoredMemory> = (start..end)
+            .zip(similarities)
+            .map(|(i, similarity)| {
+                db.memories[i]
+                    .clone()
+                    .into_scored(i, db.metric.score(similarity))
+            })
+            .collect();
+        window[matched.index - start] = matched;
+        window.retain(|memory| !db.memories[memory.index].archived || filter.archived);
+        Ok(window)
+    }
+
+    /// Get a memory from the store, falling back to lexical matching if semantic search finds
+    /// nothing convincing.
+    ///
+    /// If the best semantic match scores below `fallback_threshold`, the memory whose value or
+    /// description best matches the description character by character is returned instead,
+    /// provided it matches closely enough. The result's `source` says which path it came from.
+    /// Semantic matches scoring below `threshold` are never returned.
+    pub fn get_hybrid(
+        &self,
+        description: &str,
+        threshold: Option<f32>,
+        fallback_threshold: f32,
+        filter: &Filter,
+    ) -> Result<Option<ScoredMemory>> {
+        Self::validate_text("Description", description)?;
+        let db = self.db()?;
+        let threshold = threshold
+            .map(|threshold| self.query_threshold(&db, threshold))
+            .transpose()?;
+        let fallback_threshold = self.query_threshold(&db, fallback_threshold)?;
+        let semantic = self.best_match(&db, description, filter)?;
+        if let Some((index, similarity)) = semantic {
+            if similarity >= fallback_threshold {
+                if threshold.is_some_and(|threshold| similarity < threshold) {
+                    return Ok(None);
+                }
+                let memory = db.memories[index].clone();
+                return Ok(Some(self.scored(&db, memory, index, similarity)));
+            }
+        }
+        let query = description.to_lowercase();
+        let lexical = filter
+            .candidates(&db.memories)
+            .into_iter()
+            .map(|i| {
+                let memory = &db.memories[i];
+                let score = Self::lexical_score(&query, &memory.value)
+                    .max(Self::lexical_score(&query, &memory.description));
+                (i, score)
+            })
+            .max_by(|(i, a), (j, b)| scoring::compare_scores(*a, *b).then(j.cmp(i)))
+            .filter(|(_, score)| *score >= Self::LEXICAL_MIN_SCORE);
+        if let Some((index, score)) = lexical {
+            let mut memory = db.memories[index].clone().into_scored(index, score);
+            memory.source = MatchSource::Lexical;
+            return Ok(Some(memory));
+        }
+        Ok(semantic
+            .filter(|(_, similarity)| match threshold {
+                Some(threshold) => *similarity >= threshold,
+                None => true,
+            })
+            .map(|(index, similarity)| {
+                self.scored(&db, db.memories[index].clone(), index, similarity)
+            }))
+    }
+
+    /// Score how closely `text` matches the lowercase `query` by its characters alone, from 0
+    /// to 1.
+    ///
+    /// Text containing the query scores 1. Otherwise the score is the Jaro-Winkler similarity,
+    /// which favors text starting like the query.
+    fn lexical_score(query: &str, text: &str) -> f32 {
+        let text = text.to_lowercase();
+        if text.contains(query) {
+            return 1.0;
+        }
+        strsim::jaro_winkler(query, &text) as f32
+    }
+
+    /// Get a memory from the store like `get`, but let `reranker` pick which of the `k` best
+    /// matches by embedding is returned.
+    ///
+    /// Candidates scoring below `threshold` by embedding are left out before reranking. The
+    /// result is scored by the reranker, and ties keep the embedding order.
+    pub fn get_reranked(
+        &self,
+        description: &str,
+        k: usize,
+        threshold: Option<f32>,
+        filter: &Filter,
+        reranker: &dyn Reranker,
+    ) -> Result<Option<ScoredMemory>> {
+        if k == 0 {
+            return Err(anyhow::anyhow!("k must be at least 1."));
+        }
+        let candidates = self.list(description, k, threshold, filter, SortBy::Score)?;
+        if candidates.is_empty() {
+            return Ok(None);
+        }
+        let scores = reranker.rerank(
+            description,
+            &candidates
+                .iter()
+                .map(|memory| Candidate {
+                    value: &memory.value,
+                    description: &memory.description,
+                })
+                .collect::<Vec<_>>(),
+        )?;
+        if scores.len() != candidates.len() {
+            return Err(anyhow::anyhow!(
+                "The reranker scored {} of {} candidates.",
+                scores.len(),
+                candidates.len()
+            ));
+        }
+        let (best, score) = scores
+            .into_iter()
+            .enumerate()
+            .max_by(|(i, a), (j, b)| scoring::compare_scores(*a, *b).then(j.cmp(i)))
+            .expect("there are candidates");
+        let mut memory = candidates
+            .into_iter()
+            .nth(best)
+            .expect("best is a candidate");
+        memory.score = score;
+        memory.source = MatchSource::Reranked;
+        Ok(Some(memory))
+    }
+
+    /// Get a memory from the store, detecting ambiguous matches.
+    ///
+    /// Like `get`, but if other memories score within `epsilon` of the best match, every one of
+    /// them is returned as a `TieResult::Tie` instead of picking one arbitrarily.
+    pub fn get_with_tie_detection(
+        &self,
+        description: &str,
+        threshold: Option<f32>,
+        epsilon: f32,
+        filter: &Filter,
+    ) -> Result<TieResult> {
+        Self::validate_text("Description", description)?;
+        if !(epsilon >= 0.0 && epsilon.is_finite()) {
+            return Err(anyhow::anyhow!(
+                "Epsilon must be a non-negative number. Got: {}",
+                epsilon
+            ));
+        }
+        let db = self.db()?;
+        let threshold = threshold
+            .map(|threshold| self.query_threshold(&db, threshold))
+            .transpose()?;
+        let similarities =
+            scoring::top_k(self.score_candidates(&db, description, filter)?, usize::MAX);
+        let Some(&(_, best_similarity)) = similarities.first() else {
+            return Ok(TieResult::NotFound);
+        };
+        if threshold.is_some_and(|threshold| best_similarity < threshold) {
+            return Ok(TieResult::NotFound);
+        }
+        let mut tied: Vec<ScoredMemory> = similarities
+            .into_iter()
+            .take_while(|(_, similarity)| *similarity >= best_similarity - epsilon)
+            .map(|(i, similarity)| self.scored(&db, db.memories[i].clone(), i, similarity))
+            .collect();
+        if tied.len() == 1 {
+            Ok(TieResult::Unique(tied.remove(0)))
+        } else {
+            Ok(TieResult::Tie(tied))
+        }
+    }
+
+    /// Find the memories whose value or description contains `pattern`, without embedding
+    /// anything.
+    ///
+    /// With `regex`, `pattern` is a regular expression to search for instead. Archived memories
+    /// are skipped. Returns `(index, memory)` pairs in storage order.
+    pub fn find(&self, pattern: &str, regex: bool) -> Result<Vec<(usize, Memory)>> {
+        let regex = if regex {
+            Some(regex::Regex::new(pattern).context("Failed to parse regex.")?)
+        } else {
+            None
+        };
+        let matches = |text: &str| match &regex {
+            Some(regex) => regex.is_match(text),
+            None => text.contains(pattern),
+        };
+        let db = self.db()?;
+        Ok(db
+            .memories
+            .iter()
+            .enumerate()
+            .filter(|(_, memory)| {
+                !memory.archived && (matches(&memory.value) || matches(&memory.description))
+            })
+            .map(|(i, memory)| (i, memory.clone()))
+            .collect())
+    }
+
+    /// Score how similar two texts are, in the units of the store's metric.
+    ///
+    /// Both texts are embedded in one request with the store's model, and the store's memories
+    /// are left alone.
+    pub fn similarity(&self, a: &str, b: &str) -> Result<f32> {
+        Self::validate_text("Text", a)?;
+        Self::validate_text("Text", b)?;
+        let db = self.db()?;
+        let mut embeddings = self
+            .embed_batch(&db, &[a.to_owned(), b.to_owned()])
+            .context("Failed to get embeddings.")?;
+        let b = db.prepare_embedding(&embeddings.remove(1));
+        let a = db.prepare_embedding(&embeddings.remove(0));
+        let norm = a.dot(&a).sqrt();
+        let similarity = db.metric.similarities(
+            a.insert_axis(Axis(0)).view(),
+            ArrayView::from(&[norm]),
+            b.view(),
+        )[0];
+        Ok(db.metric.score(similarity))
+    }
+
+    /// Score a query embedding against every memory in the store.
+    ///
+    /// The query can come from any source, e.g. a cache or another embedder, as long as it has
+    /// the store's dimension. The scores are in the units of the store's metric. Returns one
+    /// score per memory, in storage order.
+    pub fn score_against(&self, query: &Embedding) -> Result<Vec<f32>> {
+        let db = self.db()?;
+        let similarities = Self::query_similarities(&db, query)?;
+        Ok(similarities
+            .into_iter()
+            .map(|similarity| db.metric.score(similarity))
+            .collect())
+    }
+
+    /// Rank every memory in the store against a query embedding.
+    ///
+    /// Returns `(index, score)` pairs, best match first. See `score_against`.
+    pub fn rank(&self, query: &Embedding) -> Result<Vec<(usize, f32)>> {
+        let db = self.db()?;
+        let similarities = Self::query_similarities(&db, query)?
+            .into_iter()
+            .enumerate()
+            .collect();
+        Ok(scoring::top_k(similarities, usize::MAX)
+            .into_iter()
+            .map(|(i, similarity)| (i, db.metric.score(similarity)))
+            .collect())
+    }
+
+    /// Score a query embedding from outside the store against every memory as a similarity.
+    fn query_similarities(db: &MemoryDB, query: &Embedding) -> Result<Vec<f32>> {
+        if db.memories.is_empty() {
+            return Ok(vec![]);
+        }
+        if query.len() != db.embeddings.ncols() {
+            return Err(anyhow::anyhow!(
+                "Query embedding size is not correct. Expected: {}, Got: {}",
+                db.embeddings.ncols(),
+                query.len()
+            ));
+        }
+        Ok(db.similarities(query.view()))
+    }
+
+    /// Explain why `text`, usually a memory's description, matches the query `description`.
+    ///
+    /// Every phrase of up to three consecutive words in `text` is embedded and scored against
+    /// the query. Returns the phrases best first.
+    pub fn explain(&self, description: &str, text: &str) -> Result<Vec<PhraseScore>> {
+        Self::validate_text("Description", description)?;
+        let db = self.db()?;
+        let words: Vec<&str> = text.split_whitespace().collect();
+        let mut phrases: Vec<String> = vec![];
+        for n in 1..=Self::EXPLAIN_MAX_PHRASE_WORDS.min(words.len()) {
+            for window in words.windows(n) {
+                let phrase = window.join(" ");
+                if !phrases.contains(&phrase) {
+                    phrases.push(phrase);
+                }
+            }
+        }
+        if phrases.is_empty() {
+            return Ok(vec![]);
+        }
+        let query_embedding: Embedding = self
+            .embed(&db, description)
+            .context("Failed to get query embedding.")?
+            .into();
+        let mut phrase_similarities = Vec::with_capacity(phrases.len());
+        for chunk in phrases.chunks(Self::EMBEDDING_BATCH_SIZE) {
+            let embeddings = self
+                .embed_batch(&db, chunk)
+                .context("Failed to get phrase embeddings.")?;
+            let dimension = query_embedding.len();
+            let embeddings = Array2::from_shape_vec(
+                (embeddings.len(), dimension),
+                embeddings.into_iter().flatten().collect(),
+            )?;
+            let similarities = db.metric.similarities(
+                embeddings.view(),
+                scoring::norms(embeddings.view()).view(),
+                query_embedding.view(),
+            );
+            phrase_similarities.extend(chunk.iter().cloned().zip(similarities));
+        }
+        phrase_similarities.sort_by(|(_, a), (_, b)| scoring::compare_scores(*b, *a));
+        Ok(phrase_similarities
+            .into_iter()
+            .map(|(phrase, similarity)| PhraseScore {
+                phrase,
+                score: db.metric.score(similarity),
+            })
+            .collect())
+    }
+
+    /// Delete the memory that best matches the description from the store.
+    ///
+    /// Returns the deleted memory, or `None` if the store is empty.
+    pub fn delete(&mut self, description: &str) -> Result<Option<ScoredMemory>> {
+        Self::validate_text("Description", description)?;
+        self.change_db(|store, db| {
+            let Some((index, similarity)) =
+                store.best_match(db, description, &Filter::default())?
+            else {
+                return Ok((None, false));
+            };
+            let memory = db.remove(index);
+            Ok((Some(store.scored(db, memory, index, similarity)), true))
+        })
+    }
+
+    /// List up to `count` memories matching `filter` from the store, in `sort_by` order.
+    ///
+    /// Memories scoring below `threshold` are left out, so fewer than `count` memories may be
+    /// returned. Memories with an unknown creation time sort last by time.
+    pub fn list(
+        &self,
+        description: &str,
+        count: usize,
+        threshold: Option<f32>,
+        filter: &Filter,
+        sort_by: SortBy,
+    ) -> Result<Vec<ScoredMemory>> {
+        Self::validate_text("Description", description)?;
+        let db = self.db()?;
+        let threshold = threshold
+            .map(|threshold| self.query_threshold(&db, threshold))
+            .transpose()?;
+        let clears_threshold = |(_, similarity): &(usize, f32)| match threshold {
+            Some(threshold) => *similarity >= threshold,
+            None => true,
+        };
+        let index_similarity_pairs = match sort_by {
+            // the best matches come first, so the ones left out would never have made the cut
+            SortBy::Score => {
+                let mut scores = self.top_matches(&db, description, filter, count)?;
+                scores.retain(clears_threshold);
+                scores
+            }
+            SortBy::Time => {
+                let mut scores = self.score_candidates(&db, description, filter)?;
+                scores.retain(clears_threshold);
+                scores.sort_by_key(|(i, _)| std::cmp::Reverse(db.memories[*i].created_at));
+                scores.truncate(count);
+                scores
+            }
+        };
+        let scored_memories = index_similarity_pairs
+            .into_iter()
+            .map(|(i, similarity)| self.scored(&db, db.memories[i].clone(), i, similarity))
+            .collect();
+        Ok(scored_memories)
+    }
+
+    /// List memories like `list`, but pick them by maximal marginal relevance so that they
+    /// don't repeat each other.
+    ///
+    /// The best `MMR_CANDIDATES_PER_RESULT` matches per result are considered, and every pick
+    /// trades the score of a match against its similarity to the matches already picked;
+    /// `lambda` is the weight of the score, from 0 to 1. Memories keep their usual scores but
+    /// are returned in the order they were picked.
+    pub fn list_diverse(
+        &self,
+        description: &str,
+        count: usize,
+        threshold: Option<f32>,
+        filter: &Filter,
+        lambda: f32,
+    ) -> Result<Vec<ScoredMemory>> {
+        if !(0.0..=1.0).contains(&lambda) {
+            return Err(anyhow::anyhow!(
+                "Lambda must be between 0 and 1. Got: {}",
+                lambda
+            ));
+        }
+        let pool = count.saturating_mul(Self::MMR_CANDIDATES_PER_RESULT);
+        let candidates = self.list(description, pool, threshold, filter, SortBy::Score)?;
+        let db = self.db()?;
+        let rows: Vec<usize> = candidates.iter().map(|memory| memory.index).collect();
+        let relevance: Vec<f32> = candidates
+            .iter()
+            .map(|memory| match memory.source {
+                MatchSource::Hybrid => memory.score,
+                _ => db.metric.similarity(memory.score),
+            })
+            .collect();
+        let order = scoring::mmr(
+            db.metric,
+            db.embeddings.select(Axis(0), &rows).view(),
+            db.norms.select(Axis(0), &rows).view(),
+            &relevance,
+            lambda,
+            count,
+        );
+        let mut candidates: Vec<Option<ScoredMemory>> = candidates.into_iter().map(Some).collect();
+        Ok(order
+            .into_iter()
+            .filter_map(|position| candidates[position].take())
+            .collect())
+    }
+
+    /// Delete the memory at `index` from the store.
+    ///
+    /// The returned memory is scored against its own embedding since it is an exact match.
+    /// Returns the same type as `delete` so callers can treat both deletion modes uniformly.
+    pub fn delete_at(&mut self, index: usize) -> Result<Option<ScoredMemory>> {
+        self.change_db(|_, db| {
+            Self::check_index(db, index)?;
+            let score = Self::self_score(db, index);
+            let memory = db.remove(index);
+            Ok((Some(memory.into_scored(index, score)), true))
+        })
+    }
+
+    /// Archive the memory that best matches the description, like `delete` but so that it can
+    /// be restored with `restore_at`.
+    ///
+    /// Archived memories keep their index and embedding, but queries skip them unless
+    /// `Filter::archived` is set.
+    pub fn archive(&mut self, description: &str) -> Result<Option<ScoredMemory>> {
+        Self::validate_text("Description", description)?;
+        self.change_db(|store, db| {
+            let Some((index, similarity)) =
+                store.best_match(db, description, &Filter::default())?
+            else {
+                return Ok((None, false));
+            };
+            let mut memory = db.memories[index].clone();
+            memory.archived = true;
+            db.set_memory(index, memory.clone());
+            Ok((Some(store.scored(db, memory, index, similarity)), true))
+        })
+    }
+
+    /// Archive the memory at `index`, like `delete_at` but so that it can be restored.
+    pub fn archive_at(&mut self, index: usize) -> Result<Option<ScoredMemory>> {
+        self.set_archived(index, true).map(Some)
+    }
+
+    /// Bring back the archived memory at `index`, so that queries consider it again.
+    pub fn restore_at(&mut self, index: usize) -> Result<ScoredMemory> {
+        self.set_archived(index, false)
+    }
+
+    fn set_archived(&mut self, index: usize, archived: bool) -> Result<ScoredMemory> {
+        self.change_db(|_, db| {
+            Self::check_index(db, index)?;
+            let mut memory = db.memories[index].clone();
+            if memory.archived == archived {
+                return Err(anyhow::anyhow!(
+                    "Memory #{} is {}.",
+                    index,
+                    if archived {
+                        "already archived"
+                    } else {
+                        "not archived"
+                    }
+                ));
+            }
+            memory.archived = archived;
+            db.set_memory(index, memory.clone());
+            Ok((memory.into_scored(index, Self::self_score(db, index)), true))
+        })
+    }
+
+    /// Score the memory at `index` against its own embedding.
+    fn self_score(db: &MemoryDB, index: usize) -> f32 {
+        let similarity = db.metric.similarities(
+            db.embeddings.slice(s![index..=index, ..]),
+            db.norms.slice(s![index..=index]),
+            db.embeddings.row(index),
+        )[0];
+        db.metric.score(similarity)
+    }
+
+    /// Delete every memory from the store.
+    ///
+    /// Returns the number of memories deleted.
+    pub fn clear(&mut self) -> Result<usize> {
+        self.change_db(|_, db| Ok((db.clear(), true)))
+    }
+
+    /// Embed every memory again with the store's embedder, e.g. to switch to another model.
+    ///
+    /// Every embedding, the model, its revision and the dimension are replaced, along with the
+    /// metric if another one was requested. Unlike other operations, this works on a store built
+    /// with another model. Nothing changes unless every memory was embedded. Returns the number
+    /// of memories embedded.
+    pub fn reembed(&mut self) -> Result<usize> {
+        self.reembed_with_progress(|_| {})
+    }
+
+    /// Embed every memory again like `reembed`, calling `on_progress` with the number of
+    /// memories embedded so far after every batch.
+    pub fn reembed_with_progress(&mut self, mut on_progress: impl FnMut(usize)) -> Result<usize> {
+        self.forget_db()?;
+        // the model check of `load_db` is exactly what this works around
+        let mut db = self
+            .read_db()
+            .context("Failed to load database from file.")?;
+        db.model = self.embedder.model().to_owned();
+        db.model_revision = None;
+        db.dimension = None;
+        db.metric = self.metric.unwrap_or(db.metric);
+        db.quantization = self.quantization.unwrap_or(db.quantization);
+        let texts: Vec<String> = db.memories.iter().map(Memory::embedding_text).collect();
+        let mut embeddings = Vec::with_capacity(texts.len());
+        for batch in texts.chunks(Self::EMBEDDING_BATCH_SIZE) {
+            let batch = self
+                .embed_batch(&db, batch)
+                .context("Failed to get memory description embeddings.")?;
+            // later batches must match the dimension of the first one
+            db.dimension = Some(batch[0].len());
+            embeddings.extend(batch);
+            on_progress(embeddings.len());
+        }
+        let chunks = db
+            .memories
+            .iter()
+            .map(|memory| self.embed_chunks(&db, memory))
+            .collect::<Result<Vec<_>>>()?;
+        let memories = std::mem::take(&mut db.memories);
+        db.clear();
+        for (i, ((memory, embedding), chunks)) in
+            memories.into_iter().zip(embeddings).zip(chunks).enumerate()
+        {
+            db.append(memory, &embedding);
+            db.set_chunks(i, &chunks);
+        }
+        self.save_db(&mut db)
+            .context("Failed to save database to file.")?;
+        let count = db.memories.len();
+        *self.db.get_mut() = Some(db);
+        Ok(count)
+    }
+
+    /// Move the embeddings of a store written before they had their own file out of the data
+    /// file and into the binary embeddings file, or convert the store to the requested backend.
+    ///
+    /// Stores with inline embeddings are also migrated by the first write after loading them.
+    /// Returns the number of memories migrated, or `None` if there was nothing to migrate.
+    pub fn migrate(&mut self) -> Result<Option<usize>> {
+        self.forget_db()?;
+        let mut db = self
+            .read_db()
+            .context("Failed to load database from file.")?;
+        if let Some(backend) = self
+            .requested_backend
+            .filter(|backend| *backend != self.backend)
+        {
+            let old_file_paths = match self.backend {
+                Backend::Json => vec![
+                    self.data_file_path.clone(),
+                    Self::backup_file_path(&self.data_file_path),
+                    self.embeddings_file_path(),
+                    self.norms_file_path(),
+                    self.chunks_file_path(),
+                ],
+                Backend::Sqlite => vec![Self::sqlite_file_path(&self.data_file_path)],
+            };
+            self.backend = backend;
+            // every row is written to the new backend
+            db.persisted_rows = None;
+            db.memories_edited = true;
+            db.chunks_edited = true;
+            self.save_db(&mut db)
+                .context("Failed to save database to file.")?;
+            for path in old_file_paths {
+                Self::remove_file(&path)?;
+            }
+            return Ok(Some(db.memories.len()));
+        }
+        // only stores with inline embeddings are read without marking their rows as persisted
+        if db.memories.is_empty() || db.persisted_rows.is_some() {
+            return Ok(None);
+        }
+        self.save_db(&mut db)
+            .context("Failed to save database to file.")?;
+        Ok(Some(db.memories.len()))
+    }
+
+    /// Check the integrity of the store, fixing the problems found if `fix` is set.
+    ///
+    /// Every memory should have exactly one embedding row, and no row should contain NaN or
+    /// infinite values. Fixing drops memories without an embedding, embedding rows without a
+    /// memory and memories with an invalid embedding.
+    pub fn doctor(&mut self, fix: bool) -> Result<Vec<Problem>> {
+        self.forget_db()?;
+        if self.backend == Backend::Sqlite {
+            // the database keeps every memory and its embedding in the same row
+            return Err(anyhow::anyhow!(
+                "Only stores with the JSON backend can be checked."
+            ));
+        }
+        let Some(mut db) = Self::read_data_file::<MemoryDB>(&self.data_file_path)
+            .context("Failed to load database from file.")?
+        else {
+            return Ok(vec![]);
+        };
+        let mut problems = vec![];
+        let mut report = |description: String| {
+            problems.push(Problem {
+                description,
+                fixed: fix,
+            })
+        };
+        let inline = db.embeddings.nrows() > 0;
+        let (available_rows, dimension) = if inline {
+            (db.embeddings.nrows(), db.embeddings.ncols())
+        } else {
+            let dimension = db.dimension.unwrap_or(Self::LEGACY_EMBEDDING_SIZE);
+            let row_bytes = db.quantization.row_bytes(dimension).max(1);
+            let len = match std::fs::metadata(self.embeddings_file_path()) {
+                Ok(metadata) => metadata.len() as usize,
+                Err(err) if err.kind() == ErrorKind::NotFound => 0,
+                Err(err) => return Err(err).context("Failed to read embeddings file."),
+            };
+            if len % row_bytes != 0 {
+                report("The embeddings file ends with a partial row.".to_owned());
+            }
+            (len / row_bytes, dimension)
+        };
+        if db.dimension.is_some_and(|expected| expected != dimension) {
+            report(format!(
+                "The store records dimension {} but its embeddings have dimension {}.",
+                db.dimension.unwrap_or_default(),
+                dimension
+            ));
+        }
+        let rows = available_rows.min(db.memories.len());
+        if db.memories.len() > rows {
+            report(format!(
+                "{} memories have no embedding.",
+                db.memories.len() - rows
+            ));
+        } else if available_rows > rows {
+            report(format!(
+                "{} embedding rows have no memory.",
+                available_rows - rows
+            ));
+        }
+        db.dimension = (rows > 0).then_some(dimension);
+        if inline {
+            db.embeddings = db.embeddings.slice(s![..rows, ..]).to_owned();
+        } else {
+            db.embeddings = self
+                .load_embeddings(rows, dimension, db.quantization)
+                .context("Failed to load embeddings from file.")?;
+            db.persisted_rows = Some(rows);
+        }
+        db.norms = scoring::norms(db.embeddings.view());
+        if !db.chunk_parents.is_empty() {
+            // chunks are checked against the memories before the ones without an embedding are
+            // dropped, and then dropped along with them
+            self.load_chunks(&mut db)
+                .context("Failed to load chunks from file.")?;
+            let keep: Vec<usize> = (0..db.chunk_parents.len())
+                .filter(|&i| db.chunk_parents[i] < rows)
+                .collect();
+            if keep.len() < db.chunk_parents.len() {
+                db.chunks = db.chunks.select(Axis(0), &keep);
+                db.chunk_norms = db.chunk_norms.select(Axis(0), &keep);
+                db.chunk_parents = keep.iter().map(|&i| db.chunk_parents[i]).collect();
+                db.chunks_edited = true;
+                db.chunks_rewritten = true;
+            }
+        }
+        db.memories.truncate(rows);
+        let invalid: Vec<usize> = db
+            .embeddings
+            .rows()
+            .into_iter()
+            .enumerate()
+            .filter(|(_, row)| row.iter().any(|value| !value.is_finite()))
+            .map(|(i, _)| i)
+            .collect();
+        if !invalid.is_empty() {
+            report(format!(
+                "{} memories have NaN or infinite embedding values: {:?}",
+                invalid.len(),
+                invalid
+            ));
+        }
+        if fix && !problems.is_empty() {
+            for &index in invalid.iter().rev() {
+                db.remove(index);
+            }
+            db.version = Self::FORMAT_VERSION;
+            self.save_db(&mut db)
+                .context("Failed to save database to file.")?;
+        }
+        Ok(problems)
+    }
+
+    /// Find clusters of memories whose descriptions score at least `threshold` against each
+    /// other.
+    ///
+    /// Every memory in a cluster scores at least `threshold` against some other memory in it.
+    /// Clusters are returned in order of their first memory, each with its indices in order.
+    /// Every pair of memories is compared, which takes `O(n²)` time for `n` memories but only
+    /// `O(n)` extra memory, so it is meant for stores of up to tens of thousands of memories.
+    pub fn find_duplicates(&self, threshold: f32) -> Result<Vec<Vec<usize>>> {
+        let db = self.db()?;
+        Self::validate_threshold(db.metric, threshold)?;
+        let threshold = db.metric.similarity(threshold);
+        let rows = db.memories.len();
+        // every memory starts out in a cluster of its own, named by its first memory
+        let mut clusters: Vec<usize> = (0..rows).collect();
+        fn root(clusters: &mut [usize], mut i: usize) -> usize {
+            while clusters[i] != i {
+                clusters[i] = clusters[clusters[i]];
+                i = clusters[i];
+            }
+            i
+        }
+        for i in 0..rows {
+            let similarities = db.metric.similarities(
+                db.embeddings.slice(s![i + 1.., ..]),
+                db.norms.slice(s![i + 1..]),
+                db.embeddings.row(i),
+            );
+            for (offset, similarity) in similarities.into_iter().enumerate() {
+                if similarity >= threshold {
+                    let (a, b) = (root(&mut clusters, i), root(&mut clusters, i + 1 + offset));
+                    clusters[a.max(b)] = a.min(b);
+                }
+            }
+        }
+        let mut members: Vec<Vec<usize>> = vec![vec![]; rows];
+        for i in 0..rows {
+            let cluster = root(&mut clusters, i);
+            members[cluster].push(i);
+        }
+        Ok(members
+            .into_iter()
+            .filter(|members| members.len() > 1)
+            .collect())
+    }
+
+    /// Describe the store and how similar its memories are to each other.
+    ///
+    /// Scoring every pair of memories is quadratic, so only up to `STATS_SAMPLE_ROWS` evenly
+    /// spaced memories are scored against each other. If most pairs score high, the descriptions
+    /// may be too alike for retrieval to tell them apart.
+    pub fn stats(&self) -> Result<StoreStats> {
+        let db = self.db()?;
+        let rows = db.memories.len();
+        let sample_rows = Self::STATS_SAMPLE_ROWS.min(rows);
+        let sample: Vec<usize> = (0..sample_rows).map(|i| i * rows / sample_rows).collect();
+        let embeddings = db.embeddings.select(Axis(0), &sample);
+        let norms = db.norms.select(Axis(0), &sample);
+        let mut scores = vec![];
+        for (i, row) in embeddings.rows().into_iter().enumerate() {
+            let similarities = db.metric.similarities(
+                embeddings.slice(s![i + 1.., ..]),
+                norms.slice(s![i + 1..]),
+                row,
+            );
+            scores.extend(
+                similarities
+                    .into_iter()
+                    .map(|similarity| db.metric.score(similarity)),
+            );
+        }
+        let disk_size = [
+            self.data_file_path.clone(),
+            self.embeddings_file_path(),
+            self.norms_file_path(),
+            self.chunks_file_path(),
+            Self::sqlite_file_path(&self.data_file_path),
+            self.index_file_path(),
+            self.chunk_index_file_path(),
+            self.bm25_file_path(),
+        ]
+        .iter()
+        .map(|path| std::fs::metadata(path).map_or(0, |metadata| metadata.len()))
+        .sum::<u64>();
+        Ok(StoreStats {
+            memories: rows,
+            model: db.model.clone(),
+            metric: db.metric,
+            quantization: db.quantization,
+            dimension: db.dimension,
+            disk_size,
+            sampled_pairs: scores.len(),
+            mean_score: (!scores.is_empty())
+                .then(|| scores.iter().sum::<f32>() / scores.len() as f32),
+            histogram: Self::histogram(&scores),
+        })
+    }
+
+    /// Count the scores in equally wide buckets between the lowest and the highest score.
+    fn histogram(scores: &[f32]) -> Vec<ScoreBucket> {
+        let scores: Vec<f32> = scores.iter().copied().filter(|s| s.is_finite()).collect();
+        let (Some(min), Some(max)) = (
+            scores.iter().copied().reduce(f32::min),
+            scores.iter().copied().reduce(f32::max),
+        ) else {
+            return vec![];
+        };
+        let buckets = if max > min {
+            Self::STATS_HISTOGRAM_BUCKETS
+        } else {
+            1
+        };
+        let width = (max - min) / buckets as f32;
+        let mut counts = vec![0; buckets];
+        for score in scores {
+            let bucket = if width > 0.0 {
+                // the highest score lands on the upper edge of the last bucket
+                (((score - min) / width) as usize).min(buckets - 1)
+            } else {
+                0
+            };
+            counts[bucket] += 1;
+        }
+        counts
+            .into_iter()
+            .enumerate()
+            .map(|(i, count)| ScoreBucket {
+                start: min + i as f32 * width,
+                end: min + (i + 1) as f32 * width,
+                count,
+            })
+            .collect()
+    }
+
+    /// Get every memory in the store, without embeddings.
+    ///
+    /// Re-inserting the memories recreates the store. Unless the database is already in memory,
+    /// only the memories are read.
+    pub fn export(&self) -> Result<Vec<Memory>> {
+        #[derive(Deserialize)]
+        struct StoredMemories {
+            memories: Vec<Memory>,
+        }
+        if let Some(db) = self.db.borrow().as_ref() {
+            return Ok(db.memories.clone());
+        }
+        if self.backend == Backend::Sqlite {
+            return sqlite::memories(&Self::sqlite_file_path(&self.data_file_path))
+                .context("Failed to load memories from SQLite.");
+        }
+        let stored: Option<StoredMemories> = Self::read_data_file(&self.data_file_path)
+            .context("Failed to load database from file.")?;
+        Ok(stored.map_or(vec![], |stored| stored.memories))
+    }
+
+    /// Get every memory that isn't archived, in storage order, without embedding a query.
+    ///
+    /// Returns `(index, memory)` pairs like `find`. Like `export`, only the memories are read
+    /// unless the database is already in memory.
+    pub fn all(&self) -> Result<Vec<(usize, Memory)>> {
+        Ok(self
+            .export()?
+            .into_iter()
+            .enumerate()
+            .filter(|(_, memory)| !memory.archived)
+            .collect())
+    }
+
+    /// Count the memories in the store.
+    ///
+    /// Unless the database is already in memory, only the memories are read; their embeddings
+    /// are never loaded.
+    pub fn len(&self) -> Result<usize> {
+        #[derive(Deserialize)]
+        struct StoredMemories {
+            memories: Vec<IgnoredAny>,
+        }
+        if let Some(db) = self.db.borrow().as_ref() {
+            return Ok(db.memories.len());
+        }
+        if self.backend == Backend::Sqlite {
+            return sqlite::count(&Self::sqlite_file_path(&self.data_file_path), None)
+                .context("Failed to count memories in SQLite.");
+        }
+        let stored: Option<StoredMemories> = Self::read_data_file(&self.data_file_path)
+            .context("Failed to load database from file.")?;
+        Ok(stored.map_or(0, |stored| stored.memories.len()))
+    }
+
+    /// Count the memories that `filter` lets through.
+    ///
+    /// Like `len`, the embeddings are never loaded. A SQLite store counts them in SQL without
+    /// reading the memories either.
+    pub fn count(&self, filter: &Filter) -> Result<usize> {
+        if let Some(db) = self.db.borrow().as_ref() {
+            return Ok(filter.candidates(&db.memories).len());
+        }
+        if self.backend == Backend::Sqlite {
+            return sqlite::count(&Self::sqlite_file_path(&self.data_file_path), Some(filter))
+                .context("Failed to count memories in SQLite.");
+        }
+        Ok(filter.candidates(&self.export()?).len())
+    }
+
+    /// Check whether the store has no memories.
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Make sure `index` refers to a memory in the database.
+    fn check_index(db: &MemoryDB, index: usize) -> Result<()> {
+        if index >= db.memories.len() {
+            return Err(anyhow::anyhow!(
+                "Index {} is out of bounds. The store has {} memories.",
+                index,
+                db.memories.len()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Find the index and score of the memory matching `filter` that best matches the
+    /// description.
+    fn best_match(
+        &self,
+        db: &MemoryDB,
+        description: &str,
+        filter: &Filter,
+    ) -> Result<Option<(usize, f32)>> {
+        Ok(self
+            .top_matches(db, description, filter, 1)?
+            .into_iter()
+            .next())
+    }
+
+    /// Find the `k` memories matching `filter` that best match the description, best first.
+    ///
+    /// Returns `(index, similarity)` pairs, see `score_candidates`. Large stores are searched
+    /// with the approximate index when nothing but archived memories is filtered out, unless
+    /// exact search or hybrid ranking was requested. Archived memories asked for with
+    /// `Filter::archived` are only searched with it if every memory is archived.
+    fn top_matches(
+        &self,
+        db: &MemoryDB,
+        description: &str,
+        filter: &Filter,
+        k: usize,
+    ) -> Result<Vec<(usize, f32)>> {
+        if !self.exact_search
+            && self.hybrid_alpha.is_none()
+            && self.recency_half_life.is_none()
+            && k < db.memories.len()
+            && filter.filters_by_archived_alone()
+            && (!filter.archived || filter.lets_all_through(db))
+        {
+            if let Some(index) = self.index(db).context("Failed to load index.")? {
+                // the index ranks by dot product, and rows of stores it is built for are unit length
+                let query_embedding = db.prepare_embedding(
+                    &self
+                        .embed(db, description)
+                        .context("Failed to get query embedding.")?,
+                );
+                // archived memories stay in the index, so as many more rows are searched as
+                // there are archived memories and then left out
+                let left_out = db
+                    .memories
+                    .iter()
+                    .filter(|memory| memory.archived != filter.archived)
+                    .count();
+                let mut matches = index.search(
+                    db.embeddings.view(),
+                    query_embedding.view(),
+                    k + left_out,
+                    Self::INDEX_EF_SEARCH,
+                );
+                if !db.chunk_parents.is_empty() {
+                    matches =
+                        self.add_chunk_matches(db, matches, query_embedding.view(), k + left_out)?;
+                }
+                matches.retain(|&(i, _)| db.memories[i].archived == filter.archived);
+                matches.truncate(k);
+                return Ok(matches);
+            }
+        }
+        let scores = self.score_candidates(db, description, filter)?;
+        Ok(scoring::top_k(scores, k))
+    }
+
+    /// Rank the `matches` of the approximate index together with the parents of the `k` chunks
+    /// that best match the query, best first, so that a memory matches about as well as any
+    /// part of its value does, like with `fold_chunks`.
+    ///
+    /// The chunks are searched with their own approximate index, or scored one by one if there
+    /// are too few of them to need one.
+    fn add_chunk_matches(
+        &self,
+        db: &MemoryDB,
+        matches: Vec<(usize, f32)>,
+        query_embedding: ArrayView1<f32>,
+        k: usize,
+    ) -> Result<Vec<(usize, f32)>> {
+        let chunk_matches = match self
+            .chunk_index(db)
+            .context("Failed to load chunk index.")?
+        {
+            Some(index) => {
+                index.search(db.chunks.view(), query_embedding, k, Self::INDEX_EF_SEARCH)
+            }
+            None => db
+                .chunks
+                .dot(&query_embedding)
+                .into_iter()
+                .enumerate()
+                .collect(),
+        };
+        let mut best: HashMap<usize, f32> = matches.into_iter().collect();
+        for (chunk, chunk_similarity) in chunk_matches {
+            let parent = db.chunk_parents[chunk];
+            let similarity = best
+                .entry(parent)
+                .or_insert_with(|| db.embeddings.row(parent).dot(&query_embedding));
+            if scoring::compare_scores(chunk_similarity, *similarity).is_gt() {
+                *similarity = chunk_similarity;
+            }
+        }
+        let matches: Vec<(usize, f32)> = best.into_iter().collect();
+        let len = matches.len();
+        Ok(scoring::top_k(matches, len))
+    }
+
+    /// Score every memory matching `filter` against the description.
+    ///
+    /// Returns `(index, similarity)` pairs in storage order. Memories that don't match the
+    /// filter are never scored. With hybrid ranking, the similarities are blended with BM25
+    /// scores; see `blend_bm25`. With a recency half-life, they are then decayed by age; see
+    /// `decay_by_age`.
+    fn score_candidates(
+        &self,
+        db: &MemoryDB,
+        description: &str,
+        filter: &Filter,
+    ) -> Result<Vec<(usize, f32)>> {
+        let candidates = filter.candidates(&db.memories);
+        if candidates.is_empty() {
+            return Ok(vec![]);
+        }
+        let query_embedding: Embedding = self
+            .embed(db, description)
+            .context("Failed to get query embedding.")?
+            .into();
+        let similarities = if candidates.len() == db.memories.len() {
+            db.similarities(query_embedding.view())
+        } else {
+            let selected = db.embeddings.select(Axis(0), &candidates);
+            let norms = db.norms.select(Axis(0), &candidates);
+            db.metric
+                .similarities(selected.view(), norms.view(), query_embedding.view())
+        };
+        let similarities = Self::fold_chunks(db, &candidates, similarities, query_embedding.view());
+        let similarities = match self.hybrid_alpha {
+            Some(alpha) => self.blend_bm25(db, description, &candidates, similarities, alpha)?,
+            None => similarities,
+        };
+        let similarities = match self.recency_half_life {
+            Some(half_life) => Self::decay_by_age(db, &candidates, similarities, half_life),
+            None => similarities,
+        };
+        Ok(candidates.into_iter().zip(similarities).collect())
+    }
+
+    /// Decay the similarity of every candidate by its age, halving it every `half_life`.
+    ///
+    /// Memories with an unknown creation time are treated as old as the oldest memory whose
+    /// time is known, and memories from the future, e.g. because of clock skew, as brand new.
+    fn decay_by_age(
+        db: &MemoryDB,
+        candidates: &[usize],
+        similarities: Vec<f32>,
+        half_life: Duration,
+    ) -> Vec<f32> {
+        let now = Utc::now();
+        let oldest = db
+            .memories
+            .iter()
+            .filter_map(|memory| memory.created_at)
+            .min()
+            .unwrap_or(now);
+        candidates
+            .iter()
+            .zip(similarities)
+            .map(|(&i, similarity)| {
+                let created_at = db.memories[i].created_at.unwrap_or(oldest);
+                let age = (now - created_at).to_std().unwrap_or_default();
+                scoring::decay(similarity, scoring::recency_decay(age, half_life))
+            })
+            .collect()
+    }
+
+    /// Raise the similarity of every candidate with chunks to that of its best matching chunk, so
+    /// that a memory matches as well as any part of its value does.
+    fn fold_chunks(
+        db: &MemoryDB,
+        candidates: &[usize],
+        mut similarities: Vec<f32>,
+        query_embedding: ArrayView1<f32>,
+    ) -> Vec<f32> {
+        if db.chunk_parents.is_empty() {
+            return similarities;
+        }
+        let mut positions = vec![None; db.memories.len()];
+        for (position, &i) in candidates.iter().enumerate() {
+            positions[i] = Some(position);
+        }
+        let chunk_similarities =
+            db.metric
+                .similarities(db.chunks.view(), db.chunk_norms.view(), query_embedding);
+        for (&parent, chunk_similarity) in db.chunk_parents.iter().zip(chunk_similarities) {
+            if let Some(position) = positions[parent] {
+                let similarity = &mut similarities[position];
+                if scoring::compare_scores(chunk_similarity, *similarity).is_gt() {
+                    *similarity = chunk_similarity;
+                }
+            }
+        }
+        similarities
+    }
+
+    /// Blend BM25 keyword scores of the description into the similarities of the candidates.
+    ///
+    /// Neither score has a fixed range, so both are rescaled to `[0, 1]` across the candidates
+    /// first. The similarity is then weighted by `alpha` and the BM25 score by `1 - alpha`.
+    fn blend_bm25(
+        &self,
+        db: &MemoryDB,
+        description: &str,
+        candidates: &[usize],
+        mut similarities: Vec<f32>,
+        alpha: f32,
+    ) -> Result<Vec<f32>> {
+        let bm25_scores = self
+            .bm25_index(db)
+            .context("Failed to load BM25 index.")?
+            .scores(description);
+        let mut keyword_scores: Vec<f32> = candidates.iter().map(|&i| bm25_scores[i]).collect();
+        scoring::rescale(&mut similarities);
+        scoring::rescale(&mut keyword_scores);
+        Ok(similarities
+            .into_iter()
+            .zip(keyword_scores)
+            .map(|(similarity, keyword_score)| alpha * similarity + (1.0 - alpha) * keyword_score)
+            .collect())
+    }
+
+    /// Turn a similarity from `top_matches` or `score_candidates` into a `ScoredMemory` whose
+    /// score is in the units users see.
+    fn scored(&self, db: &MemoryDB, memory: Memory, index: usize, similarity: f32) -> ScoredMemory {
+        if self.hybrid_alpha.is_some() {
+            let mut memory = memory.into_scored(index, similarity);
+            memory.source = MatchSource::Hybrid;
+            memory
+        } else {
+            memory.into_scored(index, db.metric.score(similarity))
+        }
+    }
+
+    /// Validate a threshold for a query and convert it to a similarity, see `scored`.
+    fn query_threshold(&self, db: &MemoryDB, threshold: f32) -> Result<f32> {
+        if self.hybrid_alpha.is_none() {
+            Self::validate_threshold(db.metric, threshold)?;
+            return Ok(db.metric.similarity(threshold));
+        }
+        if !(0.0..=1.0).contains(&threshold) {
+            return Err(anyhow::anyhow!(
+                "Threshold must be between 0 and 1 for hybrid ranking. Got: {}",
+                threshold
+            ));
+        }
+        Ok(threshold)
+    }
+
+    /// Find the index and similarity of the embedding row most similar to `query_embedding`,
+    /// among the memories that aren't archived.
+    fn best_unarchived_score(
+        db: &MemoryDB,
+        query_embedding: ArrayView1<f32>,
+    ) -> Option<(usize, f32)> {
+        if db.embeddings.nrows() == 0 {
+            return None;
+        }
+        let similarities = db.similarities(query_embedding);
+        // get the index of the max similarity
+        similarities
+            .into_iter()
+            .enumerate()
+            .filter(|(i, _)| !db.memories[*i].archived)
+            .max_by(|(_, a), (_, b)| scoring::compare_scores(*a, *b))
+    }
+
+    /// Embed the chunks of a memory's value, see `Memory::chunks`.
+    fn embed_chunks(&self, db: &MemoryDB, memory: &Memory) -> Result<Vec<Vec<f32>>> {
+        let chunks = memory.chunks();
+        let mut embeddings = Vec::with_capacity(chunks.len());
+        for batch in chunks.chunks(Self::EMBEDDING_BATCH_SIZE) {
+            embeddings.extend(
+                self.embed_batch(db, batch)
+                    .context("Failed to get memory chunk embeddings.")?,
+            );
+        }
+        Ok(embeddings)
+    }
+
+    /// Embed text using the store's embedder.
+    ///
+    /// Embeddings are served from the query cache when possible. Offline, nothing else is.
+    fn embed(&self, db: &MemoryDB, text: &str) -> Result<Vec<f32>> {
+        if let Some(cache) = &self.query_cache {
+            if let Some(embedding) = cache.get(&db.model, text) {
+                log::debug!("Query cache hit for {:?}", text);
+                return Ok(embedding);
+            }
+            log::debug!("Query cache miss for {:?}", text);
+        }
+        if self.offline {
+            return Err(anyhow::anyhow!(
+                "{:?} needs to be embedded, but there is no cached embedding for it and mem is offline.",
+                text
+            ));
+        }
+        let embedding = self.embed_batch(db, &[text.to_owned()])?.remove(0);
+        if let Some(cache) = &self.query_cache {
+            // the cache only saves embedding calls, so failing to update it shouldn't fail the
+            // query, e.g. in a read-only data directory
+            if let Err(err) = cache.put(&db.model, text, &embedding) {
+                log::warn!("Failed to update query cache: {:#}", err);
+            }
+        }
+        Ok(embedding)
+    }
+
+    /// Embed many texts using the store's embedder.
+    ///
+    /// The embeddings are returned as is, in the same order as `texts`. Rows are only
+    /// normalized when they are stored, if the metric needs it.
+    fn embed_batch(&self, db: &MemoryDB, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        if texts.is_empty() {
+            return Ok(vec![]);
+        }
+        if self.offline {
+            return Err(anyhow::anyhow!(
+                "{} texts need to be embedded, but mem is offline.",
+                texts.len()
+            ));
+        }
+        log::info!("Embedding {} texts with {}", texts.len(), db.model);
+        let started = Instant::now();
+        let (embeddings, tokens) = self.embedder.embed_batch_with_usage(texts)?;
+        if let Some(tokens) = tokens {
+            self.tokens_embedded
+                .set(Some(self.tokens_embedded.get().unwrap_or(0) + tokens));
+        }
+        log::debug!("Embedded {} texts in {:?}", texts.len(), started.elapsed());
+        self.check_model_revision(db)?;
+        if embeddings.len() != texts.len() {
+            return Err(anyhow::anyhow!(
+                "Wrong number of embeddings. Expected: {}, Got: {}",
+                texts.len(),
+                embeddings.len()
+            ));
+        }
+        for embedding in &embeddings {
+            Self::check_dimension(db, embedding)?;
+            // an empty store adopts the dimension of whatever the model returns, which must
+            // at least be the same for the whole batch
+            if embedding.len() != embeddings[0].len() {
+                return Err(anyhow::anyhow!(
+                    "The {} model returned embeddings of different dimensions: {} and {}.",
+                    db.model,
+                    embeddings[0].len(),
+                    embedding.len()
+                ));
+            }
+        }
+        Ok(embeddings)
+    }
+
+    /// Check that an embedding has the dimension of the embeddings in the store, if it has any,
+    /// explaining how to fix a mismatch.
+    fn check_dimension(db: &MemoryDB, embedding: &[f32]) -> Result<()> {
+        match db.dimension.filter(|_| !db.memories.is_empty()) {
+            Some(dimension) if embedding.len() != dimension => Err(anyhow::anyhow!(
+                "The store was built with the {} model, whose embeddings have dimension {}, but \
+                 got an embedding of dimension {}. If the provider now serves another model \
+                 under that name, run `mem reembed` to re-embed every memory with it.",
+                db.model,
+                dimension,
+                embedding.len()
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check that the embedder's model revision is the pinned one, or else the one the store was
+    /// built with, since embeddings from different revisions can't be compared.
+    ///
+    /// With `allow_model_drift`, a different revision is only logged.
+    fn check_model_revision(&self, db: &MemoryDB) -> Result<()> {
+        let Some(revision) = self.embedder.model_revision() else {
+            return Ok(());
+        };
+        let stored = db
+            .model_revision
+            .as_ref()
+            .filter(|_| !db.memories.is_empty());
+        let message = match (&self.model_revision, stored) {
+            (Some(pinned), _) if *pinned != revision => format!(
+                "The {} model returned embeddings from {} but {} is pinned with --model-revision.",
+                db.model, revision, pinned
+            ),
+            (None, Some(stored)) if *stored != revision => format!(
+                "The {} model now returns embeddings from {} but the store was built with {}. \
+                 Scores would be meaningless; run `mem reembed` to rebuild the store, or pass \
+                 --force to use it anyway.",
+                db.model, revision, stored
+            ),
+            _ => return Ok(()),
+        };
+        if self.allow_model_drift {
+            log::warn!("{}", message);
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!(message))
+        }
+    }
+
+    /// Embedding blank text gives a meaningless vector, so reject it before calling the API.
+    fn validate_text(name: &str, text: &str) -> Result<()> {
+        if text.trim().is_empty() {
+            return Err(anyhow::anyhow!("{} must not be empty.", name));
+        }
+        Ok(())
+    }
+
+    /// Reject thresholds that no score of the metric can ever be compared against meaningfully.
+    fn validate_threshold(metric: Metric, threshold: f32) -> Result<()> {
+        match metric {
+            Metric::Cosine if !(-1.0..=1.0).contains(&threshold) => Err(anyhow::anyhow!(
+                "Threshold must be between -1 and 1 for cosine similarity. Got: {}",
+                threshold
+            )),
+            Metric::Euclidean if !(threshold >= 0.0 && threshold.is_finite()) => {
+                Err(anyhow::anyhow!(
+                    "Threshold must be a non-negative distance for Euclidean distance. Got: {}",
+                    threshold
+                ))
+            }
+            Metric::Dot if !threshold.is_finite() => Err(anyhow::anyhow!(
+                "Threshold must be a number. Got: {}",
+                threshold
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Loading and saving the `MemoryDB` to a file.
+///
+/// The memories are stored as JSON in the data file and their embeddings in a sibling
+/// `.embeddings` file that is memory-mapped on load.
+impl MemoryStore {
+    /// The tokens billed for everything this store embedded since it was opened, and what they
+    /// cost if the price of the model is known.
+    ///
+    /// `None` if nothing was embedded or the embedder doesn't report usage.
+    pub fn embedding_usage(&self) -> Option<EmbeddingUsage> {
+        let tokens = self.tokens_embedded.get()?;
+        let estimated_cost = openai::price_per_million_tokens(self.embedder.model())
+            .map(|price| price * tokens as f64 / 1_000_000.0);
+        Some(EmbeddingUsage {
+            tokens,
+            estimated_cost,
+        })
+    }
+
+    /// Keep changes in memory until `flush` is called, instead of saving after every change.
+    ///
+    /// This makes many small changes to a long-lived store cheap. Changes that haven't been
+    /// flushed are saved when the store is dropped, but errors are lost then, so call `flush`.
+    pub fn buffer_writes(&mut self, buffer_writes: bool) {
+        self.buffer_writes = buffer_writes;
+    }
+
+    /// Favor recent memories like `StoreOptions::recency_half_life` does, or rank memories
+    /// regardless of their age with `None`.
+    pub fn set_recency_half_life(&mut self, half_life: Option<Duration>) -> Result<()> {
+        if half_life.is_some_and(|half_life| half_life.is_zero()) {
+            return Err(anyhow::anyhow!("The recency half-life must not be zero."));
+        }
+        self.recency_half_life = half_life;
+        Ok(())
+    }
+
+    /// Save the changes made since writes were buffered.
+    pub fn flush(&mut self) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        let mut db = self
+            .db
+            .get_mut()
+            .take()
+            .expect("a store with unsaved changes has its database loaded");
+        let saved = self.save_db(&mut db);
+        *self.db.get_mut() = Some(db);
+        saved.context("Failed to save database to file.")?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Get the `MemoryDB`, loading it from the data file the first time.
+    fn db(&self) -> Result<Ref<'_, MemoryDB>> {
+        if self.db.borrow().is_none() {
+            let db = self
+                .load_db()
+                .context("Failed to load database from file.")?;
+            *self.db.borrow_mut() = Some(db);
+        }
+        Ok(Ref::map(self.db.borrow(), |db| {
+            db.as_ref().expect("the database was just loaded")
+        }))
+    }
+
+    /// Apply `change` to the `MemoryDB` and save it, unless writes are buffered.
+    ///
+    /// `change` returns its result and whether it changed the database. It must not change the
+    /// database before everything that can fail, like embedding, has succeeded, since the
+    /// database is kept as is when it fails.
+    fn change_db<T>(
+        &mut self,
+        change: impl FnOnce(&Self, &mut MemoryDB) -> Result<(T, bool)>,
+    ) -> Result<T> {
+        let mut db = match self.db.get_mut().take() {
+            Some(db) => db,
+            None => self
+                .load_db()
+                .context("Failed to load database from file.")?,
+        };
+        let (result, changed) = match change(self, &mut db) {
+            Ok(result) => result,
+            Err(err) => {
+                *self.db.get_mut() = Some(db);
+                return Err(err);
+            }
+        };
+        if changed {
+            if self.buffer_writes {
+                self.dirty = true;
+            } else {
+                // a failed save leaves the files in an unknown state, so the database is
+                // dropped and loaded again by the next operation
+                self.save_db(&mut db)
+                    .context("Failed to save database to file.")?;
+            }
+        }
+        *self.db.get_mut() = Some(db);
+        Ok(result)
+    }
+
+    /// Save any buffered changes and drop the in-memory `MemoryDB`, for operations that work on
+    /// the files directly.
+    fn forget_db(&mut self) -> Result<()> {
+        if self.in_memory {
+            return Err(anyhow::anyhow!(
+                "This only works on stores backed by files, not in-memory ones."
+            ));
+        }
+        self.flush()?;
+        *self.db.get_mut() = None;
+        Ok(())
+    }
+
+    /// Load the `MemoryDB` from the data file.
+    ///
+    /// Fails if the embedder uses a different model, or a different metric was requested, than
+    /// the one a non-empty store was built with.
+    fn load_db(&self) -> Result<MemoryDB> {
+        log::debug!("Loading {}", self.data_file_path.display());
+        let started = Instant::now();
+        if let Some(backend) = self
+            .requested_backend
+            .filter(|backend| *backend != self.backend)
+        {
+            return Err(anyhow::anyhow!(
+                "The store uses the {} backend but the {} backend was requested. Run `mem \
+                 --backend {} migrate` to convert it.",
+                self.backend.name(),
+                backend.name(),
+                backend.name()
+            ));
+        }
+        let mut db = self.read_db()?;
+        log::debug!(
+            "Loaded {} memories with the {} model in {:?}",
+            db.memories.len(),
+            db.model,
+            started.elapsed()
+        );
+        let model = self.embedder.model();
+        if db.memories.is_empty() {
+            // an empty store can switch models and metrics freely
+            db.model = model.to_owned();
+            db.model_revision = None;
+            db.dimension = None;
+            db.metric = self.metric.unwrap_or(db.metric);
+            db.quantization = self.quantization.unwrap_or(db.quantization);
+        } else if model != db.model {
+            let dimension = db
+                .dimension
+                .map(|dimension| format!(" (dimension {})", dimension))
+                .unwrap_or_default();
+            return Err(anyhow::anyhow!(
+                "The store was built with the {} model{} but the {} model was requested. \
+                 Embeddings from different models can't be mixed in one store; run \
+                 `mem reembed {}` to re-embed every memory with it.",
+                db.model,
+                dimension,
+                model,
+                model
+            ));
+        } else if let Some(metric) = self.metric.filter(|metric| *metric != db.metric) {
+            return Err(anyhow::anyhow!(
+                "The store uses the {} metric but the {} metric was requested. \
+                 Only an empty store can change its metric.",
+                db.metric.name(),
+                metric.name()
+            ));
+        } else if let Some(quantization) = self
+            .quantization
+            .filter(|quantization| *quantization != db.quantization)
+        {
+            return Err(anyhow::anyhow!(
+                "The store is quantized to {} but {} was requested. Only an empty store can \
+                 change its quantization; run `mem --quantize {} reembed` to convert it.",
+                db.quantization.name(),
+                quantization.name(),
+                quantization.name()
+            ));
+        }
+        Ok(db)
+    }
+
+    /// The `MemoryDB` of a store without memories.
+    fn empty_db(&self) -> MemoryDB {
+        MemoryDB {
+            version: Self::FORMAT_VERSION,
+            model: self.embedder.model().to_owned(),
+            model_revision: None,
+            dimension: None,
+            metric: self.metric.unwrap_or_default(),
+            quantization: self.quantization.unwrap_or_default(),
+            memories: vec![],
+            embeddings: Array2::zeros((0, 0)),
+            norms: Array1::zeros(0),
+            persisted_rows: None,
+            memories_edited: false,
+            chunk_parents: vec![],
+            chunks: Array2::zeros((0, 0)),
+            chunk_norms: Array1::zeros(0),
+            chunks_edited: false,
+            chunks_rewritten: false,
+        }
+    }
+
+    /// Read the `MemoryDB` from the data file as is.
+    fn read_db(&self) -> Result<MemoryDB> {
+        if self.backend == Backend::Sqlite {
+            let db = sqlite::read(&Self::sqlite_file_path(&self.data_file_path))
+                .context("Failed to load database from SQLite.")?;
+            return Ok(db.unwrap_or_else(|| self.empty_db()));
+        }
+        let db = match Self::read_data_file::<MemoryDB>(&self.data_file_path) {
+            Err(err) if err.is::<serde_json::Error>() => self.recover_db(err)?,
+            db => db?,
+        };
+        let Some(mut db) = db else {
+            return Ok(self.empty_db());
+        };
+        // version 0 only lacks fields that serde fills in with defaults, and its inline
+        // embeddings are handled below, so upgrading is just a matter of saving it again
+        db.version = Self::FORMAT_VERSION;
+        if db.embeddings.nrows() > 0 {
+            db.dimension = Some(db.embeddings.ncols());
+            // stores written before the embeddings file existed keep their embeddings inline,
+            // and may contain rows written before embeddings were normalized
+            for mut row in db.embeddings.rows_mut() {
+                let norm = row.dot(&row).sqrt();
+                if norm > 0.0 {
+                    row /= norm;
+                }
+            }
+        } else {
+            if db.dimension.is_none() && !db.memories.is_empty() {
+                db.dimension = Some(Self::LEGACY_EMBEDDING_SIZE);
+            }
+            db.embeddings = self
+                .load_embeddings(
+                    db.memories.len(),
+                    db.dimension.unwrap_or(0),
+                    db.quantization,
+                )
+                .context("Failed to load embeddings from file.")?;
+            db.persisted_rows = Some(db.embeddings.nrows());
+        }
+        // scoring assumes one row per memory, so an edited data file could otherwise make it
+        // index past the end of the memories
+        if db.embeddings.nrows() != db.memories.len() {
+            return Err(anyhow::anyhow!(
+                "The store has {} memories but {} embeddings. Run `mem doctor --fix` to drop \
+                 the memories or embeddings that don't have a counterpart.",
+                db.memories.len(),
+                db.embeddings.nrows()
+            ));
+        }
+        let norms = if db.persisted_rows.is_some() {
+            self.load_norms(db.memories.len())
+                .context("Failed to load norms from file.")?
+        } else {
+            // inline embeddings are moved to their own file along with their norms on save
+            None
+        };
+        db.norms = match norms {
+            Some(norms) => norms,
+            None => {
+                let norms = scoring::norms(db.embeddings.view());
+                if db.persisted_rows.is_some() {
+                    // stores written before norms were saved compute them once
+                    self.write_norms(norms.view())
+                        .context("Failed to save norms to file.")?;
+                }
+                norms
+            }
+        };
+        if !db.chunk_parents.is_empty() {
+            self.load_chunks(&mut db)
+                .context("Failed to load chunks from file.")?;
+        }
+        Ok(db)
+    }
+
+    /// Recover from a data file that can't be parsed by moving it aside to a `.corrupt` file and
+    /// falling back to the backup of the last good save, or else to an empty store.
+    fn recover_db(&self, err: anyhow::Error) -> Result<Option<MemoryDB>> {
+        let mut corrupt_file_path = self.data_file_path.as_os_str().to_owned();
+        corrupt_file_path.push(".corrupt");
+        let corrupt_file_path = PathBuf::from(corrupt_file_path);
+        std::fs::rename(&self.data_file_path, &corrupt_file_path)
+            .context("Failed to move the corrupt data file aside.")?;
+        log::warn!(
+            "{} is corrupt ({}) and was moved to {}.",
+            self.data_file_path.display(),
+            err,
+            corrupt_file_path.display()
+        );
+        let backup_file_path = Self::backup_file_path(&self.data_file_path);
+        match Self::read_data_file::<MemoryDB>(&backup_file_path) {
+            Ok(Some(db)) => {
+                // restored right away, or the next load would find no data file at all
+                std::fs::copy(&backup_file_path, &self.data_file_path)
+                    .context("Failed to restore the backup of the data file.")?;
+                log::warn!(
+                    "Restored the {} memories of the last good save from {}. Memories \
+                     inserted after it are lost.",
+                    db.memories.len(),
+                    backup_file_path.display()
+                );
+                Ok(Some(db))
+            }
+            _ => {
+                log::warn!(
+                    "There is no usable backup in {}, so the store starts out empty.",
+                    backup_file_path.display()
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    /// Load the chunk rows of `db` from the chunks file.
+    ///
+    /// Chunks only improve recall, so if the file doesn't match the data file they are dropped
+    /// with a warning instead of failing, and the next save forgets them.
+    fn load_chunks(&self, db: &mut MemoryDB) -> Result<()> {
+        let dimension = db.embeddings.ncols();
+        let rows = db.chunk_parents.len();
+        let contents = match std::fs::read(self.chunks_file_path()) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => vec![],
+            Err(err) => return Err(err.into()),
+        };
+        let row_bytes = db.quantization.row_bytes(dimension);
+        let len = rows * row_bytes;
+        let parents_valid = db
+            .chunk_parents
+            .iter()
+            .all(|&parent| parent < db.memories.len());
+        if contents.len() < len || !parents_valid {
+            log::warn!(
+                "Dropping the chunks of {}, which don't match its memories.",
+                self.data_file_path.display()
+            );
+            db.chunk_parents.clear();
+            db.chunks = Array2::zeros((0, dimension));
+            db.chunk_norms = Array1::zeros(0);
+            db.chunks_edited = true;
+            db.chunks_rewritten = true;
+            return Ok(());
+        }
+        let values = Self::decode_rows(&contents[..len], row_bytes, db.quantization);
+        db.chunks = Array2::from_shape_vec((rows, dimension), values)?;
+        db.chunk_norms = scoring::norms(db.chunks.view());
+        Ok(())
+    }
+
+    /// Deserialize the data file at `data_file_path`, or `None` if it is missing or empty.
+    ///
+    /// `T` only needs to declare the fields it cares about. Data files written in a newer
+    /// format than this version of mem understands are rejected before `T` is deserialized.
+    fn read_data_file<T: DeserializeOwned>(data_file_path: &Path) -> Result<Option<T>> {
+        #[derive(Deserialize)]
+        struct StoredVersion {
+            #[serde(default)]
+            version: u32,
+        }
+        let contents = match std::fs::read(data_file_path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        if contents.is_empty() {
+            return Ok(None);
+        }
+        let contents = if contents.starts_with(&Self::ZSTD_MAGIC) {
+            // reported like invalid JSON, so that a corrupt data file is recovered either way
+            zstd::decode_all(contents.as_slice()).map_err(|err| {
+                <serde_json::Error as serde::de::Error>::custom(format!(
+                    "Invalid zstd data: {}",
+                    err
+                ))
+            })?
+        } else {
+            contents
+        };
+        // a value followed by garbage, e.g. left behind by another program, is still readable
+        let stored: StoredVersion = Self::parse_first(&contents)?.0;
+        if stored.version > Self::FORMAT_VERSION {
+            return Err(anyhow::anyhow!(
+                "The store was written by a newer version of mem (format version {}, but this \
+                 version only supports up to {}). Please upgrade mem.",
+                stored.version,
+                Self::FORMAT_VERSION
+            ));
+        }
+        let (value, end) = Self::parse_first(&contents)?;
+        let trailing = contents[end..]
+            .iter()
+            .filter(|byte| !byte.is_ascii_whitespace())
+            .count();
+        if trailing > 0 {
+            log::warn!(
+                "Ignoring {} bytes of garbage at the end of {}.",
+                contents.len() - end,
+                data_file_path.display()
+            );
+        }
+        Ok(Some(value))
+    }
+
+    /// Parse the JSON value at the start of `contents`, returning it and where it ends.
+    fn parse_first<T: DeserializeOwned>(contents: &[u8]) -> serde_json::Result<(T, usize)> {
+        let mut values = serde_json::Deserializer::from_slice(contents).into_iter::<T>();
+        let value = match values.next() {
+            Some(value) => value?,
+            // only whitespace, for which this fails with the usual error
+            None => serde_json::from_slice(contents)?,
+        };
+        Ok((value, values.byte_offset()))
+    }
+
+    /// Load the first `rows` embeddings of the given dimension from the embeddings file.
+    ///
+    /// Any rows past `rows` were left behind by an interrupted save and are ignored.
+    fn load_embeddings(
+        &self,
+        rows: usize,
+        dimension: usize,
+        quantization: Quantization,
+    ) -> Result<EmbeddingMatrix> {
+        if rows == 0 {
+            return Ok(Array2::zeros((0, dimension)));
+        }
+        let row_bytes = quantization.row_bytes(dimension);
+        let embeddings_file_path = self.embeddings_file_path();
+        let embeddings_file = File::open(&embeddings_file_path).context(format!(
+            "Failed to open embeddings file {}",
+            embeddings_file_path.display()
+        ))?;
+        let len = embeddings_file.metadata()?.len() as usize;
+        if len < rows * row_bytes {
+            return Err(anyhow::anyhow!(
+                "Embeddings file is missing rows. Expected: {}, Got: {}. Run `mem doctor --fix` \
+                 to drop the memories without an embedding.",
+                rows,
+                len / row_bytes
+            ));
+        }
+        // Safety: the embeddings file is only modified by mem while it holds the store's lock,
+        // and the map is dropped before this function returns.
+        let mmap = unsafe { Mmap::map(&embeddings_file)? };
+        let values = Self::decode_rows(&mmap[..rows * row_bytes], row_bytes, quantization);
+        Ok(Array2::from_shape_vec((rows, dimension), values)?)
+    }
+
+    /// Decode rows of `row_bytes` bytes each, as encoded with `quantization`, into their values.
+    fn decode_rows(bytes: &[u8], row_bytes: usize, quantization: Quantization) -> Vec<f32> {
+        let mut values = vec![];
+        for row in bytes.chunks_exact(row_bytes) {
+            quantization.decode(row, &mut values);
+        }
+        values
+    }
+
+    /// Load the first `rows` norms from the norms file, or `None` if it doesn't have that many.
+    fn load_norms(&self, rows: usize) -> Result<Option<Array1<f32>>> {
+        if rows == 0 {
+            return Ok(Some(Array1::zeros(0)));
+        }
+        let contents = match std::fs::read(self.norms_file_path()) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let len = rows * std::mem::size_of::<f32>();
+        if contents.len() < len {
+            return Ok(None);
+        }
+        Ok(Some(
+            contents[..len]
+                .chunks_exact(std::mem::size_of::<f32>())
+                .map(|bytes| f32::from_le_bytes(bytes.try_into().expect("chunks are 4 bytes")))
+                .collect(),
+        ))
+    }
+
+    /// Save the `MemoryDB` to the data file.
+    ///
+    /// The database is written to a temporary file which is then renamed over the data file, so
+    /// a crash mid-write never leaves a half-written store behind. The embeddings and their
+    /// norms are saved first so that the data file never refers to rows that don't exist.
+    fn save_db(&mut self, db: &mut MemoryDB) -> Result<()> {
+        if self.in_memory {
+            return Ok(());
+        }
+        let started = Instant::now();
+        let store_dir_path = self
+            .data_file_path
+            .parent()
+            .expect("the data file is in a directory");
+        std::fs::create_dir_all(store_dir_path).context(format!(
+            "Failed to create data directory. Make sure you have write permissions to {}",
+            store_dir_path.display()
+        ))?;
+        if self.backup_count > 0 {
+            self.back_up().context("Failed to back up the store.")?;
+            self.prune_backups()
+                .context("Failed to remove old backups.")?;
+        }
+        if db.model_revision.is_none() && !db.memories.is_empty() {
+            // stores record the revision the first time it is known
+            db.model_revision = self.embedder.model_revision();
+        }
+        let append_only = db.persisted_rows.is_some();
+        if !append_only {
+            // the index can only grow, so it is rebuilt the next time it is needed
+            Self::remove_file(&self.index_file_path()).context("Failed to remove index file.")?;
+        }
+        let chunks_append_only = append_only && !db.chunks_rewritten;
+        if !chunks_append_only {
+            Self::remove_file(&self.chunk_index_file_path())
+                .context("Failed to remove chunk index file.")?;
+        }
+        let text_append_only = append_only && !db.memories_edited;
+        if !text_append_only {
+            Self::remove_file(&self.bm25_file_path())
+                .context("Failed to remove BM25 index file.")?;
+        }
+        match self.backend {
+            Backend::Json => self.save_files(db)?,
+            Backend::Sqlite => {
+                sqlite::write(&Self::sqlite_file_path(&self.data_file_path), db)
+                    .context("Failed to save database to SQLite.")?;
+                db.persisted_rows = Some(db.memories.len());
+                db.chunks_edited = false;
+            }
+        }
+        db.memories_edited = false;
+        db.chunks_rewritten = false;
+        log::debug!(
+            "Saved {} memories to {} in {:?}",
+            db.memories.len(),
+            self.data_file_path.display(),
+            started.elapsed()
+        );
+        if append_only && self.index_file_path().exists() {
+            self.index(db).context("Failed to update index.")?;
+        }
+        if chunks_append_only && self.chunk_index_file_path().exists() {
+            self.chunk_index(db)
+                .context("Failed to update chunk index.")?;
+        }
+        if text_append_only && self.bm25_file_path().exists() {
+            self.bm25_index(db)
+                .context("Failed to update BM25 index.")?;
+        }
+        Ok(())
+    }
+
+    /// Save the `MemoryDB` to the data file and the binary files next to it.
+    fn save_files(&self, db: &mut MemoryDB) -> Result<()> {
+        self.save_norms(db)
+            .context("Failed to save norms to file.")?;
+        self.save_embeddings(db)
+            .context("Failed to save embeddings to file.")?;
+        if db.chunks_edited {
+            self.save_chunks(db)
+                .context("Failed to save chunks to file.")?;
         }
+        let tmp_file_path = Self::tmp_file_path(&self.data_file_path);
+        let mut writer = BufWriter::new(File::create(&tmp_file_path)?);
+        let writer = if self.compress {
+            let mut encoder = zstd::Encoder::new(writer, Self::ZSTD_LEVEL)?;
+            serde_json::to_writer(&mut encoder, &*db)?;
+            encoder.finish()?
+        } else {
+            serde_json::to_writer(&mut writer, &*db)?;
+            writer
+        };
+        writer.into_inner()?.sync_all()?;
+        std::fs::rename(&tmp_file_path, &self.data_file_path)?;
+        // the backup is what a corrupt data file is recovered from
+        std::fs::copy(
+            &self.data_file_path,
+            Self::backup_file_path(&self.data_file_path),
+        )
+        .context("Failed to back up the data file.")?;
+        Ok(())
     }
-}
 
-pub struct ScoredMemory {
-    pub value: String,
-    pub description: String,
-    pub score: f32,
-}
+    /// Copy the files of the store as they are into a new backup named after the current time.
+    fn back_up(&self) -> Result<()> {
+        if Self::stored_backend(&self.data_file_path).is_none() {
+            return Ok(());
+        }
+        let id = Utc::now().format("%Y%m%dT%H%M%S%.3fZ").to_string();
+        let backup_dir_path = self.backups_dir_path().join(&id);
+        std::fs::create_dir_all(&backup_dir_path).context(format!(
+            "Failed to create backup directory. Make sure you have write permissions to {}",
+            backup_dir_path.display()
+        ))?;
+        for path in self.backed_up_file_paths() {
+            if path.exists() {
+                let file_name = path.file_name().expect("store files have a name");
+                std::fs::copy(&path, backup_dir_path.join(file_name))?;
+            }
+        }
+        log::debug!("Backed up the store to {}", backup_dir_path.display());
+        Ok(())
+    }
 
-type Embedding = Array1<f32>;
-type EmbeddingMatrix = Array2<f32>;
+    /// Remove the oldest backups beyond `backup_count`.
+    fn prune_backups(&self) -> Result<()> {
+        let backups = self.backups()?;
+        for backup in backups.iter().skip(self.backup_count) {
+            std::fs::remove_dir_all(self.backups_dir_path().join(&backup.id))?;
+        }
+        Ok(())
+    }
 
-/// A memory database.
-///
-/// TODO: optimize all this for fast insertion and retrieval.
-#[derive(Serialize, Deserialize)]
-struct MemoryDB {
-    memories: Vec<Memory>,
-    embeddings: EmbeddingMatrix,
-}
+    /// List the rolling backups of the store, newest first.
+    pub fn backups(&self) -> Result<Vec<Backup>> {
+        #[derive(Deserialize)]
+        struct StoredMemories {
+            memories: Vec<IgnoredAny>,
+        }
+        if self.in_memory {
+            return Ok(vec![]);
+        }
+        let entries = match std::fs::read_dir(self.backups_dir_path()) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err).context("Failed to read backups directory."),
+        };
+        let mut backups = vec![];
+        for entry in entries {
+            let entry = entry.context("Failed to read backups directory.")?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            let data_file_name = self
+                .data_file_path
+                .file_name()
+                .expect("store files have a name");
+            let data_file_path = entry.path().join(data_file_name);
+            let memories = match Self::stored_backend(&data_file_path) {
+                Some(Backend::Sqlite) => {
+                    sqlite::count(&Self::sqlite_file_path(&data_file_path), None).ok()
+                }
+                _ => Self::read_data_file::<StoredMemories>(&data_file_path)
+                    .unwrap_or(None)
+                    .map(|stored| stored.memories.len()),
+            };
+            backups.push(Backup {
+                id: entry.file_name().to_string_lossy().into_owned(),
+                memories,
+            });
+        }
+        // ids are timestamps, which sort in the order they were taken
+        backups.sort_by(|a, b| b.id.cmp(&a.id));
+        Ok(backups)
+    }
 
-/// A store for memories.
-///
-/// Memories have a description and a value. The description is used for semantic retrieval.
-pub struct MemoryStore {
-    data_file: File,
-    openai: openai::Client,
-}
+    /// Replace the store with the backup `id`, as listed by `backups`.
+    ///
+    /// The store is backed up first, so that restoring can be undone too. Returns the number of
+    /// memories in the restored store.
+    pub fn restore_backup(&mut self, id: &str) -> Result<usize> {
+        if !self.backups()?.iter().any(|backup| backup.id == id) {
+            return Err(anyhow::anyhow!(
+                "There is no backup {:?}. Run `mem backups list` to see the backups.",
+                id
+            ));
+        }
+        self.forget_db()?;
+        // pruning could otherwise remove the backup being restored
+        self.back_up().context("Failed to back up the store.")?;
+        let backup_dir_path = self.backups_dir_path().join(id);
+        for path in self.backed_up_file_paths() {
+            let backup_path =
+                backup_dir_path.join(path.file_name().expect("store files have a name"));
+            if backup_path.exists() {
+                std::fs::copy(&backup_path, &path)
+                    .with_context(|| format!("Failed to restore {}.", path.display()))?;
+            } else {
+                Self::remove_file(&path)?;
+            }
+        }
+        Self::remove_file(&self.index_file_path()).context("Failed to remove index file.")?;
+        Self::remove_file(&self.chunk_index_file_path())
+            .context("Failed to remove chunk index file.")?;
+        Self::remove_file(&self.bm25_file_path()).context("Failed to remove BM25 index file.")?;
+        if self.backup_count > 0 {
+            self.prune_backups()
+                .context("Failed to remove old backups.")?;
+        }
+        // the backup may have been taken before the store changed backends
+        self.backend = Self::stored_backend(&self.data_file_path).unwrap_or(self.backend);
+        self.len()
+    }
 
-impl MemoryStore {
-    const EMBEDDING_SIZE: usize = 1536;
-    const EMBEDDING_MODEL: &'static str = "text-embedding-ada-002";
+    /// Get the BM25 index over the text of the memories of `db`.
+    ///
+    /// Like the approximate index, a missing index is built and memories appended since it was
+    /// saved are added to it, after which it is saved to the BM25 index file.
+    fn bm25_index(&self, db: &MemoryDB) -> Result<Bm25Index> {
+        if self.in_memory {
+            let mut index = Bm25Index::default();
+            for memory in &db.memories {
+                index.push(&memory.keyword_text());
+            }
+            return Ok(index);
+        }
+        let bm25_file_path = self.bm25_file_path();
+        let mut index = match Bm25Index::read(&bm25_file_path)? {
+            // an index with more documents than the store is left over from an interrupted save
+            Some(index) if index.len() <= db.memories.len() => index,
+            _ => Bm25Index::default(),
+        };
+        if index.len() == db.memories.len() {
+            return Ok(index);
+        }
+        while index.len() < db.memories.len() {
+            index.push(&db.memories[index.len()].keyword_text());
+        }
+        let tmp_file_path = Self::tmp_file_path(&bm25_file_path);
+        index.write(&tmp_file_path)?;
+        std::fs::rename(&tmp_file_path, &bm25_file_path)?;
+        Ok(index)
+    }
 
-    /// Insert a new memory into the store.
-    pub fn insert(&mut self, memory: &str, description: &str) -> Result<()> {
-        let mut db = self
-            .load_db()
-            .context("Failed to load database from file.")?;
-        let embedding = self
-            .embed(description)
-            .context("Failed to get memory description embedding.")?;
-        db.embeddings
-            .push_row(ArrayView::from(&embedding))
-            .expect("dimension mismatch");
-        db.memories.push(Memory {
-            value: memory.to_string(),
-            description: description.to_string(),
-        });
-        self.save_db(&db)
-            .context("Failed to save database to file.")?;
-        Ok(())
+    /// Get the approximate index over the embeddings of `db`, or `None` if the store is too
+    /// small to need one or its metric can't use one.
+    ///
+    /// A missing index is built and rows appended since it was saved are added to it, after
+    /// which it is saved to the index file.
+    fn index(&self, db: &MemoryDB) -> Result<Option<HnswIndex>> {
+        // the index ranks by dot product, which only matches the metric for unit-length rows
+        if !db.metric.normalizes() || db.memories.len() < Self::INDEX_MIN_MEMORIES {
+            return Ok(None);
+        }
+        Self::updated_index(&self.index_file_path(), db.embeddings.view()).map(Some)
     }
 
-    /// Get a memory from the store.
-    pub fn get(&self, description: &str) -> Result<Option<ScoredMemory>> {
-        let db = self
-            .load_db()
-            .context("Failed to load database from file.")?;
-        if db.memories.is_empty() {
+    /// Get the approximate index over the chunk rows of `db` like `index`, or `None` if there
+    /// are too few chunks to need one.
+    fn chunk_index(&self, db: &MemoryDB) -> Result<Option<HnswIndex>> {
+        if !db.metric.normalizes() || db.chunk_parents.len() < Self::INDEX_MIN_MEMORIES {
             return Ok(None);
         }
-        let query_embedding: Embedding = self
-            .embed(description)
-            .context("Failed to get query embedding.")?
-            .into();
-        let dot_products = db.embeddings.dot(&query_embedding);
-        // get the index of the max dot product
-        let max_index = dot_products
-            .iter()
-            .enumerate()
-            .max_by(|(_, a), (_, b)| {
-                a.partial_cmp(b)
-                    .expect("there are no NaN values in the dot product array")
-            })
-            .map(|(i, _)| i)
-            .unwrap();
-        let memory = db.memories[max_index].clone();
-        let score = dot_products[max_index];
-        Ok(Some(memory.into_scored(score)))
+        Self::updated_index(&self.chunk_index_file_path(), db.chunks.view()).map(Some)
     }
 
-    /// List memories from the store.
-    pub fn list(&self, description: &str, count: usize) -> Result<Vec<ScoredMemory>> {
-        let db = self
-            .load_db()
-            .context("Failed to load database from file.")?;
-        if db.memories.is_empty() {
-            return Ok(vec![]);
+    /// Read the index at `index_file_path`, add the rows of `embeddings` appended since it was
+    /// saved, and save it again if any were.
+    fn updated_index(index_file_path: &Path, embeddings: ArrayView2<f32>) -> Result<HnswIndex> {
+        let mut index = match HnswIndex::read(index_file_path)? {
+            // an index with more rows than the store is left over from an interrupted save
+            Some(index) if index.len() <= embeddings.nrows() => index,
+            _ => HnswIndex::new(Self::INDEX_M, Self::INDEX_EF_CONSTRUCTION),
+        };
+        if index.len() == embeddings.nrows() {
+            return Ok(index);
         }
-        let query_embedding: Embedding = self
-            .embed(description)
-            .context("Failed to get query embedding.")?
-            .into();
-        let dot_products = db.embeddings.dot(&query_embedding);
-        let mut score_index_pairs: Vec<_> = dot_products
-            .into_iter()
-            .enumerate()
-            .map(|(i, score)| (score, i))
-            .collect();
-        score_index_pairs.sort_by(|(a, _), (b, _)| b.partial_cmp(a).unwrap());
-        score_index_pairs.truncate(count);
-        let scored_memories = score_index_pairs
-            .into_iter()
-            .map(|(score, i)| db.memories[i].clone().into_scored(score))
-            .collect();
-        Ok(scored_memories)
+        while index.len() < embeddings.nrows() {
+            index.insert(embeddings);
+        }
+        let tmp_file_path = Self::tmp_file_path(index_file_path);
+        index.write(&tmp_file_path)?;
+        std::fs::rename(&tmp_file_path, index_file_path)?;
+        Ok(index)
     }
 
-    /// Embed text using the OpenAI API.
-    fn embed(&self, text: &str) -> Result<Vec<f32>> {
-        let req = EmbeddingRequest::new(Self::EMBEDDING_MODEL.to_owned(), text.to_owned());
-        let mut res = self
-            .openai
-            .embedding(req)
-            .context("Failed to get embedding from OpenAI API.")?;
-        if res.data[0].embedding.len() != Self::EMBEDDING_SIZE {
-            return Err(anyhow::anyhow!(
-                "Embedding size is not correct. Expected: {}, Got: {}",
-                Self::EMBEDDING_SIZE,
-                res.data[0].embedding.len()
-            ));
+    /// Remove the file at `path` if it exists.
+    fn remove_file(path: &Path) -> Result<()> {
+        match std::fs::remove_file(path) {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err.into()),
         }
-        Ok(res.data.remove(0).embedding)
     }
-}
 
-/// Loading and saving the `MemoryDB` to a file.
-///
-/// TODO: this is all very inefficient. Need a more efficient way to store data / cache / etc.
-/// TODO: But we're just prototyping for now.
-impl MemoryStore {
-    /// Load the `MemoryDB` from the given `File`.
-    fn load_db(&self) -> Result<MemoryDB> {
-        // if file is empty create a new db else load the db from the file
-        let db = if self.data_file.metadata()?.len() == 0 {
-            MemoryDB {
-                memories: vec![],
-                embeddings: Array2::zeros((0, Self::EMBEDDING_SIZE)),
-            }
+    /// Save the embeddings of the `MemoryDB` to the embeddings file.
+    ///
+    /// If rows have only been appended since the database was loaded, just the new rows are
+    /// written. Otherwise the whole file is rewritten atomically.
+    fn save_embeddings(&self, db: &mut MemoryDB) -> Result<()> {
+        let embeddings_file_path = self.embeddings_file_path();
+        let row_bytes = db.quantization.row_bytes(db.embeddings.ncols());
+        if let Some(persisted_rows) = db.persisted_rows {
+            let embeddings_file = OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(&embeddings_file_path)?;
+            // drop any partial rows left behind by an interrupted save
+            embeddings_file.set_len((persisted_rows * row_bytes) as u64)?;
+            let mut writer = BufWriter::new(embeddings_file);
+            Self::write_rows(
+                &mut writer,
+                db.embeddings.slice(s![persisted_rows.., ..]),
+                db.quantization,
+            )?;
+            writer.into_inner()?.sync_all()?;
+        } else {
+            let tmp_file_path = Self::tmp_file_path(&embeddings_file_path);
+            let mut writer = BufWriter::new(File::create(&tmp_file_path)?);
+            Self::write_rows(&mut writer, db.embeddings.view(), db.quantization)?;
+            writer.into_inner()?.sync_all()?;
+            std::fs::rename(&tmp_file_path, &embeddings_file_path)?;
+        }
+        db.persisted_rows = Some(db.embeddings.nrows());
+        Ok(())
+    }
+
+    /// Atomically replace the chunks file with the chunk rows of the `MemoryDB`, or remove it if
+    /// there are none.
+    fn save_chunks(&self, db: &mut MemoryDB) -> Result<()> {
+        let chunks_file_path = self.chunks_file_path();
+        if db.chunk_parents.is_empty() {
+            Self::remove_file(&chunks_file_path)?;
         } else {
-            serde_json::from_reader(&self.data_file)?
+            let tmp_file_path = Self::tmp_file_path(&chunks_file_path);
+            let mut writer = BufWriter::new(File::create(&tmp_file_path)?);
+            Self::write_rows(&mut writer, db.chunks.view(), db.quantization)?;
+            writer.into_inner()?.sync_all()?;
+            std::fs::rename(&tmp_file_path, &chunks_file_path)?;
+        }
+        db.chunks_edited = false;
+        Ok(())
+    }
+
+    /// Save the norms of the `MemoryDB` to the norms file.
+    ///
+    /// Like the embeddings, only the norms of appended rows are written when possible. A norms
+    /// file that is missing rows it should have is rewritten instead.
+    fn save_norms(&self, db: &MemoryDB) -> Result<()> {
+        let norms_file_path = self.norms_file_path();
+        let value_bytes = std::mem::size_of::<f32>();
+        let saved_rows = match std::fs::metadata(&norms_file_path) {
+            Ok(metadata) => metadata.len() as usize / value_bytes,
+            Err(err) if err.kind() == ErrorKind::NotFound => 0,
+            Err(err) => return Err(err.into()),
         };
-        Ok(db)
+        match db.persisted_rows {
+            Some(persisted_rows) if saved_rows >= persisted_rows => {
+                let norms_file = OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .open(&norms_file_path)?;
+                // drop any norms left behind by an interrupted save
+                norms_file.set_len((persisted_rows * value_bytes) as u64)?;
+                let mut writer = BufWriter::new(norms_file);
+                Self::write_floats(&mut writer, db.norms.slice(s![persisted_rows..]))?;
+                writer.into_inner()?.sync_all()?;
+                Ok(())
+            }
+            _ => self.write_norms(db.norms.view()),
+        }
+    }
+
+    /// Atomically replace the norms file with `norms`.
+    fn write_norms(&self, norms: ArrayView1<f32>) -> Result<()> {
+        let norms_file_path = self.norms_file_path();
+        let tmp_file_path = Self::tmp_file_path(&norms_file_path);
+        let mut writer = BufWriter::new(File::create(&tmp_file_path)?);
+        Self::write_floats(&mut writer, norms)?;
+        writer.into_inner()?.sync_all()?;
+        std::fs::rename(&tmp_file_path, &norms_file_path)?;
+        Ok(())
+    }
+
+    /// Write every row of `rows` encoded with `quantization`.
+    fn write_rows(
+        writer: &mut impl Write,
+        rows: ArrayView2<f32>,
+        quantization: Quantization,
+    ) -> Result<()> {
+        let mut bytes = Vec::with_capacity(quantization.row_bytes(rows.ncols()));
+        for row in rows.rows() {
+            bytes.clear();
+            quantization.encode(row, &mut bytes);
+            writer.write_all(&bytes)?;
+        }
+        Ok(())
     }
 
-    /// Save the `MemoryDB` to the given `File`.
-    fn save_db(&mut self, db: &MemoryDB) -> Result<()> {
-        // delete the file contents
-        self.data_file.set_len(0)?;
-        self.data_file.seek(SeekFrom::Start(0))?;
-        serde_json::to_writer(&self.data_file, db)?;
+    /// Write every value of `values` as a little-endian `f32`, in logical order.
+    fn write_floats<D: Dimension>(
+        writer: &mut impl Write,
+        values: ArrayView<f32, D>,
+    ) -> Result<()> {
+        for value in values.iter() {
+            writer.write_all(&value.to_le_bytes())?;
+        }
         Ok(())
     }
+
+    /// The path of the file holding the embeddings of the data file.
+    fn embeddings_file_path(&self) -> PathBuf {
+        self.data_file_path.with_extension("embeddings")
+    }
+
+    /// The path of the file holding the length of every embedding row.
+    fn norms_file_path(&self) -> PathBuf {
+        self.data_file_path.with_extension("norms")
+    }
+
+    /// The path of the file holding the embeddings of the chunks of long values.
+    fn chunks_file_path(&self) -> PathBuf {
+        self.data_file_path.with_extension("chunks")
+    }
+
+    /// The path of the database of a store with the SQLite backend, which replaces the data
+    /// file and the embeddings, norms and chunks files.
+    fn sqlite_file_path(data_file_path: &Path) -> PathBuf {
+        data_file_path.with_extension("sqlite")
+    }
+
+    fn index_file_path(&self) -> PathBuf {
+        self.data_file_path.with_extension("hnsw")
+    }
+
+    fn chunk_index_file_path(&self) -> PathBuf {
+        self.data_file_path.with_extension("chunks.hnsw")
+    }
+
+    fn bm25_file_path(&self) -> PathBuf {
+        self.data_file_path.with_extension("bm25")
+    }
+
+    /// The path of the copy of the last saved data file, which a corrupt data file is recovered
+    /// from.
+    fn backup_file_path(data_file_path: &Path) -> PathBuf {
+        let mut backup_file_path = data_file_path.as_os_str().to_owned();
+        backup_file_path.push(".bak");
+        PathBuf::from(backup_file_path)
+    }
+
+    /// The directory holding the rolling backups of the store, see `StoreOptions::backup_count`.
+    fn backups_dir_path(&self) -> PathBuf {
+        self.data_file_path.with_file_name(Self::BACKUPS_DIR_NAME)
+    }
+
+    /// The files that make up the store, which are copied by rolling backups. The indexes are
+    /// left out since they are rebuilt when needed.
+    fn backed_up_file_paths(&self) -> [PathBuf; 5] {
+        [
+            self.data_file_path.clone(),
+            self.embeddings_file_path(),
+            self.norms_file_path(),
+            self.chunks_file_path(),
+            Self::sqlite_file_path(&self.data_file_path),
+        ]
+    }
+
+    /// The sibling of `path` that is written before being renamed over `path`.
+    fn tmp_file_path(path: &Path) -> PathBuf {
+        let mut tmp_file_path = path.as_os_str().to_owned();
+        tmp_file_path.push(".tmp");
+        PathBuf::from(tmp_file_path)
+    }
 }
 
 impl MemoryStore {
     const MEM_DATA_DIR_ENV_VAR: &str = "MEM_DATA_DIR";
+    const OPENAI_API_KEY_ENV_VAR: &str = "OPENAI_API_KEY";
+    const OPENAI_API_KEYS_ENV_VAR: &str = "OPENAI_API_KEYS";
     const DEFAULT_DATA_DIR_NAME: &str = ".mem";
     const DATA_FILE_NAME: &str = "store.json";
+    const STORES_DIR_NAME: &str = "stores";
+    const DEFAULT_STORE_NAME: &str = "default";
     const OPENAI_API_KEY_FILE_NAME: &str = "openai_api_key.txt";
+    const QUERY_CACHE_FILE_NAME: &str = "query_cache.json";
+    const CONFIG_FILE_NAME: &str = "config.toml";
+    const BACKUPS_DIR_NAME: &str = "backups";
+    #[cfg(feature = "keyring")]
+    const KEYRING_SERVICE: &str = "mem";
+    #[cfg(feature = "keyring")]
+    const KEYRING_USER: &str = "openai_api_key";
 
     /// Load the `MemoryStore` from the default data file.
     ///
-    /// The default data directory is set by the `MEM_DATA_DIR` environment variable.
-    /// If this variable is not set, the default data directory is `~/.mem`.
-    pub fn load() -> Result<MemoryStore> {
-        let data_file = Self::default_data_file().context("Failed to load default data file.")?;
-        let openai =
-            Self::default_openai_client().context("Failed to load default OpenAI client.")?;
-        Ok(Self::with_options(data_file, openai))
+    /// The data directory is `options.data_dir` if set, otherwise the `MEM_DATA_DIR`
+    /// environment variable. If neither is set, the default data directory is `~/.mem`.
+    /// Each named store in it has its own memories and embeddings.
+    ///
+    /// The store is locked until it is dropped; `options.lock_mode` decides what happens if
+    /// another process already holds the lock.
+    pub fn load(options: &StoreOptions) -> Result<MemoryStore> {
+        let data_dir = options.data_dir.as_deref();
+        // the store's directory is only created once something is saved in it
+        let data_file_path = Self::find_data_file_path(data_dir, options.store_name.as_deref())
+            .context("Failed to load default data file.")?;
+        let backend = Self::stored_backend(&data_file_path)
+            .or(options.backend)
+            .unwrap_or_default();
+        let model = match &options.model {
+            Some(model) => model.clone(),
+            None => Self::stored_model(&data_file_path, backend)
+                .context("Failed to read the model of the store.")?
+                .unwrap_or_else(|| options.provider.default_model().to_owned()),
+        };
+        if let Some(base_url) = &options.openai_base_url {
+            openai::Client::validate_base_url(base_url)?;
+        }
+        let embedder: Box<dyn Embedder> = match options.provider {
+            // a missing API key only matters once something needs to be embedded
+            Provider::OpenAi => {
+                match Self::default_openai_client(data_dir, &options.openai_api_keys)
+                    .context("Failed to load default OpenAI client.")
+                {
+                    Ok(openai) => Box::new(OpenAiEmbedder::new(
+                        openai
+                            .with_base_url(
+                                options
+                                    .openai_base_url
+                                    .as_deref()
+                                    .unwrap_or(openai::Client::DEFAULT_BASE_URL),
+                            )
+                            .with_retry_policy(options.retry_policy)
+                            .with_timeout(
+                                options.timeout.unwrap_or(openai::Client::DEFAULT_TIMEOUT),
+                            ),
+                        model,
+                    )),
+                    Err(err) => Box::new(UnavailableEmbedder::new(model, format!("{:#}", err))),
+                }
+            }
+            Provider::Ollama => {
+                let base_url = options
+                    .ollama_url
+                    .clone()
+                    .unwrap_or_else(|| OllamaEmbedder::DEFAULT_BASE_URL.to_owned());
+                Box::new(OllamaEmbedder::new(base_url, model))
+            }
+        };
+        let mut store = Self::with_options(data_file_path, embedder);
+        store.exact_search = options.exact;
+        store.offline = options.offline;
+        store.metric = options.metric;
+        store.quantization = options.quantization;
+        store.model_revision = options.model_revision.clone();
+        store.allow_model_drift = options.allow_model_drift;
+        store.backup_count = options.backup_count;
+        store.compress = options.compress;
+        store.backend = backend;
+        store.requested_backend = options.backend;
+        if let Some(alpha) = options.hybrid_alpha {
+            if !(0.0..=1.0).contains(&alpha) {
+                return Err(anyhow::anyhow!(
+                    "Alpha must be between 0 and 1. Got: {}",
+                    alpha
+                ));
+            }
+            store.hybrid_alpha = Some(alpha);
+        }
+        store.set_recency_half_life(options.recency_half_life)?;
+        if options.query_cache_size > 0 {
+            store.query_cache = Some(Self::default_query_cache(
+                data_dir,
+                options.query_cache_size,
+            ));
+        }
+        store.lock(options.lock_mode)?;
+        Ok(store)
+    }
+
+    /// Create a new `MemoryStore` backed by the data file at the given path.
+    ///
+    /// The data file does not need to exist yet. This is useful for testing.
+    pub fn with_options(data_file_path: PathBuf, embedder: Box<dyn Embedder>) -> MemoryStore {
+        MemoryStore {
+            data_file_path,
+            in_memory: false,
+            db: RefCell::new(None),
+            dirty: false,
+            buffer_writes: false,
+            embedder,
+            query_cache: None,
+            tokens_embedded: Cell::new(None),
+            exact_search: false,
+            offline: false,
+            metric: None,
+            quantization: None,
+            hybrid_alpha: None,
+            recency_half_life: None,
+            model_revision: None,
+            allow_model_drift: false,
+            backup_count: 0,
+            compress: false,
+            backend: Backend::Json,
+            requested_backend: None,
+            _lock_file: None,
+        }
     }
 
-    /// Create a new `MemoryStore` from the given `File`.
+    /// Create an empty `MemoryStore` that is only kept in memory and never touches the
+    /// filesystem, e.g. for tests or short-lived programs.
     ///
-    /// This is useful for testing.
-    pub fn with_options(data_file: File, openai: openai::Client) -> MemoryStore {
-        MemoryStore { data_file, openai }
+    /// Memories can be inserted and queried as usual, and are lost when the store is dropped.
+    /// Every query scores every memory, since the approximate index lives in a file. Operations
+    /// that work on the files directly, like `reembed`, `migrate`, `doctor` and
+    /// `restore_backup`, fail.
+    pub fn in_memory(embedder: Box<dyn Embedder>) -> MemoryStore {
+        let mut store = Self::with_options(PathBuf::new(), embedder);
+        store.in_memory = true;
+        store.exact_search = true;
+        *store.db.get_mut() = Some(store.empty_db());
+        store
     }
 
-    /// Get a handle to the default data file.
-    pub fn default_data_file() -> Result<File> {
-        let data_dir_path = Self::resolve_data_dir_path();
-        let data_file_path = data_dir_path.join(Self::DATA_FILE_NAME);
-        std::fs::create_dir_all(&data_dir_path).context(format!(
+    /// Read the model that the store at `data_file_path` was built with, if it exists.
+    fn stored_model(data_file_path: &Path, backend: Backend) -> Result<Option<String>> {
+        #[derive(Deserialize)]
+        struct StoredModel {
+            #[serde(default = "legacy_model")]
+            model: String,
+        }
+        if backend == Backend::Sqlite {
+            let Some(settings) = sqlite::settings(&Self::sqlite_file_path(data_file_path))? else {
+                return Ok(None);
+            };
+            let stored: StoredModel = serde_json::from_str(&settings)
+                .context("Failed to parse the settings of the store.")?;
+            return Ok(Some(stored.model));
+        }
+        let stored: Option<StoredModel> = match Self::read_data_file(data_file_path) {
+            Ok(stored) => stored,
+            // a corrupt data file is recovered from its backup when the store is loaded
+            Err(err) if err.is::<serde_json::Error>() => {
+                Self::read_data_file(&Self::backup_file_path(data_file_path)).unwrap_or(None)
+            }
+            Err(err) => return Err(err),
+        };
+        Ok(stored.map(|stored| stored.model))
+    }
+
+    /// The file holding the store with the data file at `data_file_path`, which is the data file
+    /// itself unless the store uses SQLite, or `None` if the store has no files yet.
+    pub fn stored_file_path(data_file_path: &Path) -> Option<PathBuf> {
+        match Self::stored_backend(data_file_path)? {
+            Backend::Json => Some(data_file_path.to_owned()),
+            Backend::Sqlite => Some(Self::sqlite_file_path(data_file_path)),
+        }
+    }
+
+    /// The backend of the store at `data_file_path`, or `None` if it has no files yet.
+    fn stored_backend(data_file_path: &Path) -> Option<Backend> {
+        if Self::sqlite_file_path(data_file_path).exists() {
+            Some(Backend::Sqlite)
+        } else if data_file_path.exists() {
+            Some(Backend::Json)
+        } else {
+            None
+        }
+    }
+
+    /// Acquire an advisory lock on the store that is held until the store is dropped.
+    ///
+    /// The lock is taken on a separate `.lock` file since the data file itself is replaced on
+    /// every save, see `lock_file_path`.
+    pub fn lock(&mut self, lock_mode: LockMode) -> Result<()> {
+        self._lock_file = Some(Self::lock_data_file(&self.data_file_path, lock_mode)?);
+        Ok(())
+    }
+
+    /// The lock file of the store with the data file at `data_file_path`.
+    ///
+    /// Named stores are locked with `stores/<name>.lock` next to their directory rather than a
+    /// file in it, so that the lock stays put while the store is renamed. Other data files are
+    /// locked with a sibling `.lock` file.
+    fn lock_file_path(data_file_path: &Path) -> PathBuf {
+        let store_dir_path = data_file_path.parent().filter(|store_dir_path| {
+            store_dir_path
+                .parent()
+                .and_then(Path::file_name)
+                .is_some_and(|name| name == Self::STORES_DIR_NAME)
+        });
+        let mut lock_file_path = match store_dir_path {
+            Some(store_dir_path) => store_dir_path.as_os_str().to_owned(),
+            None => data_file_path.as_os_str().to_owned(),
+        };
+        lock_file_path.push(".lock");
+        PathBuf::from(lock_file_path)
+    }
+
+    /// Lock the store with the data file at `data_file_path`, until the returned file is closed.
+    fn lock_data_file(data_file_path: &Path, lock_mode: LockMode) -> Result<File> {
+        let lock_file_path = Self::lock_file_path(data_file_path);
+        let lock_dir_path = lock_file_path
+            .parent()
+            .expect("the lock file is in a directory");
+        std::fs::create_dir_all(lock_dir_path).context(format!(
             "Failed to create data directory. Make sure you have write permissions to {}",
-            data_dir_path.display()
+            lock_dir_path.display()
         ))?;
-        let data_file = OpenOptions::new()
+        let lock_file = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .open(&data_file_path)
+            .truncate(false)
+            .open(&lock_file_path)
             .context(format!(
-                "Failed to open data file. Make sure you have write permissions to {}",
-                data_file_path.display()
+                "Failed to open lock file. Make sure you have write permissions to {}",
+                lock_file_path.display()
             ))?;
-        Ok(data_file)
+        match lock_mode {
+            LockMode::Blocking => lock_file
+                .lock_exclusive()
+                .context("Failed to lock the store.")?,
+            LockMode::NonBlocking => lock_file
+                .try_lock_exclusive()
+                .context("The store is locked by another mem process.")?,
+        }
+        Ok(lock_file)
+    }
+
+    /// Get the path to the data file of the named store, creating its directory if needed.
+    ///
+    /// Stores live in `stores/<name>` in the data directory. The default store keeps using the
+    /// data file at the root of the data directory if one was created before stores had names.
+    pub fn default_data_file_path(
+        data_dir: Option<&Path>,
+        store_name: Option<&str>,
+    ) -> Result<PathBuf> {
+        let data_file_path = Self::find_data_file_path(data_dir, store_name)?;
+        let store_dir_path = data_file_path
+            .parent()
+            .expect("the data file is in a directory");
+        std::fs::create_dir_all(store_dir_path).context(format!(
+            "Failed to create data directory. Make sure you have write permissions to {}",
+            store_dir_path.display()
+        ))?;
+        Ok(data_file_path)
+    }
+
+    /// Get the path to the data file of the named store like `default_data_file_path`, but
+    /// without creating anything, e.g. to look for a store in a data directory that may be
+    /// read-only.
+    pub fn find_data_file_path(
+        data_dir: Option<&Path>,
+        store_name: Option<&str>,
+    ) -> Result<PathBuf> {
+        let data_dir_path = Self::resolve_data_dir_path(data_dir);
+        let store_name = store_name.unwrap_or(Self::DEFAULT_STORE_NAME);
+        Self::validate_store_name(store_name)?;
+        let store_dir_path = data_dir_path.join(Self::STORES_DIR_NAME).join(store_name);
+        let legacy_data_file_path = data_dir_path.join(Self::DATA_FILE_NAME);
+        if store_name == Self::DEFAULT_STORE_NAME
+            && legacy_data_file_path.exists()
+            && !store_dir_path.join(Self::DATA_FILE_NAME).exists()
+        {
+            return Ok(legacy_data_file_path);
+        }
+        Ok(store_dir_path.join(Self::DATA_FILE_NAME))
+    }
+
+    /// List the names of the stores in the data directory, sorted alphabetically.
+    pub fn list_stores(data_dir: Option<&Path>) -> Result<Vec<String>> {
+        let data_dir_path = Self::resolve_data_dir_path(data_dir);
+        let stores_dir_path = data_dir_path.join(Self::STORES_DIR_NAME);
+        let mut names = vec![];
+        match std::fs::read_dir(&stores_dir_path) {
+            Ok(entries) => {
+                for entry in entries {
+                    let entry = entry.context("Failed to read stores directory.")?;
+                    if entry.file_type()?.is_dir() {
+                        names.push(entry.file_name().to_string_lossy().into_owned());
+                    }
+                }
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(err).context("Failed to read stores directory."),
+        }
+        if data_dir_path.join(Self::DATA_FILE_NAME).exists() {
+            names.push(Self::DEFAULT_STORE_NAME.to_owned());
+        }
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+
+    /// Rename the store `old_name` in the data directory to `new_name`.
+    ///
+    /// Fails if there is no store `old_name` or a store `new_name` already exists. Both names
+    /// are locked from before that is checked until the store is renamed, so no other process
+    /// can use either store in between; `lock_mode` decides what happens if another process
+    /// holds one of the locks.
+    pub fn rename_store(
+        data_dir: Option<&Path>,
+        old_name: &str,
+        new_name: &str,
+        lock_mode: LockMode,
+    ) -> Result<()> {
+        Self::validate_store_name(old_name)?;
+        Self::validate_store_name(new_name)?;
+        let data_dir_path = Self::resolve_data_dir_path(data_dir);
+        let stores_dir_path = data_dir_path.join(Self::STORES_DIR_NAME);
+        let old_dir_path = stores_dir_path.join(old_name);
+        let new_dir_path = stores_dir_path.join(new_name);
+        let legacy_data_file_path = data_dir_path.join(Self::DATA_FILE_NAME);
+        let is_legacy_default = |name: &str, dir_path: &Path| {
+            name == Self::DEFAULT_STORE_NAME
+                && legacy_data_file_path.exists()
+                && !dir_path.join(Self::DATA_FILE_NAME).exists()
+        };
+        if new_name == old_name {
+            return Err(anyhow::anyhow!(
+                "A store named {:?} already exists.",
+                new_name
+            ));
+        }
+        if is_legacy_default(old_name, &old_dir_path) {
+            return Err(anyhow::anyhow!(
+                "The default store was created by an older version of mem at the root of the data \
+                 directory, and can't be renamed. Copy it into a new store with `mem --store {} \
+                 merge {}` instead.",
+                new_name,
+                data_dir_path.display()
+            ));
+        }
+        if !old_dir_path.is_dir() {
+            return Err(anyhow::anyhow!("There is no store named {:?}.", old_name));
+        }
+        // locking in name order keeps two renames of the same pair of stores from waiting on
+        // each other forever
+        let mut names = [old_name, new_name];
+        names.sort();
+        let _lock_files = names
+            .iter()
+            .map(|name| {
+                Self::lock_data_file(
+                    &stores_dir_path.join(name).join(Self::DATA_FILE_NAME),
+                    lock_mode,
+                )
+            })
+            .collect::<Result<Vec<_>>>()?;
+        // renaming onto an empty directory would silently replace it
+        if new_dir_path.exists() || is_legacy_default(new_name, &new_dir_path) {
+            return Err(anyhow::anyhow!(
+                "A store named {:?} already exists.",
+                new_name
+            ));
+        }
+        if !old_dir_path.is_dir() {
+            return Err(anyhow::anyhow!("There is no store named {:?}.", old_name));
+        }
+        std::fs::rename(&old_dir_path, &new_dir_path).context(format!(
+            "Failed to rename the store. Make sure you have write permissions to {}",
+            stores_dir_path.display()
+        ))?;
+        Ok(())
+    }
+
+    /// Store names become directory names, so they can't contain path separators.
+    fn validate_store_name(store_name: &str) -> Result<()> {
+        if store_name.is_empty()
+            || store_name == "."
+            || store_name == ".."
+            || store_name.contains(['/', '\\'])
+        {
+            return Err(anyhow::anyhow!(
+                "Invalid store name: {:?}. Store names can't be empty or contain path separators.",
+                store_name
+            ));
+        }
+        Ok(())
+    }
+
+    /// Get the query cache stored in the `query_cache.json` file in the data directory.
+    pub fn default_query_cache(data_dir: Option<&Path>, capacity: usize) -> QueryCache {
+        QueryCache::new(
+            Self::resolve_data_dir_path(data_dir).join(Self::QUERY_CACHE_FILE_NAME),
+            capacity,
+        )
+    }
+
+    /// Get the path to the `config.toml` file in the data directory.
+    pub fn default_config_file_path(data_dir: Option<&Path>) -> PathBuf {
+        Self::resolve_data_dir_path(data_dir).join(Self::CONFIG_FILE_NAME)
     }
 
     /// Get the default OpenAI client.
     ///
-    /// Uses the OpenAI API key stored in the `openai_api_key.txt` file in the data directory.
-    pub fn default_openai_client() -> Result<openai::Client> {
+    /// Uses the comma-separated keys of the `OPENAI_API_KEYS` environment variable if it is set,
+    /// then the `OPENAI_API_KEY` environment variable, then `configured_api_keys` from the
+    /// config file, then the OS keychain when built with the `keyring` feature, and finally the
+    /// OpenAI API key stored in the `openai_api_key.txt` file in the data directory. Several
+    /// keys are rotated through on rate limits.
+    pub fn default_openai_client(
+        data_dir: Option<&Path>,
+        configured_api_keys: &[String],
+    ) -> Result<openai::Client> {
+        let api_keys: Vec<String> = env::var(Self::OPENAI_API_KEYS_ENV_VAR)
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|key| !key.is_empty())
+            .map(str::to_owned)
+            .collect();
+        if !api_keys.is_empty() {
+            return Ok(openai::Client::with_api_keys(api_keys));
+        }
+        if let Some(openai_api_key) = env::var(Self::OPENAI_API_KEY_ENV_VAR)
+            .ok()
+            .filter(|key| !key.trim().is_empty())
+        {
+            return Ok(openai::Client::new(openai_api_key));
+        }
+        if !configured_api_keys.is_empty() {
+            return Ok(openai::Client::with_api_keys(configured_api_keys.to_vec()));
+        }
+        #[cfg(feature = "keyring")]
+        match Self::keyring_entry()?.get_password() {
+            Ok(openai_api_key) => return Ok(openai::Client::new(openai_api_key)),
+            Err(keyring::Error::NoEntry) => {}
+            Err(err) => {
+                return Err(err).context("Failed to read OpenAI API key from the OS keychain.")
+            }
+        }
         let openai_api_key_file_path =
-            Self::resolve_data_dir_path().join(Self::OPENAI_API_KEY_FILE_NAME);
-        let openai_api_key = std::fs::read_to_string(openai_api_key_file_path)
-            .context("Failed to read OpenAI API key file. Did you set the OpenAI API key?")?;
+            Self::resolve_data_dir_path(data_dir).join(Self::OPENAI_API_KEY_FILE_NAME);
+        let openai_api_key = std::fs::read_to_string(openai_api_key_file_path).context(format!(
+            "Failed to read OpenAI API key file. Did you set the OpenAI API key with `mem set-key` or the {} environment variable?",
+            Self::OPENAI_API_KEY_ENV_VAR
+        ))?;
         Ok(openai::Client::new(openai_api_key))
     }
 
     /// Store the OpenAI API key in the `openai_api_key.txt` file in the data directory.
-    pub fn store_openai_api_key(openai_api_key: &str) -> Result<()> {
-        let data_dir_path = Self::resolve_data_dir_path();
+    ///
+    /// On Unix the file is only readable and writable by its owner. Returns the path to the file.
+    pub fn store_openai_api_key(data_dir: Option<&Path>, openai_api_key: &str) -> Result<PathBuf> {
+        let data_dir_path = Self::resolve_data_dir_path(data_dir);
         let openai_api_key_file_path = data_dir_path.join(Self::OPENAI_API_KEY_FILE_NAME);
         std::fs::create_dir_all(&data_dir_path).context(format!(
             "Failed to create data directory. Make sure you have write permissions to {}",
             data_dir_path.display()
         ))?;
-        std::fs::write(&openai_api_key_file_path, openai_api_key).context(format!(
-            "Failed to write OpenAI API key file. Make sure you have write permissions to {}",
-            openai_api_key_file_path.display()
-        ))?;
+        Self::write_private_file(&openai_api_key_file_path, openai_api_key.as_bytes()).context(
+            format!(
+                "Failed to write OpenAI API key file. Make sure you have write permissions to {}",
+                openai_api_key_file_path.display()
+            ),
+        )?;
+        Ok(openai_api_key_file_path)
+    }
+
+    /// Store the OpenAI API key in the OS keychain instead of a file.
+    ///
+    /// Only available when built with the `keyring` feature.
+    pub fn store_openai_api_key_in_keyring(openai_api_key: &str) -> Result<()> {
+        #[cfg(feature = "keyring")]
+        {
+            Self::keyring_entry()?
+                .set_password(openai_api_key)
+                .context("Failed to store OpenAI API key in the OS keychain.")
+        }
+        #[cfg(not(feature = "keyring"))]
+        {
+            let _ = openai_api_key;
+            Err(anyhow::anyhow!(
+                "mem was built without keychain support. Rebuild it with `--features keyring`."
+            ))
+        }
+    }
+
+    #[cfg(feature = "keyring")]
+    fn keyring_entry() -> Result<keyring::Entry> {
+        keyring::Entry::new(Self::KEYRING_SERVICE, Self::KEYRING_USER)
+            .context("Failed to access the OS keychain.")
+    }
+
+    /// Write `contents` to a file that, on Unix, only its owner can read or write.
+    fn write_private_file(path: &Path, contents: &[u8]) -> Result<()> {
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+            options.mode(0o600);
+            // the mode only applies to new files, so tighten an existing key file too
+            if path.exists() {
+                std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+            }
+        }
+        let mut file = options.open(path)?;
+        file.write_all(contents)?;
         Ok(())
     }
 
-    fn resolve_data_dir_path() -> PathBuf {
-        if let Ok(data_dir) = env::var(Self::MEM_DATA_DIR_ENV_VAR) {
+    /// Resolve the data directory: `data_dir` if given, then `MEM_DATA_DIR`, then `~/.mem`.
+    pub fn resolve_data_dir_path(data_dir: Option<&Path>) -> PathBuf {
+        if let Some(data_dir) = data_dir {
+            data_dir.to_path_buf()
+        } else if let Ok(data_dir) = env::var(Self::MEM_DATA_DIR_ENV_VAR) {
             PathBuf::from(data_dir)
         } else {
             let home_dir = dirs::home_dir().expect(&format!(
@@ -260,3 +4130,10 @@ impl MemoryStore {
         }
     }
 }
+
+impl Drop for MemoryStore {
+    /// Save changes that were never flushed. See `MemoryStore::buffer_writes`.
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
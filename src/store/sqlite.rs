@@ -0,0 +1,290 @@
+use super::{Filter, Memory, MemoryDB, MemoryStore};
+use crate::quantize::Quantization;
+use crate::scoring;
+use anyhow::{Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use ndarray::{Array2, ArrayView2};
+use rusqlite::types::ToSql;
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use std::path::Path;
+
+/// The tables of a SQLite store.
+///
+/// The settings of the store, like its model and metric, are kept as the JSON of a `MemoryDB`
+/// without memories, so that they evolve like those of JSON stores. Memories are kept as JSON
+/// too, next to the columns that `count` filters on, and embeddings are rows encoded with the
+/// store's quantization.
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS settings (
+        id INTEGER PRIMARY KEY CHECK (id = 0),
+        json TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS memories (
+        position INTEGER PRIMARY KEY,
+        json TEXT NOT NULL,
+        created_at TEXT,
+        archived INTEGER NOT NULL,
+        length INTEGER NOT NULL,
+        embedding BLOB NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS memories_by_created_at ON memories (created_at);
+    CREATE TABLE IF NOT EXISTS tags (
+        tag TEXT NOT NULL,
+        position INTEGER NOT NULL,
+        PRIMARY KEY (tag, position)
+    );
+    CREATE TABLE IF NOT EXISTS chunks (
+        position INTEGER PRIMARY KEY,
+        parent INTEGER NOT NULL,
+        embedding BLOB NOT NULL
+    );
+";
+
+/// Open the SQLite store at `path`, creating its tables if needed.
+fn open(path: &Path) -> Result<Connection> {
+    let connection = Connection::open(path).context(format!(
+        "Failed to open SQLite database. Make sure you have write permissions to {}",
+        path.display()
+    ))?;
+    connection.execute_batch(SCHEMA)?;
+    Ok(connection)
+}
+
+/// Creation times are stored with a fixed number of digits, so that comparing them as text
+/// compares them as times.
+fn timestamp(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Nanos, true)
+}
+
+/// Read the settings of the SQLite store at `path` as JSON, or `None` if it has none yet.
+pub(super) fn settings(path: &Path) -> Result<Option<String>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    Ok(open(path)?
+        .query_row("SELECT json FROM settings WHERE id = 0", [], |row| {
+            row.get(0)
+        })
+        .optional()?)
+}
+
+/// Read the whole database of the SQLite store at `path`, or `None` if it has none yet.
+///
+/// Every memory is loaded since searches rank all of them, and their filters are applied
+/// afterwards like for JSON stores; only `count` filters in SQL.
+pub(super) fn read(path: &Path) -> Result<Option<MemoryDB>> {
+    let Some(settings) = settings(path)? else {
+        return Ok(None);
+    };
+    let mut db: MemoryDB =
+        serde_json::from_str(&settings).context("Failed to parse the settings of the store.")?;
+    if db.version > MemoryStore::FORMAT_VERSION {
+        return Err(anyhow::anyhow!(
+            "The store was written by a newer version of mem (format version {}, but this \
+             version only supports up to {}). Please upgrade mem.",
+            db.version,
+            MemoryStore::FORMAT_VERSION
+        ));
+    }
+    let dimension = db.dimension.unwrap_or(0);
+    let connection = open(path)?;
+    let mut statement =
+        connection.prepare("SELECT json, embedding FROM memories ORDER BY position")?;
+    let mut rows = statement.query([])?;
+    let mut values = vec![];
+    while let Some(row) = rows.next()? {
+        let memory: Memory = serde_json::from_str(&row.get::<_, String>(0)?)
+            .with_context(|| format!("Failed to parse memory {}.", db.memories.len()))?;
+        decode_row(
+            db.quantization,
+            dimension,
+            &row.get::<_, Vec<u8>>(1)?,
+            &mut values,
+        )?;
+        db.memories.push(memory);
+    }
+    db.embeddings = Array2::from_shape_vec((db.memories.len(), dimension), values)?;
+    db.norms = scoring::norms(db.embeddings.view());
+    db.persisted_rows = Some(db.memories.len());
+    let mut statement =
+        connection.prepare("SELECT parent, embedding FROM chunks ORDER BY position")?;
+    let mut rows = statement.query([])?;
+    let mut values = vec![];
+    db.chunk_parents.clear();
+    while let Some(row) = rows.next()? {
+        db.chunk_parents.push(row.get::<_, i64>(0)? as usize);
+        decode_row(
+            db.quantization,
+            dimension,
+            &row.get::<_, Vec<u8>>(1)?,
+            &mut values,
+        )?;
+    }
+    db.chunks = Array2::from_shape_vec((db.chunk_parents.len(), dimension), values)?;
+    db.chunk_norms = scoring::norms(db.chunks.view());
+    Ok(Some(db))
+}
+
+/// Append the values of an embedding row read from the database to `values`.
+fn decode_row(
+    quantization: Quantization,
+    dimension: usize,
+    bytes: &[u8],
+    values: &mut Vec<f32>,
+) -> Result<()> {
+    if bytes.len() != quantization.row_bytes(dimension) {
+        return Err(anyhow::anyhow!(
+            "An embedding has {} bytes, but {} embeddings of dimension {} have {}.",
+            bytes.len(),
+            quantization.name(),
+            dimension,
+            quantization.row_bytes(dimension)
+        ));
+    }
+    quantization.decode(bytes, values);
+    Ok(())
+}
+
+/// Save `db` to the SQLite store at `path` in a single transaction.
+///
+/// Like the embeddings file, only the memories appended since the database was loaded are
+/// written, unless memories were changed or removed, in which case every memory is rewritten.
+/// Chunks are only rewritten if they changed.
+pub(super) fn write(path: &Path, db: &MemoryDB) -> Result<()> {
+    let mut connection = open(path)?;
+    let transaction = connection.transaction()?;
+    let mut settings = serde_json::to_value(db)?;
+    if let Some(settings) = settings.as_object_mut() {
+        settings.insert("memories".to_owned(), serde_json::Value::Array(vec![]));
+        settings.remove("chunk_parents");
+    }
+    transaction.execute(
+        "INSERT OR REPLACE INTO settings (id, json) VALUES (0, ?1)",
+        params![settings.to_string()],
+    )?;
+    let start = match db.persisted_rows.filter(|_| !db.memories_edited) {
+        Some(persisted_rows) => persisted_rows,
+        None => {
+            transaction.execute_batch("DELETE FROM memories; DELETE FROM tags;")?;
+            0
+        }
+    };
+    write_memories(&transaction, db, start)?;
+    if db.chunks_edited {
+        transaction.execute("DELETE FROM chunks", [])?;
+        write_chunks(
+            &transaction,
+            db.chunks.view(),
+            &db.chunk_parents,
+            db.quantization,
+        )?;
+    }
+    transaction.commit()?;
+    Ok(())
+}
+
+/// Insert the memories of `db` from position `start` on, along with their embeddings and tags.
+fn write_memories(transaction: &Transaction, db: &MemoryDB, start: usize) -> Result<()> {
+    let mut insert_memory = transaction.prepare(
+        "INSERT OR REPLACE INTO memories (position, json, created_at, archived, length, embedding) \
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+    )?;
+    let mut insert_tag =
+        transaction.prepare("INSERT OR IGNORE INTO tags (tag, position) VALUES (?1, ?2)")?;
+    for (position, (memory, row)) in db
+        .memories
+        .iter()
+        .zip(db.embeddings.rows())
+        .enumerate()
+        .skip(start)
+    {
+        let mut embedding = vec![];
+        db.quantization.encode(row, &mut embedding);
+        insert_memory.execute(params![
+            position as i64,
+            serde_json::to_string(memory)?,
+            memory.created_at.map(timestamp),
+            memory.archived,
+            memory.value.chars().count() as i64,
+            embedding,
+        ])?;
+        for tag in &memory.tags {
+            insert_tag.execute(params![tag, position as i64])?;
+        }
+    }
+    Ok(())
+}
+
+/// Insert every chunk row along with the position of the memory it belongs to.
+fn write_chunks(
+    transaction: &Transaction,
+    chunks: ArrayView2<f32>,
+    parents: &[usize],
+    quantization: Quantization,
+) -> Result<()> {
+    let mut insert_chunk = transaction
+        .prepare("INSERT INTO chunks (position, parent, embedding) VALUES (?1, ?2, ?3)")?;
+    for (position, (row, parent)) in chunks.rows().into_iter().zip(parents).enumerate() {
+        let mut embedding = vec![];
+        quantization.encode(row, &mut embedding);
+        insert_chunk.execute(params![position as i64, *parent as i64, embedding])?;
+    }
+    Ok(())
+}
+
+/// Read every memory of the SQLite store at `path`, without their embeddings.
+pub(super) fn memories(path: &Path) -> Result<Vec<Memory>> {
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let connection = open(path)?;
+    let mut statement = connection.prepare("SELECT json FROM memories ORDER BY position")?;
+    let mut rows = statement.query([])?;
+    let mut memories = vec![];
+    while let Some(row) = rows.next()? {
+        let memory: Memory = serde_json::from_str(&row.get::<_, String>(0)?)
+            .with_context(|| format!("Failed to parse memory {}.", memories.len()))?;
+        memories.push(memory);
+    }
+    Ok(memories)
+}
+
+/// Count the memories of the SQLite store at `path` that match `filter`, or every memory
+/// without one, in SQL instead of loading them.
+pub(super) fn count(path: &Path, filter: Option<&Filter>) -> Result<usize> {
+    if !path.exists() {
+        return Ok(0);
+    }
+    let mut sql = "SELECT COUNT(*) FROM memories".to_owned();
+    let mut values: Vec<Box<dyn ToSql>> = vec![];
+    if let Some(filter) = filter {
+        sql.push_str(" WHERE archived = ?");
+        values.push(Box::new(filter.archived));
+        for tag in &filter.tags {
+            sql.push_str(" AND position IN (SELECT position FROM tags WHERE tag = ?)");
+            values.push(Box::new(tag.clone()));
+        }
+        if let Some(since) = filter.since {
+            sql.push_str(" AND created_at >= ?");
+            values.push(Box::new(timestamp(since)));
+        }
+        if let Some(min_length) = filter.min_length {
+            sql.push_str(" AND length >= ?");
+            values.push(Box::new(min_length as i64));
+        }
+        if let Some(max_length) = filter.max_length {
+            sql.push_str(" AND length <= ?");
+            values.push(Box::new(max_length as i64));
+        }
+        for position in &filter.exclude {
+            sql.push_str(" AND position != ?");
+            values.push(Box::new(*position as i64));
+        }
+    }
+    let count: i64 = open(path)?.query_row(
+        &sql,
+        rusqlite::params_from_iter(values.iter().map(|value| value.as_ref())),
+        |row| row.get(0),
+    )?;
+    Ok(count as usize)
+}
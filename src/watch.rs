@@ -0,0 +1,78 @@
+use crate::output::Output;
+use anyhow::{Context, Result};
+use mem::store::{Filter, MemoryStore, SortBy, StoreOptions};
+use notify::{Event, RecursiveMode, Watcher};
+use std::io::{stdout, IsTerminal};
+use std::sync::mpsc;
+use std::time::Duration;
+
+/// How long the store must go unchanged before the results are printed again, so that a burst
+/// of inserts only prints them once.
+const DEBOUNCE: Duration = Duration::from_millis(250);
+
+/// Print the memories `list` returns, and print them again whenever the store changes, until
+/// the process is stopped.
+///
+/// The store is loaded again for every refresh and only locked while it is queried, so that
+/// other processes can keep changing it.
+pub fn watch(
+    options: &StoreOptions,
+    output: &Output,
+    description: &str,
+    count: usize,
+    filter: &Filter,
+) -> Result<()> {
+    let data_file_path = MemoryStore::default_data_file_path(
+        options.data_dir.as_deref(),
+        options.store_name.as_deref(),
+    )
+    .context("Failed to load default data file.")?;
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender).context("Failed to watch the store.")?;
+    // the data file is replaced on every save, so its directory is watched instead
+    let dir = data_file_path
+        .parent()
+        .expect("the data file is in a directory");
+    watcher
+        .watch(dir, RecursiveMode::NonRecursive)
+        .context(format!("Failed to watch {}.", dir.display()))?;
+    // SQLite stores keep everything in a database next to where the data file would be
+    let sqlite_file_path = data_file_path.with_extension("sqlite");
+    let store_file_names = [data_file_path.file_name(), sqlite_file_path.file_name()];
+    let changes_store = |event: notify::Result<Event>| match event {
+        Ok(event) => {
+            !event.kind.is_access()
+                && event
+                    .paths
+                    .iter()
+                    .any(|path| store_file_names.contains(&path.file_name()))
+        }
+        Err(err) => {
+            log::warn!("Failed to watch the store: {}", err);
+            false
+        }
+    };
+    loop {
+        refresh(options, output, description, count, filter)?;
+        while !changes_store(receiver.recv().context("Stopped watching the store.")?) {}
+        while receiver.recv_timeout(DEBOUNCE).is_ok() {}
+        log::debug!("The store changed, listing memories again");
+    }
+}
+
+/// List the memories again, replacing the previous results if they are on a terminal.
+fn refresh(
+    options: &StoreOptions,
+    output: &Output,
+    description: &str,
+    count: usize,
+    filter: &Filter,
+) -> Result<()> {
+    let memories =
+        MemoryStore::load(options)?.list(description, count, None, filter, SortBy::Score)?;
+    if stdout().is_terminal() {
+        // clear the screen and move the cursor to the top left
+        print!("\x1b[2J\x1b[H");
+    }
+    output.memories(&memories)
+}
@@ -0,0 +1,199 @@
+//! End-to-end tests of the OpenAI embedder and reranker against a local mock of the OpenAI API.
+
+use httpmock::prelude::*;
+use mem::embedder::{Embedder, OpenAiEmbedder};
+use mem::openai::{Client, RetryPolicy};
+use mem::rerank::{Candidate, LlmReranker, Reranker};
+use mem::store::MemoryStore;
+use serde_json::json;
+use std::time::Duration;
+
+const MODEL: &str = "text-embedding-3-small";
+
+/// An embedder that sends its requests to `server`, retrying quickly.
+fn embedder(server: &MockServer, max_retries: u32) -> OpenAiEmbedder {
+    let client = Client::new("sk-test".to_owned())
+        .with_base_url(server.url("/v1"))
+        .with_retry_policy(RetryPolicy {
+            max_retries,
+            base_delay: Duration::from_millis(1),
+        });
+    OpenAiEmbedder::new(client, MODEL.to_owned())
+}
+
+#[test]
+fn sends_the_model_inputs_and_key() {
+    let server = MockServer::start();
+    let mock = server.mock(|when, then| {
+        when.method(POST)
+            .path("/v1/embeddings")
+            .header("authorization", "Bearer sk-test")
+            .json_body(json!({ "model": MODEL, "input": ["list pods"] }));
+        then.status(200)
+            .json_body(json!({ "data": [{ "embedding": [0.6, 0.8], "index": 0 }] }));
+    });
+    let embedding = embedder(&server, 0).embed("list pods").unwrap();
+    mock.assert();
+    assert_eq!(embedding, [0.6, 0.8]);
+}
+
+#[test]
+fn batches_are_returned_in_input_order() {
+    let server = MockServer::start();
+    let mock = server.mock(|when, then| {
+        when.method(POST).path("/v1/embeddings");
+        then.status(200).json_body(json!({
+            "data": [
+                { "embedding": [0.0, 0.0, 1.0], "index": 2 },
+                { "embedding": [1.0, 0.0, 0.0], "index": 0 },
+                { "embedding": [0.0, 1.0, 0.0], "index": 1 },
+            ]
+        }));
+    });
+    let texts = ["a", "b", "c"].map(str::to_owned);
+    let embeddings = embedder(&server, 0).embed_batch(&texts).unwrap();
+    mock.assert_hits(1);
+    assert_eq!(
+        embeddings,
+        [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]]
+    );
+}
+
+#[test]
+fn wrong_number_of_embeddings_is_an_error() {
+    let server = MockServer::start();
+    server.mock(|when, then| {
+        when.method(POST).path("/v1/embeddings");
+        then.status(200)
+            .json_body(json!({ "data": [{ "embedding": [1.0], "index": 0 }] }));
+    });
+    let texts = ["a", "b"].map(str::to_owned);
+    let err = embedder(&server, 0).embed_batch(&texts).unwrap_err();
+    assert!(err.to_string().contains("Wrong number of embeddings"));
+}
+
+#[test]
+fn an_empty_data_array_is_an_error() {
+    let server = MockServer::start();
+    server.mock(|when, then| {
+        when.method(POST).path("/v1/embeddings");
+        then.status(200).json_body(json!({ "data": [] }));
+    });
+    let err = embedder(&server, 0).embed("list pods").unwrap_err();
+    assert!(err.to_string().contains("returned no embeddings"));
+}
+
+#[test]
+fn server_errors_are_retried() {
+    let server = MockServer::start();
+    let mock = server.mock(|when, then| {
+        when.method(POST).path("/v1/embeddings");
+        then.status(503).body("overloaded");
+    });
+    let err = embedder(&server, 2).embed("list pods").unwrap_err();
+    // the first attempt and two retries
+    mock.assert_hits(3);
+    assert!(format!("{err:#}").contains("status 503"));
+}
+
+#[test]
+fn client_errors_are_not_retried() {
+    let server = MockServer::start();
+    let mock = server.mock(|when, then| {
+        when.method(POST).path("/v1/embeddings");
+        then.status(401).body("invalid api key");
+    });
+    let err = embedder(&server, 2).embed("list pods").unwrap_err();
+    mock.assert_hits(1);
+    assert!(format!("{err:#}").contains("invalid api key"));
+}
+
+#[test]
+fn rate_limited_keys_are_rotated() {
+    let server = MockServer::start();
+    let limited = server.mock(|when, then| {
+        when.method(POST)
+            .path("/v1/embeddings")
+            .header("authorization", "Bearer sk-first");
+        then.status(429).body("rate limited");
+    });
+    let ok = server.mock(|when, then| {
+        when.method(POST)
+            .path("/v1/embeddings")
+            .header("authorization", "Bearer sk-second");
+        then.status(200)
+            .json_body(json!({ "data": [{ "embedding": [0.6, 0.8], "index": 0 }] }));
+    });
+    let client = Client::with_api_keys(vec!["sk-first".to_owned(), "sk-second".to_owned()])
+        .with_base_url(server.url("/v1"))
+        .with_retry_policy(RetryPolicy {
+            max_retries: 0,
+            base_delay: Duration::from_millis(1),
+        });
+    let embedder = OpenAiEmbedder::new(client, MODEL.to_owned());
+    assert_eq!(embedder.embed("list pods").unwrap(), [0.6, 0.8]);
+    assert_eq!(embedder.embed("list pods").unwrap(), [0.6, 0.8]);
+    // the second request starts with the key that worked
+    limited.assert_hits(1);
+    ok.assert_hits(2);
+}
+
+#[test]
+fn store_rejects_embeddings_of_another_dimension() {
+    let server = MockServer::start();
+    server.mock(|when, then| {
+        when.method(POST)
+            .path("/v1/embeddings")
+            .body_contains("list pods");
+        then.status(200)
+            .json_body(json!({ "data": [{ "embedding": [0.6, 0.8], "index": 0 }] }));
+    });
+    server.mock(|when, then| {
+        when.method(POST)
+            .path("/v1/embeddings")
+            .body_contains("show the last commit");
+        then.status(200)
+            .json_body(json!({ "data": [{ "embedding": [1.0, 0.0, 0.0], "index": 0 }] }));
+    });
+    let dir = tempfile::tempdir().unwrap();
+    let mut store = MemoryStore::with_options(
+        dir.path().join("store.json"),
+        Box::new(embedder(&server, 0)),
+    );
+    store
+        .insert("kubectl get pods", "list pods", &[], false)
+        .unwrap();
+    let err = store
+        .insert("git show HEAD", "show the last commit", &[], false)
+        .unwrap_err();
+    assert!(format!("{err:#}").contains("whose embeddings have dimension 2"));
+    assert_eq!(store.len().unwrap(), 1);
+}
+
+#[test]
+fn the_llm_reranker_reads_ratings_out_of_the_reply() {
+    let server = MockServer::start();
+    let mock = server.mock(|when, then| {
+        when.method(POST).path("/v1/chat/completions");
+        then.status(200).json_body(json!({
+            "choices": [{
+                "message": { "role": "assistant", "content": "Sure! {\"scores\": [2, 10]}" },
+            }],
+        }));
+    });
+    let client = Client::new("sk-test".to_owned()).with_base_url(server.url("/v1"));
+    let reranker = LlmReranker::new(client, LlmReranker::DEFAULT_MODEL.to_owned());
+    let candidates = [
+        Candidate {
+            value: "git show HEAD",
+            description: "show the last commit",
+        },
+        Candidate {
+            value: "docker ps",
+            description: "list running containers",
+        },
+    ];
+    let scores = reranker.rerank("containers", &candidates).unwrap();
+    mock.assert();
+    assert_eq!(scores, [0.2, 1.0]);
+}
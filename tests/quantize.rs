@@ -0,0 +1,82 @@
+//! Checks that quantized embeddings rank memories like full precision ones.
+
+use mem::quantize::Quantization;
+use ndarray::{Array1, Array2, ArrayView1};
+
+const DIMENSION: usize = 256;
+const ROWS: usize = 500;
+const QUERIES: usize = 50;
+const TOP_K: usize = 10;
+
+/// Unit vectors with pseudo-random values from a fixed seed, so that every run is the same.
+fn unit_vectors(rows: usize, seed: &mut u64) -> Array2<f32> {
+    let mut vectors = Array2::from_shape_fn((rows, DIMENSION), |_| {
+        // xorshift64
+        *seed ^= *seed << 13;
+        *seed ^= *seed >> 7;
+        *seed ^= *seed << 17;
+        (*seed >> 40) as f32 / (1u64 << 24) as f32 - 0.5
+    });
+    for mut row in vectors.rows_mut() {
+        let norm = row.dot(&row).sqrt();
+        row /= norm;
+    }
+    vectors
+}
+
+/// The indices of the `TOP_K` rows with the highest cosine similarity to `query`, best first.
+fn top_k(rows: &Array2<f32>, query: ArrayView1<f32>) -> Vec<usize> {
+    let scores: Vec<f32> = rows
+        .rows()
+        .into_iter()
+        .map(|row| row.dot(&query) / row.dot(&row).sqrt())
+        .collect();
+    let mut indices: Vec<usize> = (0..scores.len()).collect();
+    indices.sort_by(|&a, &b| scores[b].total_cmp(&scores[a]));
+    indices.truncate(TOP_K);
+    indices
+}
+
+/// The fraction of the full precision top results that the quantized rows also rank in their top
+/// results, averaged over every query.
+fn ranking_agreement(quantization: Quantization) -> f32 {
+    let mut seed = 0x9e37_79b9_7f4a_7c15;
+    let rows = unit_vectors(ROWS, &mut seed);
+    let queries = unit_vectors(QUERIES, &mut seed);
+    let mut quantized = rows.clone();
+    for mut row in quantized.rows_mut() {
+        let values = quantization.round_trip(row.view());
+        row.assign(&Array1::from(values));
+    }
+    let mut agreement = 0.0;
+    for query in queries.rows() {
+        let expected = top_k(&rows, query);
+        let actual = top_k(&quantized, query);
+        let shared = expected.iter().filter(|i| actual.contains(i)).count();
+        agreement += shared as f32 / TOP_K as f32;
+    }
+    agreement / QUERIES as f32
+}
+
+#[test]
+fn f32_keeps_every_value() {
+    let row = Array1::from(vec![0.25, -1.5, 3.0e-7]);
+    assert_eq!(Quantization::F32.round_trip(row.view()), row.to_vec());
+}
+
+#[test]
+fn rows_take_the_expected_space() {
+    assert_eq!(Quantization::F32.row_bytes(1536), 6144);
+    assert_eq!(Quantization::F16.row_bytes(1536), 3072);
+    assert_eq!(Quantization::Int8.row_bytes(1536), 1540);
+}
+
+#[test]
+fn f16_ranks_like_full_precision() {
+    assert!(ranking_agreement(Quantization::F16) >= 0.99);
+}
+
+#[test]
+fn int8_ranks_like_full_precision() {
+    assert!(ranking_agreement(Quantization::Int8) >= 0.9);
+}
@@ -0,0 +1,596 @@
+//! Ranking tests against a store whose embeddings come from a fake embedder, so that they run
+//! offline and every score is known in advance.
+
+use anyhow::Result;
+use chrono::Utc;
+use mem::embedder::Embedder;
+use mem::rerank::{Candidate, Reranker};
+use mem::store::{Embedding, Filter, InsertOutcome, LockMode, Memory, MemoryStore, SortBy};
+use std::collections::HashMap;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// Embeds a fixed set of texts as fixed vectors, and fails on anything else.
+struct FakeEmbedder {
+    embeddings: HashMap<&'static str, Vec<f32>>,
+}
+
+impl FakeEmbedder {
+    fn new() -> FakeEmbedder {
+        let embeddings = HashMap::from([
+            ("show the last commit", vec![1.0, 0.0, 0.0]),
+            ("list running containers", vec![0.0, 1.0, 0.0]),
+            ("list pods in every namespace", vec![0.0, 0.6, 0.8]),
+            ("containers", vec![0.0, 0.8, 0.6]),
+            ("git", vec![0.9, 0.1, 0.0]),
+            ("docker", vec![0.0, 1.0, 0.0]),
+            ("ps", vec![0.0, 0.0, 1.0]),
+        ]);
+        FakeEmbedder { embeddings }
+    }
+}
+
+impl Embedder for FakeEmbedder {
+    fn model(&self) -> &str {
+        "fake"
+    }
+
+    fn embed(&self, text: &str) -> Result<Vec<f32>> {
+        self.embeddings
+            .get(text)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("No fake embedding for {:?}", text))
+    }
+}
+
+/// A store in a fresh temporary directory, holding three memories.
+fn store() -> (TempDir, MemoryStore) {
+    let dir = tempfile::tempdir().unwrap();
+    let mut store = open(&dir);
+    for (value, description) in [
+        ("git show HEAD", "show the last commit"),
+        ("docker ps", "list running containers"),
+        ("kubectl get pods -A", "list pods in every namespace"),
+    ] {
+        store.insert(value, description, &[], false).unwrap();
+    }
+    (dir, store)
+}
+
+/// The store of `store()` grown with filler memories to the size that is searched with the
+/// approximate index. The fillers score at most 0.79 against "containers".
+fn large_store() -> (TempDir, MemoryStore) {
+    let (dir, mut store) = store();
+    store.buffer_writes(true);
+    for i in 0..9_997 {
+        let angle = i as f32 / 9_997.0 * 0.45 * std::f32::consts::PI;
+        store
+            .insert_with_embedding("filler", "filler", vec![angle.cos(), angle.sin(), 0.0])
+            .unwrap();
+    }
+    store.flush().unwrap();
+    (dir, store)
+}
+
+fn memory(value: &str, description: &str) -> Memory {
+    Memory {
+        id: None,
+        value: value.to_owned(),
+        description: description.to_owned(),
+        tags: vec![],
+        created_at: None,
+        embeds_value: false,
+        archived: false,
+        chunk_words: None,
+        metadata: None,
+    }
+}
+
+fn open(dir: &TempDir) -> MemoryStore {
+    MemoryStore::with_options(dir.path().join("store.json"), Box::new(FakeEmbedder::new()))
+}
+
+fn values(store: &MemoryStore, description: &str) -> Vec<String> {
+    store
+        .list(description, 10, None, &Filter::default(), SortBy::Score)
+        .unwrap()
+        .into_iter()
+        .map(|memory| memory.value)
+        .collect()
+}
+
+#[test]
+fn get_returns_the_closest_memory() {
+    let (_dir, store) = store();
+    let memory = store
+        .get("containers", None, &Filter::default())
+        .unwrap()
+        .unwrap();
+    assert_eq!(memory.value, "kubectl get pods -A");
+    assert_eq!(memory.index, 2);
+    assert!((memory.score - 0.96).abs() < 1e-5);
+}
+
+#[test]
+fn list_ranks_memories_best_first() {
+    let (_dir, store) = store();
+    assert_eq!(
+        values(&store, "containers"),
+        ["kubectl get pods -A", "docker ps", "git show HEAD"]
+    );
+    assert_eq!(
+        values(&store, "git"),
+        ["git show HEAD", "docker ps", "kubectl get pods -A"]
+    );
+}
+
+#[test]
+fn get_respects_the_threshold() {
+    let (_dir, store) = store();
+    let filter = Filter::default();
+    assert!(store
+        .get("containers", Some(0.95), &filter)
+        .unwrap()
+        .is_some());
+    assert!(store
+        .get("containers", Some(0.97), &filter)
+        .unwrap()
+        .is_none());
+}
+
+#[test]
+fn list_leaves_out_memories_below_the_threshold() {
+    let (_dir, store) = store();
+    let memories = store
+        .list(
+            "containers",
+            10,
+            Some(0.5),
+            &Filter::default(),
+            SortBy::Score,
+        )
+        .unwrap();
+    let indices: Vec<usize> = memories.iter().map(|memory| memory.index).collect();
+    assert_eq!(indices, [2, 1]);
+}
+
+#[test]
+fn length_filters_leave_out_memories_by_the_length_of_their_value() {
+    let (_dir, store) = store();
+    let filter = Filter {
+        max_length: Some(13),
+        ..Filter::default()
+    };
+    let memory = store.get("containers", None, &filter).unwrap().unwrap();
+    assert_eq!(memory.value, "docker ps");
+    let filter = Filter {
+        min_length: Some(10),
+        max_length: Some(13),
+        ..Filter::default()
+    };
+    let memories = store
+        .list("containers", 10, None, &filter, SortBy::Score)
+        .unwrap();
+    let values: Vec<&str> = memories
+        .iter()
+        .map(|memory| memory.value.as_str())
+        .collect();
+    assert_eq!(values, ["git show HEAD"]);
+}
+
+#[test]
+fn diverse_lists_trade_scores_for_memories_unlike_the_ones_picked() {
+    let (_dir, store) = store();
+    let diverse_values = |lambda| -> Vec<String> {
+        store
+            .list_diverse("containers", 2, None, &Filter::default(), lambda)
+            .unwrap()
+            .into_iter()
+            .map(|memory| memory.value)
+            .collect()
+    };
+    assert_eq!(diverse_values(1.0), ["kubectl get pods -A", "docker ps"]);
+    assert_eq!(
+        diverse_values(0.0),
+        ["kubectl get pods -A", "git show HEAD"]
+    );
+}
+
+#[test]
+fn excluded_memories_are_never_returned() {
+    let (_dir, store) = store();
+    let best = store
+        .get("containers", None, &Filter::default())
+        .unwrap()
+        .unwrap();
+    let filter = Filter {
+        exclude: vec![best.index],
+        ..Filter::default()
+    };
+    let next = store.get("containers", None, &filter).unwrap().unwrap();
+    assert_ne!(next.index, best.index);
+    let memories = store
+        .list("containers", 10, None, &filter, SortBy::Score)
+        .unwrap();
+    assert!(memories.iter().all(|memory| memory.index != best.index));
+}
+
+#[test]
+fn all_returns_every_memory_in_storage_order() {
+    let (dir, _store) = store();
+    // a fresh store reads the memories without an embedder call
+    let store = open(&dir);
+    let memories: Vec<(usize, String)> = store
+        .all()
+        .unwrap()
+        .into_iter()
+        .map(|(index, memory)| (index, memory.value))
+        .collect();
+    assert_eq!(
+        memories,
+        [
+            (0, "git show HEAD".to_owned()),
+            (1, "docker ps".to_owned()),
+            (2, "kubectl get pods -A".to_owned())
+        ]
+    );
+}
+
+#[test]
+fn rank_scores_a_given_embedding() {
+    let (_dir, store) = store();
+    let ranked = store.rank(&Embedding::from(vec![0.0, 0.0, 2.0])).unwrap();
+    let indices: Vec<usize> = ranked.iter().map(|(index, _)| *index).collect();
+    assert_eq!(indices, [2, 0, 1]);
+    assert!((ranked[0].1 - 0.8).abs() < 1e-5);
+}
+
+#[test]
+fn insert_with_embedding_skips_the_embedder() {
+    let (_dir, mut store) = store();
+    // the fake embedder has no embedding for this description
+    store
+        .insert_with_embedding("helm list", "list helm releases", vec![0.0, 0.0, 1.0])
+        .unwrap();
+    assert_eq!(values(&store, "containers")[1], "helm list");
+    assert!(store
+        .insert_with_embedding("helm ls", "list helm releases", vec![0.0, 1.0])
+        .is_err());
+    assert_eq!(store.len().unwrap(), 4);
+}
+
+#[test]
+fn an_empty_store_adopts_the_dimension_of_its_first_embedding() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut store = open(&dir);
+    let mut embedding = vec![0.0; 3072];
+    embedding[0] = 1.0;
+    store
+        .insert_with_embedding("helm list", "list helm releases", embedding.clone())
+        .unwrap();
+    drop(store);
+    let store = open(&dir);
+    assert_eq!(store.stats().unwrap().dimension, Some(3072));
+    let ranked = store.rank(&Embedding::from(embedding)).unwrap();
+    assert!((ranked[0].1 - 1.0).abs() < 1e-5);
+}
+
+#[test]
+fn hybrid_get_never_returns_semantic_matches_below_the_threshold() {
+    let (_dir, store) = store();
+    let filter = Filter::default();
+    let memory = store.get_hybrid("containers", Some(0.9), 0.75, &filter);
+    assert_eq!(memory.unwrap().unwrap().value, "kubectl get pods -A");
+    // 0.96 is convincing enough not to fall back, but still below the threshold
+    let memory = store.get_hybrid("containers", Some(0.99), 0.75, &filter);
+    assert!(memory.unwrap().is_none());
+}
+
+#[test]
+fn the_approximate_index_leaves_out_archived_memories() {
+    let (_dir, mut store) = large_store();
+    store
+        .insert_with_embedding("kubectl get pods", "containers", vec![0.0, 0.8, 0.6])
+        .unwrap();
+    let get = |store: &MemoryStore, filter: &Filter| {
+        store
+            .get("containers", None, filter)
+            .unwrap()
+            .unwrap()
+            .value
+    };
+    assert_eq!(get(&store, &Filter::default()), "kubectl get pods");
+    store.archive_at(10_000).unwrap();
+    assert_eq!(get(&store, &Filter::default()), "kubectl get pods -A");
+    let archived = Filter {
+        archived: true,
+        ..Filter::default()
+    };
+    assert_eq!(get(&store, &archived), "kubectl get pods");
+}
+
+#[test]
+fn the_approximate_index_matches_memories_by_their_chunks() {
+    let (_dir, mut store) = large_store();
+    let chunked = Memory {
+        chunk_words: Some(1),
+        ..memory("docker ps", "git")
+    };
+    store.insert_many(&[chunked]).unwrap();
+    // the memory itself scores 0 against "ps", but its second chunk scores 1
+    let memory = store.get("ps", None, &Filter::default()).unwrap().unwrap();
+    assert_eq!((memory.index, memory.value.as_str()), (10_000, "docker ps"));
+}
+
+#[test]
+fn merge_appends_the_other_stores_memories_except_duplicates() {
+    let (_dir, mut store) = store();
+    let other_dir = tempfile::tempdir().unwrap();
+    let mut other = open(&other_dir);
+    other
+        .insert("docker ps -a", "list running containers", &[], false)
+        .unwrap();
+    other.insert("podman ps", "containers", &[], false).unwrap();
+    let report = store.merge(&other, Some(0.99)).unwrap();
+    assert_eq!((report.merged, report.skipped), (1, 1));
+    assert_eq!(store.len().unwrap(), 4);
+    assert_eq!(values(&store, "containers")[0], "podman ps");
+}
+
+#[test]
+fn memories_survive_reopening_the_store() {
+    let (dir, store) = store();
+    drop(store);
+    let store = open(&dir);
+    assert_eq!(store.len().unwrap(), 3);
+    assert_eq!(values(&store, "containers")[0], "kubectl get pods -A");
+}
+
+#[test]
+fn a_corrupt_store_is_recovered_from_its_backup() {
+    let (dir, store) = store();
+    drop(store);
+    let data_file_path = dir.path().join("store.json");
+    std::fs::write(&data_file_path, "{\"memories\": [").unwrap();
+    let store = open(&dir);
+    assert_eq!(store.len().unwrap(), 3);
+    assert_eq!(values(&store, "containers")[0], "kubectl get pods -A");
+    assert!(dir.path().join("store.json.corrupt").exists());
+}
+
+#[test]
+fn get_reranked_returns_the_reranked_best_match() {
+    /// Prefers docker commands over everything else.
+    struct DockerReranker;
+
+    impl Reranker for DockerReranker {
+        fn rerank(&self, _query: &str, candidates: &[Candidate]) -> Result<Vec<f32>> {
+            Ok(candidates
+                .iter()
+                .map(|candidate| candidate.value.contains("docker") as u8 as f32)
+                .collect())
+        }
+    }
+
+    let (_dir, store) = store();
+    let filter = Filter::default();
+    let memory = store
+        .get_reranked("containers", 2, None, &filter, &DockerReranker)
+        .unwrap()
+        .unwrap();
+    assert_eq!(memory.value, "docker ps");
+    assert_eq!(memory.score, 1.0);
+    // only the best match by embedding is reranked
+    let memory = store
+        .get_reranked("git", 1, None, &filter, &DockerReranker)
+        .unwrap()
+        .unwrap();
+    assert_eq!(memory.value, "git show HEAD");
+}
+
+#[test]
+fn metadata_is_stored_and_returned() {
+    let (dir, mut store) = store();
+    let metadata = serde_json::json!({ "source": "https://example.com", "priority": 1 });
+    store
+        .insert_many(&[Memory {
+            metadata: Some(metadata.clone()),
+            ..memory("docker compose ps", "containers")
+        }])
+        .unwrap();
+    drop(store);
+    let store = open(&dir);
+    let memories = store
+        .list("containers", 10, None, &Filter::default(), SortBy::Score)
+        .unwrap();
+    assert_eq!(memories[0].value, "docker compose ps");
+    assert_eq!(memories[0].metadata, Some(metadata));
+    assert_eq!(memories[1].metadata, None);
+}
+
+#[test]
+fn an_in_memory_store_works_without_files() {
+    let mut store = MemoryStore::in_memory(Box::new(FakeEmbedder::new()));
+    store
+        .insert("docker ps", "list running containers", &[], false)
+        .unwrap();
+    store
+        .insert("git show HEAD", "show the last commit", &[], false)
+        .unwrap();
+    assert_eq!(values(&store, "git"), ["git show HEAD", "docker ps"]);
+    assert_eq!(store.len().unwrap(), 2);
+    assert!(store.reembed().is_err());
+}
+
+#[test]
+fn insert_checked_skips_duplicates() {
+    let (_dir, mut store) = store();
+    let outcome = store
+        .insert_checked("docker ps", "list running containers", &[], false, 0.99)
+        .unwrap();
+    assert!(matches!(outcome, InsertOutcome::Skipped { index: 1 }));
+    assert_eq!(store.len().unwrap(), 3);
+}
+
+#[test]
+fn upsert_replaces_the_memory_with_the_same_id() {
+    let (_dir, mut store) = store();
+    let with_value = |value: &str| Memory {
+        id: Some("ps".to_owned()),
+        ..memory(value, "list running containers")
+    };
+    let outcome = store.upsert(with_value("docker ps")).unwrap();
+    assert!(matches!(outcome, InsertOutcome::Inserted));
+    let outcome = store.upsert(with_value("docker ps")).unwrap();
+    assert!(matches!(outcome, InsertOutcome::Skipped { index: 3 }));
+    let outcome = store.upsert(with_value("docker ps -a")).unwrap();
+    assert!(matches!(outcome, InsertOutcome::Updated { index: 3 }));
+    assert_eq!(store.len().unwrap(), 4);
+    let memory = store.get_by_id("ps").unwrap().unwrap();
+    assert_eq!((memory.index, memory.value.as_str()), (3, "docker ps -a"));
+    assert!(store.insert_many(&[with_value("docker ps")]).is_err());
+}
+
+#[test]
+fn recency_decay_lets_a_recent_weaker_match_outrank_an_old_stronger_one() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut store = open(&dir);
+    let days_ago = |value: &str, description: &str, days: i64| Memory {
+        created_at: Some(Utc::now() - chrono::Duration::days(days)),
+        ..memory(value, description)
+    };
+    store
+        .insert_many(&[
+            days_ago("kubectl get pods -A", "list pods in every namespace", 30),
+            days_ago("docker ps", "list running containers", 10),
+        ])
+        .unwrap();
+    store
+        .set_recency_half_life(Some(Duration::from_secs(10 * 24 * 60 * 60)))
+        .unwrap();
+    // 0.96 halved three times against 0.8 halved once
+    let memories = store
+        .list("containers", 10, None, &Filter::default(), SortBy::Score)
+        .unwrap();
+    assert_eq!(memories[0].value, "docker ps");
+    assert!((memories[0].score - 0.4).abs() < 1e-4);
+    assert_eq!(memories[1].value, "kubectl get pods -A");
+    assert!((memories[1].score - 0.12).abs() < 1e-4);
+    assert!(store.set_recency_half_life(Some(Duration::ZERO)).is_err());
+}
+
+#[test]
+fn upserting_an_archived_memory_restores_it() {
+    let (_dir, mut store) = store();
+    let with_id = Memory {
+        id: Some("ps".to_owned()),
+        ..memory("docker ps", "list running containers")
+    };
+    store.upsert(with_id.clone()).unwrap();
+    store.archive_at(3).unwrap();
+    assert!(store.get_by_id("ps").unwrap().is_none());
+    let outcome = store.upsert(with_id).unwrap();
+    assert!(matches!(outcome, InsertOutcome::Updated { index: 3 }));
+    assert_eq!(store.get_by_id("ps").unwrap().unwrap().index, 3);
+}
+
+#[test]
+fn merge_fails_on_memories_with_the_id_of_another() {
+    let (_dir, mut store) = store();
+    let other_dir = tempfile::tempdir().unwrap();
+    let mut other = open(&other_dir);
+    let with_id = |id: &str, value: &str| Memory {
+        id: Some(id.to_owned()),
+        ..memory(value, "list running containers")
+    };
+    store.upsert(with_id("ps", "docker ps")).unwrap();
+    other
+        .insert_many(&[with_id("ps-a", "docker ps -a"), with_id("ps", "podman ps")])
+        .unwrap();
+    assert!(store.merge(&other, None).is_err());
+    assert_eq!(store.len().unwrap(), 4);
+    assert!(store.get_by_id("ps-a").unwrap().is_none());
+}
+
+#[test]
+fn doctor_drops_invalid_memories_along_with_their_chunks() {
+    let (dir, mut store) = store();
+    let chunked = |value: &str| Memory {
+        chunk_words: Some(1),
+        ..memory(value, "list running containers")
+    };
+    store
+        .insert_many(&[chunked("docker ps"), chunked("ps docker")])
+        .unwrap();
+    drop(store);
+    // overwrite the embedding of "docker ps" with NaNs
+    let embeddings_file_path = dir.path().join("store.embeddings");
+    let mut embeddings = std::fs::read(&embeddings_file_path).unwrap();
+    let row_bytes = 3 * std::mem::size_of::<f32>();
+    for value in embeddings[3 * row_bytes..4 * row_bytes].chunks_exact_mut(4) {
+        value.copy_from_slice(&f32::NAN.to_le_bytes());
+    }
+    std::fs::write(&embeddings_file_path, embeddings).unwrap();
+    let mut store = open(&dir);
+    let problems = store.doctor(true).unwrap();
+    assert_eq!(problems.len(), 1);
+    drop(store);
+    let store = open(&dir);
+    assert_eq!(store.len().unwrap(), 4);
+    // the chunks of the remaining chunked memory moved along with it
+    let memory = store.get("ps", None, &Filter::default()).unwrap().unwrap();
+    assert_eq!((memory.index, memory.value.as_str()), (3, "ps docker"));
+}
+
+#[test]
+fn the_stored_file_of_a_sqlite_store_is_its_database() {
+    let dir = tempfile::tempdir().unwrap();
+    let data_file_path = dir.path().join("store.json");
+    assert_eq!(MemoryStore::stored_file_path(&data_file_path), None);
+    std::fs::write(dir.path().join("store.sqlite"), b"").unwrap();
+    assert_eq!(
+        MemoryStore::stored_file_path(&data_file_path),
+        Some(data_file_path.with_extension("sqlite"))
+    );
+}
+
+#[test]
+fn finding_the_data_file_of_a_store_creates_nothing() {
+    let dir = tempfile::tempdir().unwrap();
+    let data_file_path = MemoryStore::find_data_file_path(Some(dir.path()), Some("work")).unwrap();
+    assert_eq!(data_file_path, dir.path().join("stores/work/store.json"));
+    assert!(!dir.path().join("stores").exists());
+}
+
+#[test]
+fn renaming_a_store_never_replaces_another() {
+    let dir = tempfile::tempdir().unwrap();
+    let stores_dir_path = dir.path().join("stores");
+    let mut store = MemoryStore::with_options(
+        stores_dir_path.join("old/store.json"),
+        Box::new(FakeEmbedder::new()),
+    );
+    store
+        .insert("docker ps", "list running containers", &[], false)
+        .unwrap();
+    drop(store);
+    std::fs::create_dir(stores_dir_path.join("new")).unwrap();
+    let rename =
+        || MemoryStore::rename_store(Some(dir.path()), "old", "new", LockMode::NonBlocking);
+    assert!(rename().is_err());
+    std::fs::remove_dir(stores_dir_path.join("new")).unwrap();
+    rename().unwrap();
+    assert_eq!(MemoryStore::list_stores(Some(dir.path())).unwrap(), ["new"]);
+    assert!(stores_dir_path.join("new/store.json").exists());
+}
+
+#[test]
+fn delete_removes_the_best_match() {
+    let (_dir, mut store) = store();
+    let deleted = store.delete("git").unwrap().unwrap();
+    assert_eq!(deleted.value, "git show HEAD");
+    assert_eq!(
+        values(&store, "containers"),
+        ["kubectl get pods -A", "docker ps"]
+    );
+}